@@ -1,167 +1,297 @@
 use crate::object;
-use crate::scanner;
+use crate::scanner::{self, Span};
 use derive_more::From;
+use std::rc::Rc;
 
 #[derive(Debug)]
-pub struct Program<'src> {
-    pub stmts: Vec<Stmt<'src>>,
+pub struct Program {
+    pub stmts: Vec<Stmt>,
+    pub source: Rc<str>,
 }
 
 #[derive(Debug, From)]
-pub enum Expr<'src> {
-    Assign(AssignExpr<'src>),
-    Binary(BinaryExpr<'src>),
-    Call(CallExpr<'src>),
-    Get(GetExpr<'src>),
-    Grouping(GroupingExpr<'src>),
+pub enum Expr {
+    Assign(AssignExpr),
+    Binary(BinaryExpr),
+    Call(CallExpr),
+    Function(FunctionExpr),
+    Get(GetExpr),
+    Grouping(GroupingExpr),
+    Index(IndexExpr),
+    List(ListExpr),
     Literal(LiteralExpr),
-    Logical(LogicalExpr<'src>),
-    Set(SetExpr<'src>),
-    Super(SuperExpr<'src>),
-    This(ThisExpr<'src>),
-    Unary(UnaryExpr<'src>),
-    Variable(VariableExpr<'src>),
+    Logical(LogicalExpr),
+    Map(MapExpr),
+    Set(SetExpr),
+    SetIndex(SetIndexExpr),
+    Super(SuperExpr),
+    This(ThisExpr),
+    Unary(UnaryExpr),
+    Variable(VariableExpr),
 }
 
 #[derive(Debug, From)]
-pub enum Stmt<'src> {
-    Block(BlockStmt<'src>),
-    Class(ClassStmt<'src>),
-    Expr(ExprStmt<'src>),
-    Function(FunctionStmt<'src>),
-    If(IfStmt<'src>),
-    Print(PrintStmt<'src>),
-    Return(ReturnStmt<'src>),
-    Var(VarStmt<'src>),
-    While(WhileStmt<'src>),
+pub enum Stmt {
+    Block(BlockStmt),
+    Break(BreakStmt),
+    Class(ClassStmt),
+    Continue(ContinueStmt),
+    Expr(ExprStmt),
+    Function(Rc<FunctionStmt>),
+    If(IfStmt),
+    Import(ImportStmt),
+    Print(PrintStmt),
+    Return(ReturnStmt),
+    Throw(ThrowStmt),
+    Try(TryStmt),
+    Var(VarStmt),
+    While(WhileStmt),
+}
+
+impl Expr {
+    // The source code the expression came from, for error messages.
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Assign(node) => node.name.span.to(node.value.span()),
+            Expr::Binary(node) => node.left.span().to(node.right.span()),
+            Expr::Call(node) => node.callee.span().to(node.paren.span),
+            Expr::Function(node) => node.span,
+            Expr::Get(node) => node.object.span().to(node.name.span),
+            Expr::Grouping(node) => node.span,
+            Expr::Index(node) => node.span,
+            Expr::List(node) => node.span,
+            Expr::Literal(node) => node.span,
+            Expr::Logical(node) => node.left.span().to(node.right.span()),
+            Expr::Map(node) => node.span,
+            Expr::Set(node) => node.object.span().to(node.value.span()),
+            Expr::SetIndex(node) => node.object.span().to(node.value.span()),
+            Expr::Super(node) => node.keyword.span.to(node.method.span),
+            Expr::This(node) => node.keyword.span,
+            Expr::Unary(node) => node.operator.span.to(node.right.span()),
+            Expr::Variable(node) => node.name.span,
+        }
+    }
+}
+
+// Where the resolver found a local variable: how many scopes out, and its
+// index within that scope. Globals aren't resolved, and are looked up by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+}
+
+#[derive(Debug)]
+pub struct AssignExpr {
+    pub name: scanner::Token,
+    pub value: Box<Expr>,
+    pub resolved_slot: Option<Slot>,
+}
+
+#[derive(Debug)]
+pub struct BinaryExpr {
+    pub left: Box<Expr>,
+    pub operator: scanner::Token,
+    pub right: Box<Expr>,
 }
 
 #[derive(Debug)]
-pub struct AssignExpr<'src> {
-    pub name: scanner::Token<'src>,
-    pub value: Box<Expr<'src>>,
-    pub resolved_depth: Option<usize>,
+pub struct CallExpr {
+    pub callee: Box<Expr>,
+    pub paren: scanner::Token,
+    pub arguments: Vec<Expr>,
 }
 
+// An anonymous function, which we represent like a declaration so that both
+// kinds of function share everything else. Its name is "lambda".
 #[derive(Debug)]
-pub struct BinaryExpr<'src> {
-    pub left: Box<Expr<'src>>,
-    pub operator: scanner::Token<'src>,
-    pub right: Box<Expr<'src>>,
+pub struct FunctionExpr {
+    pub declaration: Rc<FunctionStmt>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub struct CallExpr<'src> {
-    pub callee: Box<Expr<'src>>,
-    pub paren: scanner::Token<'src>,
-    pub arguments: Vec<Expr<'src>>,
+pub struct GetExpr {
+    pub object: Box<Expr>,
+    pub name: scanner::Token,
 }
 
 #[derive(Debug)]
-pub struct GetExpr<'src> {
-    pub object: Box<Expr<'src>>,
-    pub name: scanner::Token<'src>,
+pub struct GroupingExpr {
+    pub expr: Box<Expr>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub struct GroupingExpr<'src> {
-    pub expr: Box<Expr<'src>>,
+pub struct IndexExpr {
+    pub object: Box<Expr>,
+    pub bracket: scanner::Token,
+    pub index: Box<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct ListExpr {
+    pub bracket: scanner::Token,
+    pub elements: Vec<Expr>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct LiteralExpr {
     pub value: object::Literal,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct LogicalExpr {
+    pub left: Box<Expr>,
+    pub operator: scanner::Token,
+    pub right: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct MapExpr {
+    pub brace: scanner::Token,
+    pub entries: Vec<(Expr, Expr)>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct SetExpr {
+    pub object: Box<Expr>,
+    pub name: scanner::Token,
+    pub value: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct SetIndexExpr {
+    pub object: Box<Expr>,
+    pub bracket: scanner::Token,
+    pub index: Box<Expr>,
+    pub value: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct SuperExpr {
+    pub keyword: scanner::Token,
+    pub method: scanner::Token,
+    pub resolved_slot: Option<Slot>,
+}
+
+#[derive(Debug)]
+pub struct ThisExpr {
+    pub keyword: scanner::Token,
+    pub resolved_slot: Option<Slot>,
+}
+
+#[derive(Debug)]
+pub struct UnaryExpr {
+    pub operator: scanner::Token,
+    pub right: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct VariableExpr {
+    pub name: scanner::Token,
+    pub resolved_slot: Option<Slot>,
 }
 
 #[derive(Debug)]
-pub struct LogicalExpr<'src> {
-    pub left: Box<Expr<'src>>,
-    pub operator: scanner::Token<'src>,
-    pub right: Box<Expr<'src>>,
+pub struct BlockStmt {
+    pub stmts: Vec<Stmt>,
 }
 
 #[derive(Debug)]
-pub struct SetExpr<'src> {
-    pub object: Box<Expr<'src>>,
-    pub name: scanner::Token<'src>,
-    pub value: Box<Expr<'src>>,
+pub struct BreakStmt {
+    pub keyword: scanner::Token,
 }
 
 #[derive(Debug)]
-pub struct SuperExpr<'src> {
-    pub keyword: scanner::Token<'src>,
-    pub method: scanner::Token<'src>,
-    pub resolved_depth: Option<usize>,
+pub struct ClassStmt {
+    pub name: scanner::Token,
+    pub superclass: Option<Box<VariableExpr>>,
+    pub methods: Vec<Rc<FunctionStmt>>,
 }
 
 #[derive(Debug)]
-pub struct ThisExpr<'src> {
-    pub keyword: scanner::Token<'src>,
-    pub resolved_depth: Option<usize>,
+pub struct ContinueStmt {
+    pub keyword: scanner::Token,
 }
 
 #[derive(Debug)]
-pub struct UnaryExpr<'src> {
-    pub operator: scanner::Token<'src>,
-    pub right: Box<Expr<'src>>,
+pub struct ExprStmt {
+    pub expr: Box<Expr>,
 }
 
 #[derive(Debug)]
-pub struct VariableExpr<'src> {
-    pub name: scanner::Token<'src>,
-    pub resolved_depth: Option<usize>,
+pub struct FunctionStmt {
+    pub name: scanner::Token,
+    pub parameters: Vec<scanner::Token>,
+    pub body: Vec<Stmt>,
 }
 
 #[derive(Debug)]
-pub struct BlockStmt<'src> {
-    pub stmts: Vec<Stmt<'src>>,
+pub struct IfStmt {
+    pub condition: Box<Expr>,
+    pub then_: Box<Stmt>,
+    pub else_: Option<Box<Stmt>>,
 }
 
 #[derive(Debug)]
-pub struct ClassStmt<'src> {
-    pub name: scanner::Token<'src>,
-    pub superclass: Option<Box<VariableExpr<'src>>>,
-    pub methods: Vec<FunctionStmt<'src>>,
+pub struct ImportStmt {
+    pub keyword: scanner::Token,
+    // The string literal giving the path, for errors.
+    pub path_token: scanner::Token,
+    // Relative to the importing file.
+    pub path: String,
+    pub name: scanner::Token,
 }
 
 #[derive(Debug)]
-pub struct ExprStmt<'src> {
-    pub expr: Box<Expr<'src>>,
+pub struct PrintStmt {
+    pub expr: Box<Expr>,
 }
 
 #[derive(Debug)]
-pub struct FunctionStmt<'src> {
-    pub name: scanner::Token<'src>,
-    pub parameters: Vec<scanner::Token<'src>>,
-    pub body: Vec<Stmt<'src>>,
+pub struct ReturnStmt {
+    pub keyword: scanner::Token,
+    pub value: Option<Box<Expr>>,
 }
 
 #[derive(Debug)]
-pub struct IfStmt<'src> {
-    pub condition: Box<Expr<'src>>,
-    pub then_: Box<Stmt<'src>>,
-    pub else_: Option<Box<Stmt<'src>>>,
+pub struct ThrowStmt {
+    pub keyword: scanner::Token,
+    pub value: Box<Expr>,
 }
 
+// At least one of catch and finally is always present.
 #[derive(Debug)]
-pub struct PrintStmt<'src> {
-    pub expr: Box<Expr<'src>>,
+pub struct TryStmt {
+    pub body: Vec<Stmt>,
+    pub catch: Option<CatchClause>,
+    pub finally: Option<Vec<Stmt>>,
 }
 
+// The caught value is bound to the name in the same scope as the body, like
+// a function's parameters.
 #[derive(Debug)]
-pub struct ReturnStmt<'src> {
-    pub keyword: scanner::Token<'src>,
-    pub value: Option<Box<Expr<'src>>>,
+pub struct CatchClause {
+    pub name: scanner::Token,
+    pub body: Vec<Stmt>,
 }
 
 #[derive(Debug)]
-pub struct VarStmt<'src> {
-    pub name: scanner::Token<'src>,
-    pub initializer: Option<Box<Expr<'src>>>,
+pub struct VarStmt {
+    pub name: scanner::Token,
+    pub initializer: Option<Box<Expr>>,
 }
 
 #[derive(Debug)]
-pub struct WhileStmt<'src> {
-    pub condition: Box<Expr<'src>>,
-    pub body: Box<Stmt<'src>>,
+pub struct WhileStmt {
+    pub condition: Box<Expr>,
+    pub body: Box<Stmt>,
+    // For a desugared for loop, the increment clause, which runs after the
+    // body even if it continues.
+    pub increment: Option<Box<Expr>>,
 }
@@ -1,28 +1,36 @@
-#[cfg(test)]
 use crate::ast::*;
-#[cfg(test)]
+use crate::error::LoxError;
 use crate::parser;
-#[cfg(test)]
+use crate::scanner;
 use itertools::Itertools;
-#[cfg(test)]
 use std::fmt;
+use std::rc::Rc;
 
-#[cfg(test)]
-pub fn print<'src>(node: &Program<'src>) -> String {
-    node.stmts.iter().map(|stmt| print_stmt(stmt)).join("\n")
+/// The syntax tree of some code, as the REPL's :ast shows it.
+///
+/// # Errors
+///
+/// Any errors scanning or parsing the code.
+pub fn print_ast(source: &str) -> Result<String, LoxError> {
+    let tokens = scanner::scan_tokens(source)?;
+    Ok(print(&parser::parse(tokens)?))
+}
+
+pub fn print(node: &Program) -> String {
+    node.stmts.iter().map(print_stmt).join("\n")
 }
 
-#[cfg(test)]
 fn parenthesize(items: impl IntoIterator<Item = impl fmt::Display>) -> String {
     format!("({})", items.into_iter().join(" "))
 }
 
-#[cfg(test)]
-fn print_expr<'src>(node: &Expr<'src>) -> String {
+fn print_expr(node: &Expr) -> String {
     match node {
-        Expr::Assign(node) => parenthesize(&["assign", node.name.lexeme, &print_expr(&node.value)]),
+        Expr::Assign(node) => {
+            parenthesize(&["assign", &*node.name.lexeme, &print_expr(&node.value)])
+        }
         Expr::Binary(node) => parenthesize(&[
-            node.operator.lexeme,
+            &*node.operator.lexeme,
             &print_expr(&node.left),
             &print_expr(&node.right),
         ]),
@@ -33,29 +41,51 @@ fn print_expr<'src>(node: &Expr<'src>) -> String {
             }
             parenthesize(&parts)
         }
-        Expr::Get(node) => parenthesize(&["get", &print_expr(&node.object), node.name.lexeme]),
+        Expr::Get(node) => parenthesize(&["get", &print_expr(&node.object), &*node.name.lexeme]),
         Expr::Grouping(node) => parenthesize(&["group", &print_expr(&node.expr)]),
+        Expr::Index(node) => {
+            parenthesize(&["index", &print_expr(&node.object), &print_expr(&node.index)])
+        }
+        Expr::Function(node) => print_function(&node.declaration),
+        Expr::List(node) => {
+            let mut parts = vec!["list".to_string()];
+            parts.extend(node.elements.iter().map(print_expr));
+            parenthesize(parts)
+        }
         Expr::Literal(node) => parenthesize(&[&node.value.to_string()]),
         Expr::Logical(node) => parenthesize(&[
-            node.operator.lexeme,
+            &*node.operator.lexeme,
             &print_expr(&node.left),
             &print_expr(&node.right),
         ]),
+        Expr::Map(node) => {
+            let mut parts = vec!["map".to_string()];
+            for (key, value) in &node.entries {
+                parts.push(print_expr(key));
+                parts.push(print_expr(value));
+            }
+            parenthesize(parts)
+        }
         Expr::Set(node) => parenthesize(&[
             "set",
             &print_expr(&node.object),
-            node.name.lexeme,
+            &*node.name.lexeme,
+            &print_expr(&node.value),
+        ]),
+        Expr::SetIndex(node) => parenthesize(&[
+            "set-index",
+            &print_expr(&node.object),
+            &print_expr(&node.index),
             &print_expr(&node.value),
         ]),
-        Expr::Super(node) => parenthesize(&["super", node.method.lexeme]),
+        Expr::Super(node) => parenthesize(&["super", &*node.method.lexeme]),
         Expr::This(_) => parenthesize(&["this"]),
-        Expr::Unary(node) => parenthesize(&[node.operator.lexeme, &print_expr(&node.right)]),
-        Expr::Variable(node) => parenthesize(&["variable", node.name.lexeme]),
+        Expr::Unary(node) => parenthesize(&[&*node.operator.lexeme, &print_expr(&node.right)]),
+        Expr::Variable(node) => parenthesize(&["variable", &*node.name.lexeme]),
     }
 }
 
-#[cfg(test)]
-fn print_block<'src>(head: &str, stmts: &Vec<Stmt<'src>>) -> String {
+fn print_block(head: &str, stmts: &[Stmt]) -> String {
     let body = stmts
         .iter()
         .map(|stmt| format!("\t{}\n", print_stmt(stmt)))
@@ -64,8 +94,7 @@ fn print_block<'src>(head: &str, stmts: &Vec<Stmt<'src>>) -> String {
 }
 
 // TODO(benkraft): ick! how to avoid?
-#[cfg(test)]
-fn print_function_block<'src>(head: &str, stmts: &Vec<FunctionStmt<'src>>) -> String {
+fn print_function_block(head: &str, stmts: &[Rc<FunctionStmt>]) -> String {
     let body = stmts
         .iter()
         .map(|stmt| format!("\t{}\n", print_function(stmt)))
@@ -73,19 +102,18 @@ fn print_function_block<'src>(head: &str, stmts: &Vec<FunctionStmt<'src>>) -> St
     format!("({head}\n{body})")
 }
 
-#[cfg(test)]
-fn print_function<'src>(node: &FunctionStmt<'src>) -> String {
-    let mut parts = vec!["fun", node.name.lexeme];
-    parts.extend(node.parameters.iter().map(|param| param.lexeme));
+fn print_function(node: &FunctionStmt) -> String {
+    let mut parts = vec!["fun", &*node.name.lexeme];
+    parts.extend(node.parameters.iter().map(|param| &*param.lexeme));
     let body = print_block("", &node.body);
     parts.push(&body);
     parenthesize(parts)
 }
 
-#[cfg(test)]
-fn print_stmt<'src>(node: &Stmt<'src>) -> String {
+fn print_stmt(node: &Stmt) -> String {
     match node {
         Stmt::Block(node) => print_block("block", &node.stmts),
+        Stmt::Break(_) => parenthesize(&["break"]),
         Stmt::Class(node) => {
             let mut head = format!("class {}", node.name.lexeme);
             if let Some(e) = &node.superclass {
@@ -94,6 +122,7 @@ fn print_stmt<'src>(node: &Stmt<'src>) -> String {
 
             print_function_block(&head, &node.methods)
         }
+        Stmt::Continue(_) => parenthesize(&["continue"]),
         Stmt::Expr(node) => parenthesize(&["expr", &print_expr(&node.expr)]),
         Stmt::Function(node) => print_function(&node),
         Stmt::If(node) => {
@@ -107,6 +136,7 @@ fn print_stmt<'src>(node: &Stmt<'src>) -> String {
             }
             parenthesize(parts)
         }
+        Stmt::Import(node) => parenthesize(&["import", &node.path, &*node.name.lexeme]),
         Stmt::Print(node) => parenthesize(&["print", &print_expr(&node.expr)]),
         Stmt::Return(node) => {
             let mut parts = vec!["return".to_string()];
@@ -115,6 +145,20 @@ fn print_stmt<'src>(node: &Stmt<'src>) -> String {
             }
             parenthesize(parts)
         }
+        Stmt::Throw(node) => parenthesize(&["throw", &print_expr(&node.value)]),
+        Stmt::Try(node) => {
+            let mut parts = vec![print_block("try", &node.body)];
+            if let Some(catch) = &node.catch {
+                parts.push(print_block(
+                    &format!("catch {}", catch.name.lexeme),
+                    &catch.body,
+                ));
+            }
+            if let Some(finally) = &node.finally {
+                parts.push(print_block("finally", finally));
+            }
+            parts.join(" ")
+        }
         Stmt::Var(node) => {
             let mut parts = vec!["var".to_string(), node.name.lexeme.to_string()];
             if let Some(e) = &node.initializer {
@@ -122,11 +166,17 @@ fn print_stmt<'src>(node: &Stmt<'src>) -> String {
             }
             parenthesize(parts)
         }
-        Stmt::While(node) => parenthesize(&[
-            "while",
-            &print_expr(&node.condition),
-            &print_stmt(&node.body),
-        ]),
+        Stmt::While(node) => {
+            let mut parts = vec![
+                "while".to_string(),
+                print_expr(&node.condition),
+                print_stmt(&node.body),
+            ];
+            if let Some(e) = &node.increment {
+                parts.push(print_expr(e));
+            }
+            parenthesize(parts)
+        }
     }
 }
 
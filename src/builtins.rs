@@ -0,0 +1,133 @@
+use crate::gc::Heap;
+use crate::list::Element;
+use crate::native::{NativeError, NativeFn, NativeFunction};
+use crate::object::Literal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead};
+use std::rc::Rc;
+use std::time;
+
+// Builtin functions, which work the same on either backend (values `V` are
+// objects or VM values), and their workings.
+
+// What clock() returns, unless the host says otherwise.
+pub fn now_sec() -> f64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(t) => t.as_secs_f64(),
+        Err(e) => panic!("{e}"),
+    }
+}
+
+// Defines a global (or module member) `name`, calling the given Rust
+// function; see native.rs.
+pub fn define<V: Element, Args, N: NativeFunction<V, Args> + 'static>(
+    builtins: &mut HashMap<String, V>,
+    name: &str,
+    mut function: N,
+) {
+    let owned_name = name.to_string();
+    let function: NativeFn<V> = Rc::new(RefCell::new(move |arguments| {
+        function.call(&owned_name, arguments)
+    }));
+    builtins.insert(name.to_string(), V::native(name, N::ARITY, function));
+}
+
+// The globals every program starts with, where clock() calls the given
+// function and readLine() and readAll() read from the given input.
+pub fn globals<V: Element + 'static>(
+    heap: &Rc<RefCell<Heap>>,
+    clock: impl FnMut() -> f64 + 'static,
+    input: &Rc<RefCell<Input>>,
+) -> HashMap<String, V> {
+    let mut builtins = HashMap::new();
+    define(&mut builtins, "clock", clock);
+    let gc_heap = heap.clone();
+    #[allow(clippy::cast_precision_loss)]
+    define(&mut builtins, "gc", move || {
+        gc_heap.borrow_mut().collect() as f64
+    });
+    let stats_heap = heap.clone();
+    define(&mut builtins, "heapStats", move || {
+        stats_heap.borrow().stats().to_string()
+    });
+    define(&mut builtins, "exit", |status| {
+        Err::<(), _>(NativeError::Exit(exit_status(status)?))
+    });
+    define(&mut builtins, "getenv", |name: String| env::var(name).ok());
+    let line_input = input.clone();
+    define(&mut builtins, "readLine", move || read_line(&line_input));
+    let all_input = input.clone();
+    define(&mut builtins, "readAll", move || read_all(&all_input));
+    builtins
+}
+
+// A list of the given strings, e.g. the script's command-line arguments.
+pub fn string_list<V: Element + 'static>(
+    heap: &RefCell<Heap>,
+    strings: impl IntoIterator<Item = String>,
+) -> V {
+    let strings: Vec<V> = strings
+        .into_iter()
+        .map(|string| Literal::String(string).into())
+        .collect();
+    heap.borrow_mut().track(RefCell::new(strings)).into()
+}
+
+// The exit status exit(status) stops the program with.
+pub fn exit_status(status: f64) -> Result<u8, String> {
+    if status.fract() == 0.0 && (0.0..=255.0).contains(&status) {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Ok(status as u8)
+    } else {
+        Err(format!(
+            "Exit status must be a whole number from 0 to 255, got {status}."
+        ))
+    }
+}
+
+// Where readLine() and readAll() read from.
+pub enum Input {
+    // Which we only lock while we read, so as not to hold up anyone else.
+    Stdin,
+    Reader(Box<dyn BufRead>),
+}
+
+impl Input {
+    fn read<T>(&mut self, f: impl FnOnce(&mut dyn BufRead) -> io::Result<T>) -> Result<T, String> {
+        let result = match self {
+            Input::Stdin => f(&mut io::stdin().lock()),
+            Input::Reader(reader) => f(reader),
+        };
+        result.map_err(|err| format!("Can't read input: {err}."))
+    }
+}
+
+// The next line, less its line ending, or None at the end of the input.
+pub fn read_line(input: &RefCell<Input>) -> Result<Option<String>, String> {
+    let mut line = String::new();
+    if input
+        .borrow_mut()
+        .read(|reader| reader.read_line(&mut line))?
+        == 0
+    {
+        return Ok(None);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+// The rest of the input (which is empty at its end).
+pub fn read_all(input: &RefCell<Input>) -> Result<String, String> {
+    let mut text = String::new();
+    input
+        .borrow_mut()
+        .read(|reader| reader.read_to_string(&mut text))?;
+    Ok(text)
+}
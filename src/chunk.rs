@@ -0,0 +1,128 @@
+use crate::object::Literal;
+use crate::scanner::Span;
+use std::fmt;
+use std::rc::Rc;
+
+// Operands index into the current chunk's tables: `constants` for Constant,
+// `names` for globals/properties/methods, `functions` for Closure. Locals
+// and upvalues are slot indices; jumps are offsets from the next op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Constant(u16),
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal(u8),
+    SetLocal(u8),
+    GetGlobal(u16),
+    DefineGlobal(u16),
+    SetGlobal(u16),
+    GetUpvalue(u8),
+    SetUpvalue(u8),
+    GetProperty(u16),
+    SetProperty(u16),
+    GetSuper(u16),
+    // Builds a list from the given number of values on the stack.
+    List(u16),
+    // Builds a map from the given number of key-value pairs on the stack.
+    Map(u16),
+    GetIndex,
+    SetIndex,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Jump(u16),
+    JumpIfFalse(u16),
+    Loop(u16),
+    // Installs an exception handler at the given offset, until the matching
+    // PopHandler. Throwing to it unwinds the stack to where it was here,
+    // then pushes the exception.
+    PushHandler(u16),
+    PopHandler,
+    Throw,
+    Call(u8),
+    Closure(u16),
+    CloseUpvalue,
+    Return,
+    Class(u16),
+    Inherit,
+    Method(u16),
+    // Imports the module at the path with the given name, pushing its
+    // namespace once it's run.
+    Import(u16),
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub lines: Vec<usize>,
+    // For each op, the code it came from, for errors.
+    pub spans: Vec<Span>,
+    pub source: Rc<str>,
+    pub constants: Vec<Literal>,
+    pub names: Vec<String>,
+    pub functions: Vec<Rc<Function>>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, op: Op, line: usize, span: Span) -> usize {
+        self.code.push(op);
+        self.lines.push(line);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    pub fn add_constant(&mut self, value: Literal) -> usize {
+        if let Some(i) = self.constants.iter().position(|c| c == &value) {
+            return i;
+        }
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    pub fn add_name(&mut self, name: &str) -> usize {
+        if let Some(i) = self.names.iter().position(|n| n == name) {
+            return i;
+        }
+        self.names.push(name.to_string());
+        self.names.len() - 1
+    }
+
+    pub fn add_function(&mut self, function: Function) -> usize {
+        self.functions.push(Rc::new(function));
+        self.functions.len() - 1
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueRef {
+    pub index: u8,
+    pub is_local: bool,
+}
+
+// A compiled function body; at runtime it's always wrapped in a
+// value::Closure which supplies the upvalues.
+#[derive(Debug, Default)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub upvalues: Vec<UpvalueRef>,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<function {}>", &self.name)
+    }
+}
@@ -0,0 +1,769 @@
+use crate::ast::*;
+use crate::chunk::{Chunk, Function, Op, UpvalueRef};
+use crate::error::{self, ErrorCode, LoxError, Snippet};
+use crate::object::Literal;
+use crate::scanner::{Span, Token, TokenType};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    Script,
+    Function,
+    Initializer,
+    Method,
+}
+
+struct Local<'ast> {
+    name: &'ast str,
+    depth: usize,
+    is_captured: bool,
+    // Set while we compile a copy of a finally block, which can't see
+    // variables declared inside its try statement even though they're
+    // still on the stack.
+    hidden: bool,
+}
+
+// A loop we're compiling, with the jumps out of it that we'll patch once
+// we know where they go.
+struct Loop {
+    scope_depth: usize,
+    // How many try statements we were already inside.
+    tries: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// Code protected by an exception handler, which any early exit must first
+// remove, and then run the finally block (if any) on the way out.
+#[derive(Clone, Copy)]
+struct Try<'ast> {
+    // How many locals were in scope outside the try statement.
+    locals: usize,
+    finally: Option<&'ast [Stmt]>,
+}
+
+struct FunctionState<'ast> {
+    function: Function,
+    type_: FunctionType,
+    locals: Vec<Local<'ast>>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    tries: Vec<Try<'ast>>,
+}
+
+impl FunctionState<'_> {
+    fn new(name: &str, arity: usize, type_: FunctionType, source: Rc<str>) -> Self {
+        // Slot 0 holds the callee itself, or the receiver in methods.
+        let slot_zero = match type_ {
+            FunctionType::Script | FunctionType::Function => "",
+            FunctionType::Initializer | FunctionType::Method => "this",
+        };
+        FunctionState {
+            function: Function {
+                name: name.to_string(),
+                arity,
+                chunk: Chunk {
+                    source,
+                    ..Chunk::default()
+                },
+                ..Function::default()
+            },
+            type_,
+            locals: vec![Local {
+                name: slot_zero,
+                depth: 0,
+                is_captured: false,
+                hidden: false,
+            }],
+            scope_depth: 0,
+            loops: Vec::new(),
+            tries: Vec::new(),
+        }
+    }
+}
+
+// Compiles a resolved program into bytecode for the VM. We trust the
+// resolver's semantic checks (and its choice of which variables are
+// globals); the only errors reported here are the VM's own limits.
+struct Compiler<'ast> {
+    states: Vec<FunctionState<'ast>>,
+    errors: Vec<LoxError>,
+    // Where the code we're compiling came from.
+    source: Rc<str>,
+    line: usize,
+    span: Span,
+}
+
+pub fn compile(prog: &Program) -> Result<Function, LoxError> {
+    let source = prog.source.clone();
+    let mut compiler = Compiler {
+        states: vec![FunctionState::new(
+            "script",
+            0,
+            FunctionType::Script,
+            source.clone(),
+        )],
+        errors: Vec::new(),
+        source,
+        line: 0,
+        span: Span { start: 0, end: 0 },
+    };
+    compiler.compile_stmts(&prog.stmts);
+    compiler.emit_return();
+    error::check_errors(compiler.errors)?;
+    Ok(compiler.states.pop().unwrap().function)
+}
+
+impl<'ast> Compiler<'ast> {
+    fn compile_stmts(&mut self, stmts: &'ast [Stmt]) {
+        for stmt in stmts {
+            self.compile_stmt(stmt);
+        }
+    }
+
+    fn block(&mut self, stmts: &'ast [Stmt]) {
+        self.begin_scope();
+        self.compile_stmts(stmts);
+        self.end_scope();
+    }
+
+    fn compile_stmt(&mut self, stmt: &'ast Stmt) {
+        match stmt {
+            Stmt::Block(node) => self.block(&node.stmts),
+            Stmt::Break(BreakStmt { keyword }) | Stmt::Continue(ContinueStmt { keyword }) => {
+                self.jump_out_of_loop(keyword);
+            }
+            Stmt::Class(node) => {
+                self.at(&node.name);
+                let name = self.make_name(&node.name.lexeme);
+                let global = self.is_global_scope();
+                self.emit(Op::Class(name));
+                self.define_variable(&node.name);
+
+                if let Some(sup) = &node.superclass {
+                    self.variable(&sup.name, sup.resolved_slot.is_none());
+                    self.begin_scope();
+                    self.add_local("super");
+                    self.variable(&node.name, global);
+                    self.at(&sup.name);
+                    self.emit(Op::Inherit);
+                }
+
+                self.variable(&node.name, global);
+                for method in &node.methods {
+                    let type_ = if &*method.name.lexeme == "init" {
+                        FunctionType::Initializer
+                    } else {
+                        FunctionType::Method
+                    };
+                    self.function(method, type_);
+                    let method_name = self.make_name(&method.name.lexeme);
+                    self.emit(Op::Method(method_name));
+                }
+                self.emit(Op::Pop);
+
+                if node.superclass.is_some() {
+                    self.end_scope();
+                }
+            }
+            Stmt::Expr(node) => {
+                self.compile_expr(&node.expr);
+                self.emit(Op::Pop);
+            }
+            Stmt::Function(node) => {
+                if self.is_global_scope() {
+                    self.function(node, FunctionType::Function);
+                    self.define_variable(&node.name);
+                } else {
+                    // Declare first, so the function can refer to itself.
+                    self.add_local(&node.name.lexeme);
+                    self.function(node, FunctionType::Function);
+                }
+            }
+            Stmt::If(node) => {
+                self.compile_expr(&node.condition);
+                let then_jump = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                self.compile_stmt(&node.then_);
+                let else_jump = self.emit(Op::Jump(0));
+                self.patch_jump(then_jump);
+                self.emit(Op::Pop);
+                if let Some(else_) = &node.else_ {
+                    self.compile_stmt(else_);
+                }
+                self.patch_jump(else_jump);
+            }
+            Stmt::Import(node) => {
+                self.at(&node.path_token);
+                let path = self.make_name(&node.path);
+                self.emit(Op::Import(path));
+                self.define_variable(&node.name);
+            }
+            Stmt::Print(node) => {
+                self.compile_expr(&node.expr);
+                self.emit(Op::Print);
+            }
+            Stmt::Return(node) => {
+                self.at(&node.keyword);
+                match &node.value {
+                    Some(value) => self.compile_expr(value),
+                    None => self.emit_return_value(),
+                }
+                if self.state().tries.is_empty() {
+                    self.emit(Op::Return);
+                } else {
+                    // Keep the return value safe under the finally blocks'
+                    // locals.
+                    self.add_local("");
+                    self.exit_tries(0);
+                    self.at(&node.keyword);
+                    self.emit(Op::Return);
+                    self.state_mut().locals.pop();
+                }
+            }
+            Stmt::Throw(node) => {
+                self.compile_expr(&node.value);
+                self.at(&node.keyword);
+                self.emit(Op::Throw);
+            }
+            Stmt::Try(node) => self.try_statement(node),
+            Stmt::Var(node) => {
+                match &node.initializer {
+                    Some(init) => self.compile_expr(init),
+                    None => {
+                        self.emit(Op::Nil);
+                    }
+                }
+                self.define_variable(&node.name);
+            }
+            Stmt::While(node) => {
+                let loop_start = self.chunk().code.len();
+                self.compile_expr(&node.condition);
+                let exit_jump = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                let state = self.state_mut();
+                state.loops.push(Loop {
+                    scope_depth: state.scope_depth,
+                    tries: state.tries.len(),
+                    breaks: Vec::new(),
+                    continues: Vec::new(),
+                });
+                self.compile_stmt(&node.body);
+                let loop_ = self.state_mut().loops.pop().unwrap();
+                for jump in loop_.continues {
+                    self.patch_jump(jump);
+                }
+                if let Some(increment) = &node.increment {
+                    self.compile_expr(increment);
+                    self.emit(Op::Pop);
+                }
+                self.emit_loop(loop_start);
+                self.patch_jump(exit_jump);
+                self.emit(Op::Pop);
+                for jump in loop_.breaks {
+                    self.patch_jump(jump);
+                }
+            }
+        }
+    }
+
+    // Compiles a break or continue: leaves any try statements in the loop
+    // body, discards the body's locals, and jumps to wherever the loop tells
+    // us once it's done.
+    fn jump_out_of_loop(&mut self, keyword: &Token) {
+        self.at(keyword);
+        let Some(loop_) = self.state().loops.last() else {
+            return self.error("break or continue outside of a loop (resolver bug?)");
+        };
+        let (scope_depth, tries) = (loop_.scope_depth, loop_.tries);
+        self.exit_tries(tries);
+        self.at(keyword);
+        // Pop the locals the jump skips the end of. They stay in scope for
+        // the code after us, which might still capture them, so we can't
+        // tell yet which need closing; closing them all is always safe.
+        let count = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > scope_depth)
+            .count();
+        for _ in 0..count {
+            self.emit(Op::CloseUpvalue);
+        }
+        let jump = self.emit(Op::Jump(0));
+        let loop_ = self.state_mut().loops.last_mut().unwrap();
+        if keyword.type_ == TokenType::Break {
+            loop_.breaks.push(jump);
+        } else {
+            loop_.continues.push(jump);
+        }
+    }
+
+    // The handler for a try statement catches the exception, if there's a
+    // catch clause, and otherwise runs the finally block and rethrows. If
+    // there are both, a second handler around the catch clause does the
+    // latter. The finally block also runs after the statement completes
+    // normally, and on each early exit, so we compile it once for each.
+    fn try_statement(&mut self, node: &'ast TryStmt) {
+        let finally = node.finally.as_deref();
+        let locals = self.state().locals.len();
+        let handler = self.guarded(locals, finally, |c| c.block(&node.body));
+        let mut done = vec![self.emit(Op::Jump(0))];
+        self.patch_jump(handler);
+
+        // The handler leaves the exception on top of the stack, where the
+        // catch clause finds its variable.
+        let mut stacked = 1;
+        if let Some(catch) = &node.catch {
+            self.begin_scope();
+            self.at(&catch.name);
+            self.add_local(&catch.name.lexeme);
+            if finally.is_some() {
+                let handler = self.guarded(locals, finally, |c| c.compile_stmts(&catch.body));
+                self.end_scope();
+                done.push(self.emit(Op::Jump(0)));
+                self.patch_jump(handler);
+                // The catch variable is still there, under the new exception.
+                stacked = 2;
+            } else {
+                self.compile_stmts(&catch.body);
+                self.end_scope();
+            }
+        }
+
+        if let Some(finally) = finally {
+            self.begin_scope();
+            for _ in 0..stacked {
+                self.add_local("");
+            }
+            self.block(finally);
+            self.emit(Op::Throw);
+            // (This emits pops after the throw, which never run.)
+            self.end_scope();
+        }
+        for jump in done {
+            self.patch_jump(jump);
+        }
+        if let Some(finally) = finally {
+            self.block(finally);
+        }
+    }
+
+    // Compiles code under an exception handler, returning the handler's
+    // jump for the caller to patch.
+    fn guarded(
+        &mut self,
+        locals: usize,
+        finally: Option<&'ast [Stmt]>,
+        body: impl FnOnce(&mut Self),
+    ) -> usize {
+        let handler = self.emit(Op::PushHandler(0));
+        self.state_mut().tries.push(Try { locals, finally });
+        body(self);
+        self.state_mut().tries.pop();
+        self.emit(Op::PopHandler);
+        handler
+    }
+
+    // For an early exit, removes the handlers of the try statements we're
+    // leaving (all those after the given number), innermost first, and runs
+    // their finally blocks.
+    fn exit_tries(&mut self, outer: usize) {
+        let exited = self.state_mut().tries.split_off(outer);
+        let hidden: Vec<bool> = self.state().locals.iter().map(|l| l.hidden).collect();
+        for (i, try_) in exited.iter().enumerate().rev() {
+            self.emit(Op::PopHandler);
+            if let Some(finally) = try_.finally {
+                // The finally block runs as if it were outside the try,
+                // where an early exit only needs to leave the tries outside
+                // it.
+                let state = self.state_mut();
+                state.tries.extend(&exited[..i]);
+                for local in &mut state.locals[try_.locals..] {
+                    local.hidden = true;
+                }
+                self.block(finally);
+                self.state_mut().tries.truncate(outer);
+            }
+        }
+        let state = self.state_mut();
+        for (local, hidden) in state.locals.iter_mut().zip(hidden) {
+            local.hidden = hidden;
+        }
+        state.tries.extend(exited);
+    }
+
+    fn function(&mut self, node: &'ast FunctionStmt, type_: FunctionType) {
+        self.at(&node.name);
+        self.states.push(FunctionState::new(
+            &node.name.lexeme,
+            node.parameters.len(),
+            type_,
+            self.source.clone(),
+        ));
+        self.begin_scope();
+        for parameter in &node.parameters {
+            self.at(parameter);
+            self.add_local(&parameter.lexeme);
+        }
+        self.compile_stmts(&node.body);
+        self.emit_return();
+
+        let state = self.states.pop().unwrap();
+        let index = self.chunk().add_function(state.function);
+        let index = self.operand(index, "Too many functions in one chunk.");
+        self.emit(Op::Closure(index));
+    }
+
+    fn compile_expr(&mut self, expr: &'ast Expr) {
+        match expr {
+            Expr::Assign(node) => {
+                self.compile_expr(&node.value);
+                self.at(&node.name);
+                self.set_variable(&node.name, node.resolved_slot.is_none());
+            }
+            Expr::Function(node) => self.function(&node.declaration, FunctionType::Function),
+            Expr::Binary(node) => {
+                self.compile_expr(&node.left);
+                self.compile_expr(&node.right);
+                self.at_expr(&node.operator, expr);
+                self.emit(match node.operator.type_ {
+                    TokenType::Minus => Op::Subtract,
+                    TokenType::Plus => Op::Add,
+                    TokenType::Slash => Op::Divide,
+                    TokenType::Star => Op::Multiply,
+                    TokenType::Greater => Op::Greater,
+                    TokenType::GreaterEqual => Op::GreaterEqual,
+                    TokenType::Less => Op::Less,
+                    TokenType::LessEqual => Op::LessEqual,
+                    TokenType::EqualEqual => Op::Equal,
+                    TokenType::BangEqual => Op::NotEqual,
+                    _ => return self.error("unknown operator (parser bug?)"),
+                });
+            }
+            Expr::Call(node) => {
+                self.compile_expr(&node.callee);
+                for argument in &node.arguments {
+                    self.compile_expr(argument);
+                }
+                self.at_expr(&node.paren, expr);
+                let count =
+                    self.operand(node.arguments.len(), "Can't have more than 255 arguments.");
+                self.emit(Op::Call(count));
+            }
+            Expr::Get(node) => {
+                self.compile_expr(&node.object);
+                self.at(&node.name);
+                let name = self.make_name(&node.name.lexeme);
+                self.emit(Op::GetProperty(name));
+            }
+            Expr::Grouping(node) => self.compile_expr(&node.expr),
+            Expr::Index(node) => {
+                self.compile_expr(&node.object);
+                self.compile_expr(&node.index);
+                self.at_expr(&node.bracket, expr);
+                self.emit(Op::GetIndex);
+            }
+            Expr::List(node) => {
+                for element in &node.elements {
+                    self.compile_expr(element);
+                }
+                self.at(&node.bracket);
+                let count = self.operand(node.elements.len(), "Too many elements in list literal.");
+                self.emit(Op::List(count));
+            }
+            Expr::Literal(node) => {
+                match &node.value {
+                    Literal::Nil => self.emit(Op::Nil),
+                    Literal::Bool(true) => self.emit(Op::True),
+                    Literal::Bool(false) => self.emit(Op::False),
+                    value => {
+                        let index = self.chunk().add_constant(value.clone());
+                        let index = self.operand(index, "Too many constants in one chunk.");
+                        self.emit(Op::Constant(index))
+                    }
+                };
+            }
+            Expr::Logical(node) => {
+                self.compile_expr(&node.left);
+                self.at(&node.operator);
+                if node.operator.type_ == TokenType::And {
+                    let end_jump = self.emit(Op::JumpIfFalse(0));
+                    self.emit(Op::Pop);
+                    self.compile_expr(&node.right);
+                    self.patch_jump(end_jump);
+                } else {
+                    let else_jump = self.emit(Op::JumpIfFalse(0));
+                    let end_jump = self.emit(Op::Jump(0));
+                    self.patch_jump(else_jump);
+                    self.emit(Op::Pop);
+                    self.compile_expr(&node.right);
+                    self.patch_jump(end_jump);
+                }
+            }
+            Expr::Map(node) => {
+                for (key, value) in &node.entries {
+                    self.compile_expr(key);
+                    self.compile_expr(value);
+                }
+                self.at_expr(&node.brace, expr);
+                let count = self.operand(node.entries.len(), "Too many entries in map literal.");
+                self.emit(Op::Map(count));
+            }
+            Expr::Set(node) => {
+                self.compile_expr(&node.object);
+                self.compile_expr(&node.value);
+                self.at(&node.name);
+                let name = self.make_name(&node.name.lexeme);
+                self.emit(Op::SetProperty(name));
+            }
+            Expr::SetIndex(node) => {
+                self.compile_expr(&node.object);
+                self.compile_expr(&node.index);
+                self.compile_expr(&node.value);
+                self.at_expr(&node.bracket, expr);
+                self.emit(Op::SetIndex);
+            }
+            Expr::Super(node) => {
+                self.at(&node.keyword);
+                self.variable(&this_token(&node.keyword), false);
+                self.variable(&node.keyword, false);
+                let name = self.make_name(&node.method.lexeme);
+                self.at(&node.keyword);
+                self.emit(Op::GetSuper(name));
+            }
+            Expr::This(node) => self.variable(&node.keyword, false),
+            Expr::Unary(node) => {
+                self.compile_expr(&node.right);
+                self.at_expr(&node.operator, expr);
+                self.emit(match node.operator.type_ {
+                    TokenType::Bang => Op::Not,
+                    TokenType::Minus => Op::Negate,
+                    _ => return self.error("unknown operator (parser bug?)"),
+                });
+            }
+            Expr::Variable(node) => self.variable(&node.name, node.resolved_slot.is_none()),
+        }
+    }
+
+    // Whether a variable is global is up to the resolver; other variables
+    // we find in our own scopes.
+    fn variable(&mut self, name: &Token, global: bool) {
+        self.at(name);
+        let op = match self.resolve(&name.lexeme, global) {
+            Resolved::Local(slot) => Op::GetLocal(slot),
+            Resolved::Upvalue(index) => Op::GetUpvalue(index),
+            Resolved::Global => Op::GetGlobal(self.make_name(&name.lexeme)),
+        };
+        self.emit(op);
+    }
+
+    fn set_variable(&mut self, name: &Token, global: bool) {
+        let op = match self.resolve(&name.lexeme, global) {
+            Resolved::Local(slot) => Op::SetLocal(slot),
+            Resolved::Upvalue(index) => Op::SetUpvalue(index),
+            Resolved::Global => Op::SetGlobal(self.make_name(&name.lexeme)),
+        };
+        self.emit(op);
+    }
+
+    fn resolve(&mut self, name: &str, global: bool) -> Resolved {
+        if global {
+            return Resolved::Global;
+        }
+        let current = self.states.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name) {
+            Resolved::Local(slot)
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            Resolved::Upvalue(index)
+        } else {
+            Resolved::Global
+        }
+    }
+
+    fn resolve_local(&self, state: usize, name: &str) -> Option<u8> {
+        let locals = &self.states[state].locals;
+        // Slots are bounds-checked when locals are added.
+        #[allow(clippy::cast_possible_truncation)]
+        locals
+            .iter()
+            .rposition(|local| local.name == name && !local.hidden)
+            .map(|slot| slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[slot as usize].is_captured = true;
+            Some(self.add_upvalue(state, slot, true))
+        } else {
+            let index = self.resolve_upvalue(state - 1, name)?;
+            Some(self.add_upvalue(state, index, false))
+        }
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = UpvalueRef { index, is_local };
+        let upvalues = &mut self.states[state].function.upvalues;
+        let i = if let Some(i) = upvalues.iter().position(|u| u == &upvalue) {
+            i
+        } else {
+            upvalues.push(upvalue);
+            upvalues.len() - 1
+        };
+        self.operand(i, "Too many closure variables in function.")
+    }
+
+    fn define_variable(&mut self, name: &'ast Token) {
+        self.at(name);
+        if self.is_global_scope() {
+            let index = self.make_name(&name.lexeme);
+            self.emit(Op::DefineGlobal(index));
+        } else {
+            self.add_local(&name.lexeme);
+        }
+    }
+
+    fn add_local(&mut self, name: &'ast str) {
+        let state = self.states.last_mut().unwrap();
+        if state.locals.len() > usize::from(u8::MAX) {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        state.locals.push(Local {
+            name,
+            depth: state.scope_depth,
+            is_captured: false,
+            hidden: false,
+        });
+    }
+
+    fn is_global_scope(&self) -> bool {
+        self.states.len() == 1 && self.states[0].scope_depth == 0
+    }
+
+    fn begin_scope(&mut self) {
+        self.states.last_mut().unwrap().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.states.last_mut().unwrap();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+        let mut ops = Vec::new();
+        while state.locals.last().is_some_and(|local| local.depth > depth) {
+            let local = state.locals.pop().unwrap();
+            ops.push(if local.is_captured {
+                Op::CloseUpvalue
+            } else {
+                Op::Pop
+            });
+        }
+        for op in ops {
+            self.emit(op);
+        }
+    }
+
+    fn state(&self) -> &FunctionState<'ast> {
+        self.states.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState<'ast> {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.states.last_mut().unwrap().function.chunk
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let (line, span) = (self.line, self.span);
+        self.chunk().write(op, line, span)
+    }
+
+    fn at(&mut self, token: &Token) {
+        self.line = token.line;
+        self.span = token.span;
+    }
+
+    // For ops whose errors are about a whole expression, though we report
+    // them on the line of one of its tokens.
+    fn at_expr(&mut self, token: &Token, expr: &Expr) {
+        self.line = token.line;
+        self.span = expr.span();
+    }
+
+    fn emit_return(&mut self) {
+        self.emit_return_value();
+        self.emit(Op::Return);
+    }
+
+    // The value a function returns when it doesn't say.
+    fn emit_return_value(&mut self) {
+        if self.state().type_ == FunctionType::Initializer {
+            self.emit(Op::GetLocal(0));
+        } else {
+            self.emit(Op::Nil);
+        }
+    }
+
+    fn patch_jump(&mut self, index: usize) {
+        let offset = self.chunk().code.len() - index - 1;
+        let offset = self.operand(offset, "Too much code to jump over.");
+        let code = &mut self.chunk().code;
+        code[index] = match code[index] {
+            Op::Jump(_) => Op::Jump(offset),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(offset),
+            Op::PushHandler(_) => Op::PushHandler(offset),
+            op => panic!("can't patch non-jump {op:?}"),
+        };
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        let offset = self.chunk().code.len() - loop_start + 1;
+        let offset = self.operand(offset, "Loop body too large.");
+        self.emit(Op::Loop(offset));
+    }
+
+    fn make_name(&mut self, name: &str) -> u16 {
+        let index = self.chunk().add_name(name);
+        self.operand(index, "Too many constants in one chunk.")
+    }
+
+    // Narrows an index or count to fit in an operand, reporting an error
+    // (and returning a placeholder) if it doesn't.
+    fn operand<T: TryFrom<usize> + Default>(&mut self, value: usize, message: &str) -> T {
+        T::try_from(value).unwrap_or_else(|_| {
+            self.error(message);
+            T::default()
+        })
+    }
+
+    fn error(&mut self, message: &str) {
+        self.errors.push(LoxError {
+            code: ErrorCode::Compile,
+            line: self.line,
+            loc: String::new(),
+            exit: 65,
+            message: message.to_string(),
+            trace: Vec::new(),
+            snippet: Some(Box::new(Snippet::new(&self.source, self.span))),
+            more: Vec::new(),
+        });
+    }
+}
+
+enum Resolved {
+    Local(u8),
+    Upvalue(u8),
+    Global,
+}
+
+fn this_token(keyword: &Token) -> Token {
+    let mut token = keyword.clone();
+    token.lexeme = Rc::from("this");
+    token
+}
@@ -1,115 +1,265 @@
+use crate::ast::Slot;
+use crate::gc::{self, Heap, Trace};
+use crate::list::Element;
 use crate::object::Object;
 use crate::scanner;
 use crate::unwind::Unwinder;
+use itertools::Itertools;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
 use std::rc::Rc;
 
 // TODO: type alias for Rc<RefCell<Environment<>>>, to elide the .borrow().thing()?
-pub struct Environment<'ast, 'src: 'ast> {
-    values: HashMap<String, Object<'ast, 'src>>,
-    enclosing: Option<Rc<RefCell<Environment<'ast, 'src>>>>,
+pub enum Environment {
+    // The resolver doesn't track globals, so we look them up by name.
+    Global(HashMap<String, Object>),
+    // Everything else is indexed by the slot the resolver assigned, which
+    // is the order in which variables are defined.
+    Local {
+        slots: Vec<Object>,
+        enclosing: Rc<RefCell<Environment>>,
+        // Whether the cycle collector knows about us; see track.
+        tracked: bool,
+    },
 }
 
-impl Environment<'_, '_> {
+// Dumps of an environment start and end with these lines.
+const HEADER: &str = "===================== environment =====================";
+const FOOTER: &str = "=======================================================";
+
+// Writes global variables, sorted by name.
+fn fmt_globals<V: fmt::Display>(
+    f: &mut impl fmt::Write,
+    values: &HashMap<String, V>,
+    depth: usize,
+) -> fmt::Result {
+    for (k, v) in values.iter().sorted_by_key(|(k, _)| *k) {
+        writeln!(f, "{}{} = {}", "\t".repeat(depth), k, v)?;
+    }
+    Ok(())
+}
+
+// Dumps globals kept in a plain map, as the VM does, in the same form as a
+// global environment.
+pub fn dump_globals<V: fmt::Display>(values: &HashMap<String, V>) -> String {
+    let mut dump = format!("{HEADER}\n");
+    fmt_globals(&mut dump, values, 0).unwrap();
+    dump + FOOTER + "\n"
+}
+
+impl Environment {
     fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
         if depth == 0 {
-            writeln!(f, "===================== environment =====================")?;
-        }
-        for (k, v) in &self.values {
-            writeln!(f, "{}{} = {}", "\t".repeat(depth), k, v)?;
+            writeln!(f, "{HEADER}")?;
         }
-        if let Some(next) = &self.enclosing {
-            next.borrow().fmt_indented(f, depth + 1)?;
+        match self {
+            Environment::Global(values) => fmt_globals(f, values, depth)?,
+            Environment::Local {
+                slots, enclosing, ..
+            } => {
+                for (i, v) in slots.iter().enumerate() {
+                    writeln!(f, "{}#{} = {}", "\t".repeat(depth), i, v)?;
+                }
+                enclosing.borrow().fmt_indented(f, depth + 1)?;
+            }
         }
         if depth == 0 {
-            writeln!(f, "=======================================================")?;
+            writeln!(f, "{FOOTER}")?;
         }
         Ok(())
     }
 }
 
-impl fmt::Debug for Environment<'_, '_> {
+impl fmt::Debug for Environment {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.fmt_indented(f, 0)
     }
 }
 
-impl<'ast, 'src: 'ast> Environment<'ast, 'src> {
-    pub fn new() -> Self {
-        Self {
-            values: HashMap::new(),
-            enclosing: None,
+impl Environment {
+    pub fn child(inner: Rc<RefCell<Environment>>) -> Self {
+        Self::with_slots(inner, Vec::new())
+    }
+
+    // A child environment whose first variables are already defined.
+    pub fn with_slots(inner: Rc<RefCell<Environment>>, slots: Vec<Object>) -> Self {
+        Environment::Local {
+            slots,
+            enclosing: inner,
+            tracked: false,
         }
     }
 
-    pub fn child(inner: Rc<RefCell<Environment<'ast, 'src>>>) -> Self {
-        Self {
-            values: HashMap::new(),
-            enclosing: Some(inner),
+    pub fn define(&mut self, name: &str, value: Object) {
+        match self {
+            Environment::Global(values) => {
+                values.insert(name.to_string(), value);
+            }
+            Environment::Local { slots, .. } => slots.push(value),
         }
     }
 
-    pub fn define(&mut self, name: &'src str, value: Object<'ast, 'src>) {
-        self.values.insert(name.to_string(), value);
+    // Looks up a global by name, for the host.
+    pub fn get_global(&self, name: &str) -> Option<Object> {
+        match self {
+            Environment::Global(values) => values.get(name).cloned(),
+            Environment::Local { enclosing, .. } => enclosing.borrow().get_global(name),
+        }
     }
 
-    pub fn get_at(
-        &self,
-        depth: usize,
-        name: &scanner::Token<'src>,
-    ) -> Result<Object<'ast, 'src>, Unwinder<'ast, 'src>> {
-        if depth == 0 {
-            self.get(name)
-        } else {
-            let next = self.enclosing.as_ref().unwrap().borrow();
-            next.get_at(depth - 1, name)
+    // Every global, for the REPL.
+    pub fn globals(&self) -> Vec<(String, Object)> {
+        match self {
+            Environment::Global(values) => values
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            Environment::Local { enclosing, .. } => enclosing.borrow().globals(),
+        }
+    }
+
+    // Defines (or redefines) a global, for the host.
+    pub fn define_global(&mut self, name: &str, value: Object) {
+        match self {
+            Environment::Global(values) => {
+                values.insert(name.to_string(), value);
+            }
+            Environment::Local { enclosing, .. } => {
+                enclosing.borrow_mut().define_global(name, value);
+            }
         }
     }
 
-    pub fn get(
-        &self,
-        name: &scanner::Token<'src>,
-    ) -> Result<Object<'ast, 'src>, Unwinder<'ast, 'src>> {
-        match (self.values.get(name.lexeme), &self.enclosing) {
-            (Some(obj), _) => Ok(obj.clone()),
-            (None, Some(enclosing)) => enclosing.borrow().get(name),
-            (None, None) => undefined(name),
+    pub fn get_at(&self, slot: Slot, name: &scanner::Token) -> Result<Object, Unwinder> {
+        match (self, slot.depth) {
+            (Environment::Local { slots, .. }, 0) => match slots.get(slot.index) {
+                Some(obj) => Ok(obj.clone()),
+                None => unresolved(name),
+            },
+            (Environment::Local { enclosing, .. }, depth) => enclosing.borrow().get_at(
+                Slot {
+                    depth: depth - 1,
+                    index: slot.index,
+                },
+                name,
+            ),
+            (Environment::Global(_), _) => unresolved(name),
+        }
+    }
+
+    pub fn get(&self, name: &scanner::Token) -> Result<Object, Unwinder> {
+        match self {
+            Environment::Global(values) => match values.get(&*name.lexeme) {
+                Some(obj) => Ok(obj.clone()),
+                None => undefined(name),
+            },
+            Environment::Local { enclosing, .. } => enclosing.borrow().get(name),
         }
     }
 
     pub fn assign_at(
         &mut self,
-        depth: usize,
-        name: &scanner::Token<'src>,
-        value: Object<'ast, 'src>,
-    ) -> Result<(), Unwinder<'ast, 'src>> {
-        if depth == 0 {
-            self.assign(name, value)
-        } else {
-            let mut next = self.enclosing.as_ref().unwrap().borrow_mut();
-            next.assign_at(depth - 1, name, value)
+        slot: Slot,
+        name: &scanner::Token,
+        value: Object,
+    ) -> Result<(), Unwinder> {
+        match (self, slot.depth) {
+            (Environment::Local { slots, .. }, 0) => match slots.get_mut(slot.index) {
+                Some(obj) => {
+                    *obj = value;
+                    Ok(())
+                }
+                None => unresolved(name),
+            },
+            (Environment::Local { enclosing, .. }, depth) => enclosing.borrow_mut().assign_at(
+                Slot {
+                    depth: depth - 1,
+                    index: slot.index,
+                },
+                name,
+                value,
+            ),
+            (Environment::Global(_), _) => unresolved(name),
         }
     }
 
-    pub fn assign(
-        &mut self,
-        name: &scanner::Token<'src>,
-        value: Object<'ast, 'src>,
-    ) -> Result<(), Unwinder<'ast, 'src>> {
-        if self.values.contains_key(name.lexeme) {
-            self.define(name.lexeme, value);
-            Ok(())
-        } else {
-            match &self.enclosing {
-                Some(enclosing) => enclosing.borrow_mut().assign(name, value),
+    pub fn assign(&mut self, name: &scanner::Token, value: Object) -> Result<(), Unwinder> {
+        match self {
+            Environment::Global(values) => match values.get_mut(&*name.lexeme) {
+                Some(obj) => {
+                    *obj = value;
+                    Ok(())
+                }
                 None => undefined(name),
+            },
+            Environment::Local { enclosing, .. } => enclosing.borrow_mut().assign(name, value),
+        }
+    }
+}
+
+// Most environments (e.g. those of function calls) are gone as soon as we're
+// done with them, so we only track them once a closure captures them, which
+// is the only way they can end up in a cycle. This tracks the environment a
+// new closure captures, and those enclosing it (which it refers to).
+pub fn track(environment: &Rc<RefCell<Environment>>, heap: &mut Heap) {
+    let mut environment = environment.clone();
+    loop {
+        let enclosing = match &mut *environment.borrow_mut() {
+            Environment::Local {
+                enclosing,
+                tracked: tracked @ false,
+                ..
+            } => {
+                *tracked = true;
+                enclosing.clone()
+            }
+            // Once one is tracked, so are those enclosing it; globals live
+            // as long as the interpreter anyway.
+            _ => return,
+        };
+        heap.adopt(&environment);
+        environment = enclosing;
+    }
+}
+
+impl Trace for RefCell<Environment> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(env) = self.try_borrow() else {
+            return false;
+        };
+        match &*env {
+            Environment::Global(values) => values.values().for_each(|v| v.trace(visit)),
+            Environment::Local {
+                slots, enclosing, ..
+            } => {
+                for v in slots {
+                    v.trace(visit);
+                }
+                visit(gc::address(enclosing));
             }
         }
+        true
     }
+
+    fn clear(&self) {
+        // Drop the values only once we've released our borrow, in case that
+        // frees something that looks at us.
+        let values: Vec<_> = match &mut *self.borrow_mut() {
+            Environment::Global(values) => values.drain().map(|(_, v)| v).collect(),
+            Environment::Local { slots, .. } => std::mem::take(slots),
+        };
+        drop(values);
+    }
+}
+
+fn undefined<T>(name: &scanner::Token) -> Result<T, Unwinder> {
+    Unwinder::err(name, &format!("Undefined variable '{}'.", &name.lexeme))
 }
 
-fn undefined<'ast, 'src: 'ast, T>(name: &scanner::Token<'src>) -> Result<T, Unwinder<'ast, 'src>> {
-    Unwinder::err(name, &format!("Undefined variable '{}'.", name.lexeme))
+fn unresolved<T>(name: &scanner::Token) -> Result<T, Unwinder> {
+    Unwinder::bug(
+        name,
+        &format!("No slot for variable '{}' (resolver bug?).", &name.lexeme),
+    )
 }
@@ -1,13 +1,175 @@
-use crate::scanner;
+use crate::ast::Expr;
+use crate::object::Literal;
+use crate::scanner::{self, Span};
 use itertools::Itertools;
-use std::fmt;
+use std::fmt::{self, Write};
+use std::iter;
 
+/// An error in some Lox code, found before or while running it, with any
+/// others found at the same time.
 #[derive(Debug, Clone)]
 pub struct LoxError {
+    pub code: ErrorCode,
     pub line: usize,
     pub loc: String,
     pub exit: u8,
     pub message: String,
+    /// For runtime errors in functions, the calls in progress, outermost (the
+    /// script) first.
+    pub trace: Vec<CallSite>,
+    /// The code at fault, if we know it.
+    pub snippet: Option<Box<Snippet>>,
+    /// Any further errors found at the same time, e.g. by the parser.
+    pub more: Vec<LoxError>,
+}
+
+/// How deeply Lox calls can nest before we report a stack overflow, unless
+/// the host says otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+const TRACEBACK_REPEATS: usize = 3;
+
+/// What kind of error this is. Unlike the messages, which we reword now and
+/// then, the codes stay the same, so tools can rely on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Lexical,
+    Syntax,
+    Resolution,
+    /// Programs too big for the VM (too many constants, locals, etc).
+    Compile,
+    Runtime,
+    StackOverflow,
+    UncaughtException,
+    Import,
+    /// Not really an error: the program called `exit()`, and this is how we
+    /// stop everything and tell the host, whose exit status is `exit`.
+    Exit,
+    /// A bug in the interpreter itself (e.g. something the resolver should
+    /// have caught), which Lox code can't catch.
+    Internal,
+}
+
+impl ErrorCode {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Lexical => "E0001",
+            ErrorCode::Syntax => "E0002",
+            ErrorCode::Resolution => "E0003",
+            ErrorCode::Compile => "E0004",
+            ErrorCode::Runtime => "E0100",
+            ErrorCode::StackOverflow => "E0101",
+            ErrorCode::UncaughtException => "E0102",
+            ErrorCode::Import => "E0103",
+            ErrorCode::Exit => "E0104",
+            ErrorCode::Internal => "E0105",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+
+impl Severity {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// One problem, in a form for tools (editors, CI) rather than people.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    /// Where exactly, if we know.
+    pub column: Option<usize>,
+    pub span: Option<Span>,
+    /// Anything else worth knowing, e.g. the calls that led to the error.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    #[must_use]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "code": self.code.as_str(),
+            "severity": self.severity.as_str(),
+            "message": self.message,
+            "line": self.line,
+            "column": self.column,
+            "span": self.span.map(|span| serde_json::json!({
+                "start": span.start,
+                "end": span.end,
+            })),
+            "notes": self.notes,
+        })
+    }
+}
+
+/// The code an error is about. We take a copy of its (first) line when we make
+/// the error, since by the time we report it the source may be long gone, and
+/// in any case isn't always the main program's (e.g. for modules).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    pub span: Span,
+    pub line: usize,
+    /// Where the span starts on its line, counting characters from 1.
+    pub column: usize,
+    pub text: String,
+    /// How many characters of the line the span covers (at least one).
+    pub width: usize,
+}
+
+impl Snippet {
+    pub fn new(source: &str, span: Span) -> Self {
+        let start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let end = source[span.start..]
+            .find('\n')
+            .map_or(source.len(), |i| span.start + i);
+        Snippet {
+            span,
+            line: source[..span.start].matches('\n').count() + 1,
+            column: source[start..span.start].chars().count() + 1,
+            text: source[start..end].to_string(),
+            width: source[span.start..span.end.min(end)].chars().count().max(1),
+        }
+    }
+}
+
+// The line, then the span underlined, e.g.:
+//
+//     2 |   return x + nil;
+//       |          ^^^^^^^
+impl fmt::Display for Snippet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // Keep any tabs, so the carets line up.
+        let indent: String = self
+            .text
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(self.width);
+        write!(f, "{number} | {}\n{gutter} | {indent}{carets}", self.text)
+    }
+}
+
+/// A Lox function (or the script) in the middle of running: its name, and the
+/// line it's on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSite {
+    pub function: String,
+    pub line: usize,
 }
 
 impl fmt::Display for LoxError {
@@ -16,23 +178,102 @@ impl fmt::Display for LoxError {
             f,
             "[line {}] Error{}: {}",
             self.line, self.loc, self.message
-        )
+        )?;
+        for error in &self.more {
+            write!(f, "\n{error}")?;
+        }
+        Ok(())
     }
 }
 
-impl From<Vec<LoxError>> for LoxError {
-    fn from(value: Vec<LoxError>) -> Self {
-        if value.len() <= 1 {
-            value[0].clone()
-        } else {
-            let mut first = value[0].clone();
-            first.message = format!(
-                "{}\n{}",
-                first.message,
-                value[1..].iter().map(|err| format!("{err}")).join("\n")
-            );
-            first
+impl LoxError {
+    /// The given error, reported along with the others after it.
+    #[must_use]
+    pub fn with_more(first: LoxError, more: Vec<LoxError>) -> Self {
+        LoxError { more, ..first }
+    }
+
+    /// The error in full, for people: with the calls that led to it, like a
+    /// Python traceback, and the code at fault.
+    pub fn report(&self) -> String {
+        let errors = iter::once(self).chain(&self.more);
+        let errors = errors.map(LoxError::with_snippet).join("\n");
+        if self.trace.is_empty() {
+            return errors;
+        }
+        let mut calls = String::new();
+        for call in self.traceback() {
+            writeln!(calls, "  {call}").unwrap();
+        }
+        format!("Traceback (most recent call last):\n{calls}{errors}")
+    }
+
+    /// The error (and any others with it), for tools.
+    #[must_use]
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        iter::once(self)
+            .chain(&self.more)
+            .map(|error| Diagnostic {
+                code: error.code,
+                severity: Severity::Error,
+                message: error.message.clone(),
+                line: error.line,
+                column: error.snippet.as_ref().map(|s| s.column),
+                span: error.snippet.as_ref().map(|s| s.span),
+                notes: error.traceback(),
+            })
+            .collect()
+    }
+
+    // The calls that led to the error, outermost first, if it happened in a
+    // function.
+    fn traceback(&self) -> Vec<String> {
+        if self.trace.is_empty() {
+            return Vec::new();
+        }
+        let trace = self.trace.iter().map(|c| (&c.function, c.line));
+        // As in Python, runs of the same call (i.e. recursion) are cut short.
+        let mut calls = Vec::new();
+        for (run, (function, line)) in trace.dedup_with_count() {
+            for _ in 0..run.min(TRACEBACK_REPEATS) {
+                calls.push(format!("[line {line}] in {function}"));
+            }
+            if run > TRACEBACK_REPEATS {
+                let more = run - TRACEBACK_REPEATS;
+                calls.push(format!("[Previous line repeated {more} more times]"));
+            }
         }
+        calls
+    }
+
+    // This error alone (without the others in `more`), and its snippet.
+    fn with_snippet(&self) -> String {
+        let message = format!("[line {}] Error{}: {}", self.line, self.loc, self.message);
+        match &self.snippet {
+            Some(snippet) => format!("{message}\n{snippet}"),
+            None => message,
+        }
+    }
+
+    /// The properties Lox code sees on a caught runtime error.
+    #[must_use]
+    pub fn property(&self, name: &str) -> Option<Literal> {
+        match name {
+            "message" => Some(Literal::String(self.message.clone())),
+            #[allow(clippy::cast_precision_loss)]
+            "line" => Some(Literal::Number(self.line as f64)),
+            _ => None,
+        }
+    }
+}
+
+// The errors a pass over the code found, as one error: the first, with the
+// others in its `more`.
+pub fn check_errors(errors: Vec<LoxError>) -> Result<(), LoxError> {
+    let mut errors = errors.into_iter();
+    match errors.next() {
+        Some(first) => Err(LoxError::with_more(first, errors.collect())),
+        None => Ok(()),
     }
 }
 
@@ -40,21 +281,67 @@ pub fn parse_error(token: &scanner::Token, message: &str) -> LoxError {
     let loc = if token.type_ == scanner::TokenType::EOF {
         " at end".to_string()
     } else {
-        format!(" at '{}'", token.lexeme)
+        format!(" at '{}'", &token.lexeme)
     };
     LoxError {
+        code: ErrorCode::Syntax,
         line: token.line,
         loc,
         exit: 65,
         message: message.to_string(),
+        trace: Vec::new(),
+        snippet: Some(Box::new(Snippet::new(&token.source, token.span))),
+        more: Vec::new(),
+    }
+}
+
+// An error the resolver found: reported just like a parse error.
+pub fn resolve_error(token: &scanner::Token, message: &str) -> LoxError {
+    LoxError {
+        code: ErrorCode::Resolution,
+        ..parse_error(token, message)
     }
 }
 
 pub fn runtime_error(token: &scanner::Token, message: &str) -> LoxError {
     LoxError {
+        code: ErrorCode::Runtime,
         line: token.line,
         loc: String::new(),
         exit: 70,
         message: message.to_string(),
+        trace: Vec::new(),
+        snippet: Some(Box::new(Snippet::new(&token.source, token.span))),
+        more: Vec::new(),
+    }
+}
+
+// A runtime error that's our fault, not the program's.
+pub fn internal_error(token: &scanner::Token, message: &str) -> LoxError {
+    LoxError {
+        code: ErrorCode::Internal,
+        ..runtime_error(token, message)
+    }
+}
+
+pub fn exit_error(status: u8) -> LoxError {
+    LoxError {
+        code: ErrorCode::Exit,
+        line: 0,
+        loc: String::new(),
+        exit: status,
+        message: format!("Exited with status {status}."),
+        trace: Vec::new(),
+        snippet: None,
+        more: Vec::new(),
+    }
+}
+
+// A runtime error about a whole expression, reported on the line of the given
+// token within it.
+pub fn expr_error(token: &scanner::Token, expr: &Expr, message: &str) -> LoxError {
+    LoxError {
+        snippet: Some(Box::new(Snippet::new(&token.source, expr.span()))),
+        ..runtime_error(token, message)
     }
 }
@@ -0,0 +1,88 @@
+use crate::builtins;
+use crate::gc::Heap;
+use crate::list::Element;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+// The "file" module, for working with files. Its functions' failures are the
+// OS's errors, as Lox runtime errors, e.g. "Can't read 'a.txt': No such file
+// or directory (os error 2)."
+pub fn module<V: Element + 'static>(heap: &Rc<RefCell<Heap>>) -> V {
+    let mut functions = HashMap::new();
+    builtins::define(&mut functions, "readFile", |path: String| read_file(&path));
+    builtins::define(&mut functions, "writeFile", |path: String, text: String| {
+        write_file(&path, &text)
+    });
+    builtins::define(
+        &mut functions,
+        "appendFile",
+        |path: String, text: String| append_file(&path, &text),
+    );
+    builtins::define(&mut functions, "exists", |path: String| exists(&path));
+    let heap = heap.clone();
+    builtins::define(&mut functions, "listDir", move |path: String| {
+        let names = list_dir(&path)?;
+        Ok::<V, String>(builtins::string_list(&heap, names))
+    });
+    builtins::define(&mut functions, "remove", |path: String| remove(&path));
+    builtins::define(&mut functions, "mkdir", |path: String| mkdir(&path));
+    V::module("file", functions)
+}
+
+fn failed(action: &str, path: &str) -> impl FnOnce(io::Error) -> String {
+    let message = format!("Can't {action} '{path}'");
+    move |err| format!("{message}: {err}.")
+}
+
+pub fn read_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(failed("read", path))
+}
+
+// Replaces the file's contents, creating it if need be.
+pub fn write_file(path: &str, text: &str) -> Result<(), String> {
+    fs::write(path, text).map_err(failed("write", path))
+}
+
+// Adds to the end of the file, creating it if need be.
+pub fn append_file(path: &str, text: &str) -> Result<(), String> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(failed("append to", path))
+}
+
+pub fn exists(path: &str) -> bool {
+    Path::new(path).exists()
+}
+
+// The names of the entries in a directory, sorted.
+pub fn list_dir(path: &str) -> Result<Vec<String>, String> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path).map_err(failed("list", path))? {
+        let entry = entry.map_err(failed("list", path))?;
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names)
+}
+
+// Removes a file, or an empty directory.
+pub fn remove(path: &str) -> Result<(), String> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(path),
+        _ => fs::remove_file(path),
+    };
+    result.map_err(failed("remove", path))
+}
+
+// Makes a directory, and any parents it needs; it's fine if it's there
+// already.
+pub fn mkdir(path: &str) -> Result<(), String> {
+    fs::create_dir_all(path).map_err(failed("make directory", path))
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+// Our objects are all reference-counted, which frees everything except
+// cycles (e.g. an instance whose field holds one of its own bound methods,
+// whose closure refers back to the instance). To find those, we track every
+// object that can be part of a cycle, and periodically look for groups of
+// them that are only referenced by each other.
+//
+// Since we can't see references held on the Rust stack, we don't trace from
+// roots. Instead, as in CPython, we subtract each reference we can see
+// between tracked objects from their strong counts: anything with
+// references left over is referenced from somewhere else, and so live.
+
+// Something that can hold references to tracked objects.
+pub trait Trace {
+    // Calls visit with the address of each traced object this one
+    // references, once per reference. Returns false if the object can't be
+    // inspected right now (because it's mutably borrowed), in which case we
+    // assume it's live.
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool;
+
+    // Drops whatever references this object holds that could be part of a
+    // cycle, once we know it's garbage.
+    fn clear(&self);
+}
+
+// The address visited by Trace::trace for a given reference.
+pub fn address<T: ?Sized>(rc: &Rc<T>) -> *const () {
+    address_of(Rc::as_ptr(rc))
+}
+
+// The same, given the object itself.
+pub fn address_of<T: ?Sized>(object: *const T) -> *const () {
+    object.cast()
+}
+
+const MIN_THRESHOLD: usize = 10_000;
+
+pub struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    // Allocations since the last collection, and how many we allow before
+    // the next one.
+    allocations: usize,
+    threshold: usize,
+    collections: usize,
+    freed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub live: usize,
+    pub collections: usize,
+    pub freed: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} live objects, {} collections, {} freed",
+            self.live, self.collections, self.freed
+        )
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            allocations: 0,
+            threshold: MIN_THRESHOLD,
+            collections: 0,
+            freed: 0,
+        }
+    }
+
+    pub fn track<T: Trace + 'static>(&mut self, object: T) -> Rc<T> {
+        let rc = Rc::new(object);
+        self.adopt(&rc);
+        rc
+    }
+
+    // Starts tracking an object made without track, e.g. once it might
+    // become part of a cycle.
+    pub fn adopt<T: Trace + 'static>(&mut self, rc: &Rc<T>) {
+        self.allocations += 1;
+        if self.allocations >= self.threshold {
+            self.collect();
+        }
+        let weak: Weak<T> = Rc::downgrade(rc);
+        self.objects.push(weak);
+    }
+
+    // Frees all unreachable cycles, returning the number of objects freed.
+    pub fn collect(&mut self) -> usize {
+        let objects: Vec<Rc<dyn Trace>> = self.objects.iter().filter_map(Weak::upgrade).collect();
+        self.objects = objects.iter().map(Rc::downgrade).collect();
+        let index: HashMap<*const (), usize> = objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (address(object), i))
+            .collect();
+
+        // Don't count the reference we just took ourselves.
+        let mut external: Vec<usize> = objects
+            .iter()
+            .map(|object| Rc::strong_count(object) - 1)
+            .collect();
+        let mut children: Vec<Option<Vec<usize>>> = Vec::with_capacity(objects.len());
+        for object in &objects {
+            let mut referenced = Vec::new();
+            let traced = object.trace(&mut |ptr| {
+                if let Some(&i) = index.get(&ptr) {
+                    referenced.push(i);
+                }
+            });
+            if traced {
+                for &i in &referenced {
+                    external[i] -= 1;
+                }
+                children.push(Some(referenced));
+            } else {
+                children.push(None);
+            }
+        }
+
+        let mut live = vec![false; objects.len()];
+        let mut stack: Vec<usize> = (0..objects.len())
+            .filter(|&i| external[i] > 0 || children[i].is_none())
+            .collect();
+        while let Some(i) = stack.pop() {
+            if !live[i] {
+                live[i] = true;
+                stack.extend(children[i].iter().flatten());
+            }
+        }
+
+        let mut freed = 0;
+        for (object, live) in objects.iter().zip(live) {
+            if !live {
+                object.clear();
+                freed += 1;
+            }
+        }
+
+        self.allocations = 0;
+        self.threshold = MIN_THRESHOLD.max(2 * (objects.len() - freed));
+        self.collections += 1;
+        self.freed += freed;
+        freed
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            live: self
+                .objects
+                .iter()
+                .filter(|object| object.strong_count() > 0)
+                .count(),
+            collections: self.collections,
+            freed: self.freed,
+        }
+    }
+}
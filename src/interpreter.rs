@@ -1,79 +1,269 @@
 use crate::ast::*;
-use crate::environment::Environment;
-use crate::error::{runtime_error, LoxError};
-use crate::object::{instance_get, BuiltinFunction, Class, Function, Instance, Literal, Object};
-#[cfg(test)]
+use crate::builtins::{self, Input};
+use crate::environment::{self, Environment};
+use crate::error::{
+    exit_error, expr_error, internal_error, runtime_error, CallSite, ErrorCode, LoxError, Snippet,
+    DEFAULT_MAX_DEPTH,
+};
+use crate::file;
+use crate::gc::Heap;
+use crate::map::Map;
+use crate::module::{Import, Modules};
+use crate::native::{NativeError, NativeFunction};
+use crate::object::{
+    instance_get, list_get, map_get, module_get, Class, Function, Instance, Literal, Module, Object,
+};
 use crate::parser;
-#[cfg(test)]
 use crate::resolver;
 use crate::scanner;
 use crate::scanner::TokenType;
 use crate::unwind::Unwinder;
+#[cfg(test)]
+use crate::vm;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::BufRead;
+use std::iter;
+use std::path::Path;
 use std::rc::Rc;
-use std::time;
 
-pub struct Interpreter<'ast, 'src: 'ast, F: FnMut(String)> {
+/// The tree-walking interpreter, which hosts can define functions and
+/// globals on; `F` is what it calls to print.
+pub struct Interpreter<F: FnMut(String)> {
     // TODO: define printer as a global (even if it's still a magic statement)?
     printer: F,
-    globals: Rc<RefCell<Environment<'ast, 'src>>>,
-    environment: Rc<RefCell<Environment<'ast, 'src>>>,
+    // Each module starts with its own copy of these globals.
+    builtins: HashMap<String, Object>,
+    environment: Rc<RefCell<Environment>>,
+    heap: Rc<RefCell<Heap>>,
+    modules: Modules<Object>,
+    // The Lox functions we're in the middle of, and the lines they were
+    // called from, for tracebacks.
+    call_stack: Vec<(Rc<str>, usize)>,
+    max_depth: usize,
+    input: Rc<RefCell<Input>>,
 }
 
-fn now_sec<'ast, 'src: 'ast>() -> Result<Object<'ast, 'src>, LoxError> {
-    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-        Ok(t) => Ok(Literal::Number(t.as_secs_f64()).into()),
-        Err(e) => panic!("{e}"),
-    }
+// When less than this much Rust stack is left, calls continue on a new
+// segment of this size. Checking takes long enough to show up in call-heavy
+// code, so we only do it every few calls, which the red zone has to have
+// room for: up to 100K or so each in debug builds, a tenth of that when
+// optimized. The segments add up, so a raised maximum depth is a raised
+// memory limit too: recursing 100,000 calls deep takes some 8GB in debug
+// builds.
+const STACK_RED_ZONE: usize = 1024 * 1024;
+const STACK_SEGMENT: usize = 8 * 1024 * 1024;
+const STACK_CHECK_INTERVAL: usize = 8;
+
+/// An interpreter whose print statements write to stdout.
+#[must_use]
+pub fn interpreter() -> Interpreter<impl FnMut(String)> {
+    Interpreter::new(|s| println!("{s}"), builtins::now_sec)
 }
 
-pub fn interpreter<'ast, 'src: 'ast>() -> Interpreter<'ast, 'src, impl FnMut(String)> {
-    let globals = Rc::new(RefCell::new(Environment::new()));
-    globals.borrow_mut().define(
-        "clock",
-        BuiltinFunction {
-            arity: 0,
-            function: Rc::new(RefCell::new(|_| now_sec())),
-            name: "clock".to_string(),
+impl<F: FnMut(String)> Interpreter<F> {
+    /// An interpreter whose print statements call the given function (with
+    /// each line, less its newline) rather than writing to stdout.
+    pub fn with_printer(printer: F) -> Self {
+        Self::new(printer, builtins::now_sec)
+    }
+
+    fn new(printer: F, clock: impl FnMut() -> f64 + 'static) -> Self {
+        let heap = Rc::new(RefCell::new(Heap::new()));
+        let input = Rc::new(RefCell::new(Input::Stdin));
+        let builtins = builtins::globals(&heap, clock, &input);
+        let mut modules = Modules::new();
+        modules.define("file", file::module(&heap));
+        Interpreter {
+            printer,
+            environment: Rc::new(RefCell::new(Environment::Global(builtins.clone()))),
+            builtins,
+            heap,
+            modules,
+            call_stack: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            input,
         }
-        .into(),
-    );
-    Interpreter {
-        printer: |s| println!("{s}"),
-        globals: globals.clone(),
-        environment: globals.clone(),
     }
-}
 
-impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
-    pub fn execute_program(&mut self, node: &'ast Program<'src>) -> Result<(), LoxError> {
+    // The environment for a closure we're making to capture.
+    fn capture_environment(&self) -> Rc<RefCell<Environment>> {
+        environment::track(&self.environment, &mut self.heap.borrow_mut());
+        self.environment.clone()
+    }
+
+    /// Tells us the file the program came from, so we can find its imports.
+    pub fn set_script(&mut self, path: &Path) {
+        self.modules.set_script(path);
+    }
+
+    /// Forgets the file `set_script` gave, so imports are relative to the
+    /// current directory again.
+    pub fn clear_script(&mut self) {
+        self.modules.clear_script();
+    }
+
+    /// Sets how deeply Lox calls can nest before we report a stack overflow.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// Has `readLine()` and `readAll()` read from the given input, rather than
+    /// stdin.
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        *self.input.borrow_mut() = Input::Reader(Box::new(input));
+    }
+
+    /// Defines the global `args`, a list of the given strings (e.g. the
+    /// script's command-line arguments), in the program and its modules.
+    pub fn set_args(&mut self, args: impl IntoIterator<Item = String>) {
+        let args: Object = builtins::string_list(&self.heap, args);
+        self.builtins.insert("args".to_string(), args.clone());
+        self.environment.borrow_mut().define_global("args", args);
+    }
+
+    /// Runs some Lox code, returning the value of its last statement if
+    /// that's an expression (and nil otherwise), e.g. 3 for "1 + 2;".
+    /// Definitions are kept, for later code to use.
+    ///
+    /// # Errors
+    ///
+    /// Any errors in the code, found before or while running it. If the
+    /// code calls `exit`, an error with code [`ErrorCode::Exit`].
+    pub fn eval(&mut self, source: &str) -> Result<Object, LoxError> {
+        let tokens = scanner::scan_tokens(source)?;
+        let mut prog = parser::parse(tokens)?;
+        resolver::resolve(&mut prog)?;
+        let (stmts, last) = match prog.stmts.split_last() {
+            Some((Stmt::Expr(last), stmts)) => (stmts, Some(&last.expr)),
+            _ => (&prog.stmts[..], None),
+        };
+        Self::program_result(self.execute_stmts(stmts, self.environment.clone()))?;
+        match last {
+            Some(expr) => Self::program_result(self.evaluate(expr)),
+            None => Ok(Literal::Nil.into()),
+        }
+    }
+
+    /// Runs a line typed at the REPL, printing the value of an expression
+    /// typed without its semicolon.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Interpreter::eval`].
+    pub fn eval_line(&mut self, source: &str) -> Result<(), LoxError> {
+        let tokens = scanner::scan_tokens(source)?;
+        let mut prog = parser::parse_repl(tokens)?;
+        resolver::resolve(&mut prog)?;
+        self.execute_program(&prog)
+    }
+
+    /// Defines a global function, implemented in Rust, for Lox code to call,
+    /// e.g. `|n: f64| n * 2.0`; see native.rs for the types it can take and
+    /// return. Its errors (including being passed the wrong types) are
+    /// reported as Lox runtime errors, which Lox code can catch.
+    pub fn define_function<Args>(
+        &mut self,
+        name: &str,
+        function: impl NativeFunction<Object, Args> + 'static,
+    ) {
+        // Modules we import later get it too.
+        builtins::define(&mut self.builtins, name, function);
+        let function = self.builtins[name].clone();
+        self.set_global(name, function);
+    }
+
+    /// The value of a global, if it's defined.
+    pub fn get_global(&self, name: &str) -> Option<Object> {
+        self.environment.borrow().get_global(name)
+    }
+
+    /// The variables in scope (at the top level, just the globals), as the
+    /// REPL's :env shows them.
+    pub fn dump_globals(&self) -> String {
+        format!("{:?}", self.environment.borrow())
+    }
+
+    /// The names of the globals, for the REPL to complete.
+    pub fn global_names(&self) -> Vec<String> {
+        let globals = self.environment.borrow().globals();
+        globals.into_iter().map(|(name, _)| name).collect()
+    }
+
+    /// The properties (fields, methods and module members) of the globals'
+    /// values, for the REPL to complete after a dot.
+    pub fn property_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for (_, value) in self.environment.borrow().globals() {
+            match value {
+                Object::Instance(instance) => {
+                    let instance = instance.borrow();
+                    names.extend(instance.fields.keys().cloned());
+                    names.extend(instance.class_.borrow().method_names());
+                }
+                Object::Module(module) => {
+                    let members = module.globals.borrow().globals();
+                    names.extend(members.into_iter().map(|(name, _)| name));
+                }
+                _ => {}
+            }
+        }
+        names
+    }
+
+    /// Defines (or redefines) a global, as a var statement would.
+    pub fn set_global(&mut self, name: &str, value: impl Into<Object>) {
+        self.environment
+            .borrow_mut()
+            .define_global(name, value.into());
+    }
+
+    /// Runs a program that's already been parsed and resolved.
+    ///
+    /// # Errors
+    ///
+    /// Any runtime error, or [`ErrorCode::Exit`] if it calls `exit`.
+    pub(crate) fn execute_program(&mut self, node: &Program) -> Result<(), LoxError> {
         let result = self.execute_stmts(&node.stmts, self.environment.clone());
+        Self::program_result(result)
+    }
+
+    fn program_result<T>(result: Result<T, Unwinder>) -> Result<T, LoxError> {
         match result {
-            Ok(()) => Ok(()),
+            Ok(value) => Ok(value),
             Err(Unwinder::Err(e)) => Err(e),
-            Err(Unwinder::Return { keyword, value: _ }) => Err(runtime_error(
+            Err(Unwinder::Throw {
                 keyword,
+                value,
+                trace,
+            }) => Err(Unwinder::uncaught(&keyword, &value, trace)),
+            Err(Unwinder::Return { keyword, value: _ }) => Err(internal_error(
+                &keyword,
                 "[resolver bug] Can't return from top-level code.",
             )),
+            Err(Unwinder::Break { keyword } | Unwinder::Continue { keyword }) => {
+                Err(internal_error(
+                    &keyword,
+                    "[resolver bug] Can't break or continue outside of a loop.",
+                ))
+            }
+            Err(Unwinder::Exit { status }) => Err(exit_error(status)),
         }
     }
 
-    fn evaluate(&mut self, node: &Expr<'src>) -> Result<Object<'ast, 'src>, Unwinder<'ast, 'src>> {
-        match node {
+    fn evaluate(&mut self, expr: &Expr) -> Result<Object, Unwinder> {
+        match expr {
             Expr::Assign(node) => {
                 let value = self.evaluate(&node.value)?;
-                match node.resolved_depth {
-                    Some(depth) => {
-                        self.environment.borrow_mut().assign_at(
-                            depth,
-                            &node.name,
-                            value.clone(),
-                        )?;
+                match node.resolved_slot {
+                    Some(slot) => {
+                        self.environment
+                            .borrow_mut()
+                            .assign_at(slot, &node.name, value.clone())?;
                     }
 
                     None => self
-                        .globals
+                        .environment
                         .borrow_mut()
                         .assign(&node.name, value.clone())?,
                 }
@@ -89,7 +279,11 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                             Object::Literal(Literal::Number(l)),
                             Object::Literal(Literal::Number(r)),
                         ) => Ok(Object::Literal(Literal::Number(l - r))),
-                        (_, _) => Unwinder::err(&node.operator, "invalid types for subtraction"),
+                        (_, _) => Unwinder::expr_err(
+                            &node.operator,
+                            expr,
+                            "invalid types for subtraction",
+                        ),
                     },
                     TokenType::Plus => match (left, right) {
                         (
@@ -100,55 +294,77 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                             Object::Literal(Literal::String(l)),
                             Object::Literal(Literal::String(r)),
                         ) => Ok(Object::Literal(Literal::String(l + &r))),
-                        (_, _) => Unwinder::err(&node.operator, "invalid types for addition"),
+                        (_, _) => {
+                            Unwinder::expr_err(&node.operator, expr, "invalid types for addition")
+                        }
                     },
                     TokenType::Slash => match (left, right) {
                         (
                             Object::Literal(Literal::Number(l)),
                             Object::Literal(Literal::Number(r)),
                         ) => Ok(Object::Literal(Literal::Number(l / r))),
-                        (_, _) => Unwinder::err(&node.operator, "invalid types for division"),
+                        (_, _) => {
+                            Unwinder::expr_err(&node.operator, expr, "invalid types for division")
+                        }
                     },
                     TokenType::Star => match (left, right) {
                         (
                             Object::Literal(Literal::Number(l)),
                             Object::Literal(Literal::Number(r)),
                         ) => Ok(Object::Literal(Literal::Number(l * r))),
-                        (_, _) => Unwinder::err(&node.operator, "invalid types for multiplication"),
+                        (_, _) => Unwinder::expr_err(
+                            &node.operator,
+                            expr,
+                            "invalid types for multiplication",
+                        ),
                     },
                     TokenType::Greater => match (left, right) {
                         (
                             Object::Literal(Literal::Number(l)),
                             Object::Literal(Literal::Number(r)),
                         ) => Ok(Object::Literal(Literal::Bool(l > r))),
-                        (_, _) => Unwinder::err(&node.operator, "invalid types for comparison"),
+                        (_, _) => {
+                            Unwinder::expr_err(&node.operator, expr, "invalid types for comparison")
+                        }
                     },
                     TokenType::GreaterEqual => match (left, right) {
                         (
                             Object::Literal(Literal::Number(l)),
                             Object::Literal(Literal::Number(r)),
                         ) => Ok(Object::Literal(Literal::Bool(l >= r))),
-                        (_, _) => Unwinder::err(&node.operator, "invalid types for comparison"),
+                        (_, _) => {
+                            Unwinder::expr_err(&node.operator, expr, "invalid types for comparison")
+                        }
                     },
                     TokenType::Less => match (left, right) {
                         (
                             Object::Literal(Literal::Number(l)),
                             Object::Literal(Literal::Number(r)),
                         ) => Ok(Object::Literal(Literal::Bool(l < r))),
-                        (_, _) => Unwinder::err(&node.operator, "invalid types for comparison"),
+                        (_, _) => {
+                            Unwinder::expr_err(&node.operator, expr, "invalid types for comparison")
+                        }
                     },
                     TokenType::LessEqual => match (left, right) {
                         (
                             Object::Literal(Literal::Number(l)),
                             Object::Literal(Literal::Number(r)),
                         ) => Ok(Object::Literal(Literal::Bool(l <= r))),
-                        (_, _) => Unwinder::err(&node.operator, "invalid types for comparison"),
+                        (_, _) => {
+                            Unwinder::expr_err(&node.operator, expr, "invalid types for comparison")
+                        }
                     },
                     TokenType::EqualEqual => Ok(Object::Literal(Literal::Bool(left.eq(&right)))),
                     TokenType::BangEqual => Ok(Object::Literal(Literal::Bool(!left.eq(&right)))),
-                    _ => Unwinder::err(&node.operator, "unknown operator (parser bug?)"),
+                    _ => Unwinder::bug(&node.operator, "unknown operator (parser bug?)"),
                 }
             }
+            Expr::Function(node) => Ok(Function {
+                declaration: node.declaration.clone(),
+                closure: self.capture_environment(),
+                is_initializer: false,
+            }
+            .into()),
             Expr::Call(node) => {
                 let callee = self.evaluate(&node.callee)?;
 
@@ -159,12 +375,30 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
 
                 match callee {
                     Object::BuiltinFunction(f) => {
-                        Self::arity_check(f.arity, arguments.len(), node)?;
-                        Unwinder::promote((f.function.borrow_mut())(arguments))
+                        Self::arity_check(f.arity, arguments.len(), node, expr)?;
+                        (f.function.borrow_mut())(arguments).or_else(|error| match error {
+                            NativeError::Message(message) => {
+                                Unwinder::expr_err(&node.paren, expr, &message)
+                            }
+                            NativeError::Exit(status) => Err(Unwinder::Exit { status }),
+                        })
+                    }
+                    Object::BoundNative(method) => {
+                        Self::arity_check(method.arity, arguments.len(), node, expr)?;
+                        method
+                            .call(arguments, |values| {
+                                self.heap.borrow_mut().track(RefCell::new(values)).into()
+                            })
+                            .or_else(|message| Unwinder::expr_err(&node.paren, expr, &message))
                     }
                     Object::Function(f) => {
-                        Self::arity_check(f.declaration.parameters.len(), arguments.len(), node)?;
-                        self.call_function(&f, &arguments)
+                        Self::arity_check(
+                            f.declaration.parameters.len(),
+                            arguments.len(),
+                            node,
+                            expr,
+                        )?;
+                        self.call_function(&f, arguments, &node.paren, expr)
                     }
                     Object::Class(c) => {
                         let initializer = c.borrow().find_method("init");
@@ -172,42 +406,81 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                             Some(init) => init.declaration.parameters.len(),
                             None => 0,
                         };
-                        Self::arity_check(arity, arguments.len(), node)?;
-                        let instance = Rc::new(RefCell::new(Instance {
+                        Self::arity_check(arity, arguments.len(), node, expr)?;
+                        let instance = self.heap.borrow_mut().track(RefCell::new(Instance {
                             class_: c,
                             fields: HashMap::new(),
                         }));
                         if let Some(init) = initializer {
-                            self.call_function(&init.bind(instance.clone()), &arguments)?;
+                            let init = init.bind(instance.clone(), &mut self.heap.borrow_mut());
+                            self.call_function(&init, arguments, &node.paren, expr)?;
                         }
                         Ok(instance.into())
                     }
-                    o => Unwinder::err(
+                    o => Unwinder::expr_err(
                         &node.paren,
+                        expr,
                         &format!("Can only call functions and classes, got '{o}'."),
                     ),
                 }
             }
             Expr::Get(node) => {
                 let object = self.evaluate(&node.object)?;
-                if let Object::Instance(obj) = object {
-                    instance_get(obj, &node.name)
-                } else {
-                    Unwinder::err(
+                match object {
+                    Object::Instance(obj) => {
+                        instance_get(obj, &node.name, &mut self.heap.borrow_mut())
+                    }
+                    Object::List(l) => list_get(l, &node.name),
+                    Object::Map(m) => map_get(m, &node.name),
+                    Object::Module(m) => module_get(&m, &node.name),
+                    Object::Error(e) => e.property(&node.name.lexeme).map(Into::into).map_or_else(
+                        || {
+                            Unwinder::err(
+                                &node.name,
+                                &format!("Undefined property '{}'.", &node.name.lexeme),
+                            )
+                        },
+                        Ok,
+                    ),
+                    _ => Unwinder::err(
                         &node.name,
                         &format!("Only instances have properties, got '{object}'."),
-                    )
+                    ),
                 }
             }
             Expr::Grouping(node) => self.evaluate(&node.expr),
+            Expr::Index(node) => {
+                let object = self.evaluate(&node.object)?;
+                let index = self.evaluate(&node.index)?;
+                object
+                    .get_index(&index)
+                    .or_else(|message| Unwinder::expr_err(&node.bracket, expr, &message))
+            }
+            Expr::List(node) => {
+                let mut elements = Vec::new();
+                for element in &node.elements {
+                    elements.push(self.evaluate(element)?);
+                }
+                Ok(self.heap.borrow_mut().track(RefCell::new(elements)).into())
+            }
             Expr::Literal(node) => Ok(node.value.clone().into()),
             Expr::Logical(node) => {
                 let left = self.evaluate(&node.left)?;
                 match (node.operator.type_, left.is_truthy()) {
                     (TokenType::Or, true) | (TokenType::And, false) => Ok(left),
                     (TokenType::Or, false) | (TokenType::And, true) => self.evaluate(&node.right),
-                    _ => Unwinder::err(&node.operator, "unknown operator (parser bug?)"),
+                    _ => Unwinder::bug(&node.operator, "unknown operator (parser bug?)"),
+                }
+            }
+            Expr::Map(node) => {
+                let mut map = Map::new();
+                for (key, value) in &node.entries {
+                    let key = self.evaluate(key)?;
+                    let value = self.evaluate(value)?;
+                    map.set(&key, value)
+                        .or_else(|message| Unwinder::expr_err(&node.brace, expr, &message))?;
                 }
+                Ok(self.heap.borrow_mut().track(RefCell::new(map)).into())
             }
             Expr::Set(node) => {
                 let object = self.evaluate(&node.object)?;
@@ -222,38 +495,52 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                     )
                 }
             }
+            Expr::SetIndex(node) => {
+                let object = self.evaluate(&node.object)?;
+                let index = self.evaluate(&node.index)?;
+                let value = self.evaluate(&node.value)?;
+                object
+                    .set_index(&index, value.clone())
+                    .or_else(|message| Unwinder::expr_err(&node.bracket, expr, &message))?;
+                Ok(value)
+            }
             Expr::Super(node) => {
-                let depth = node.resolved_depth.ok_or(Unwinder::Err(runtime_error(
+                let slot = node.resolved_slot.ok_or(Unwinder::Err(internal_error(
                     &node.keyword,
                     "no super found (resolver bug?)",
                 )))?;
 
-                let superclass = self.environment.borrow().get_at(depth, &node.keyword)?;
+                let superclass = self.environment.borrow().get_at(slot, &node.keyword)?;
                 let Object::Class(sup) = superclass else {
-                    return Unwinder::err(
+                    return Unwinder::bug(
                         &node.keyword,
                         "super was not a class (interpreter bug?)",
                     );
                 };
                 // TODO: eugh
                 let mut fake_token = node.keyword.clone();
-                fake_token.lexeme = "this";
-                let object = self.environment.borrow().get_at(depth - 1, &fake_token)?;
+                fake_token.lexeme = Rc::from("this");
+                // 'this' is always alone in the scope just inside 'super'.
+                let this_slot = Slot {
+                    depth: slot.depth - 1,
+                    index: 0,
+                };
+                let object = self.environment.borrow().get_at(this_slot, &fake_token)?;
                 let Object::Instance(obj) = object else {
-                    return Unwinder::err(
+                    return Unwinder::bug(
                         &node.keyword,
                         "this was not an instance (interpreter bug?)",
                     );
                 };
-                let Some(method) = sup.borrow().find_method(node.method.lexeme) else {
+                let Some(method) = sup.borrow().find_method(&node.method.lexeme) else {
                     return Unwinder::err(
                         &node.keyword,
-                        &format!("Undefined property '{}'.", node.method.lexeme),
+                        &format!("Undefined property '{}'.", &node.method.lexeme),
                     );
                 };
-                return Ok(method.bind(obj).into());
+                Ok(method.bind(obj, &mut self.heap.borrow_mut()).into())
             }
-            Expr::This(node) => self.lookup_variable(&node.resolved_depth, &node.keyword),
+            Expr::This(node) => self.lookup_variable(node.resolved_slot, &node.keyword),
             Expr::Unary(node) => {
                 let right = self.evaluate(&node.right)?;
 
@@ -263,24 +550,26 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                         Object::Literal(Literal::Number(n)) => {
                             Ok(Object::Literal(Literal::Number(-n)))
                         }
-                        _ => Unwinder::err(&node.operator, "invalid type for negation"),
+                        _ => Unwinder::expr_err(&node.operator, expr, "invalid type for negation"),
                     },
-                    _ => Unwinder::err(&node.operator, "unknown operator (parser bug?)"),
+                    _ => Unwinder::bug(&node.operator, "unknown operator (parser bug?)"),
                 }
             }
-            Expr::Variable(node) => self.lookup_variable(&node.resolved_depth, &node.name),
+            Expr::Variable(node) => self.lookup_variable(node.resolved_slot, &node.name),
         }
     }
 
     fn arity_check(
         expected: usize,
         actual: usize,
-        node: &CallExpr<'src>,
-    ) -> Result<(), Unwinder<'ast, 'src>> {
+        node: &CallExpr,
+        call: &Expr,
+    ) -> Result<(), Unwinder> {
         if expected != actual {
-            Unwinder::err(
+            Unwinder::expr_err(
                 &node.paren,
-                &format!("Expected {expected} arguments but got {actual}.",),
+                call,
+                &format!("Expected {expected} arguments but got {actual}."),
             )
         } else {
             Ok(())
@@ -290,45 +579,136 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
     // TODO: getting pretty awk here
     fn call_function(
         &mut self,
-        f: &Function<'ast, 'src>,
-        arguments: &[Object<'ast, 'src>],
-    ) -> Result<Object<'ast, 'src>, Unwinder<'ast, 'src>> {
-        let environment = Rc::new(RefCell::new(Environment::child(f.closure.clone())));
-        for (i, parameter) in f.declaration.parameters.iter().enumerate() {
-            environment
-                .borrow_mut()
-                .define(parameter.lexeme, arguments[i].clone());
+        f: &Function,
+        arguments: Vec<Object>,
+        paren: &scanner::Token,
+        call: &Expr,
+    ) -> Result<Object, Unwinder> {
+        if self.call_stack.len() >= self.max_depth {
+            return Err(Unwinder::Err(LoxError {
+                code: ErrorCode::StackOverflow,
+                ..expr_error(paren, call, "Stack overflow.")
+            }));
+        }
+        // The parameters are the first slots, in order.
+        let environment = Rc::new(RefCell::new(Environment::with_slots(
+            f.closure.clone(),
+            arguments,
+        )));
+        self.call_stack
+            .push((f.declaration.name.lexeme.clone(), paren.line));
+        // Each Lox call takes several Rust calls, so rather than have the
+        // maximum depth depend on the host's stack, we grow it as we go.
+        let mut result = if self.call_stack.len() % STACK_CHECK_INTERVAL == 0 {
+            stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, || {
+                self.execute_stmts(&f.declaration.body, environment)
+            })
+        } else {
+            self.execute_stmts(&f.declaration.body, environment)
+        };
+        // Runtime errors don't know where they are, so we tell them as they
+        // leave the innermost call.
+        if let Err(Unwinder::Err(error)) = &mut result {
+            if error.trace.is_empty() {
+                error.trace = self.trace(error.line);
+            }
         }
-        let result = self.execute_stmts(&f.declaration.body, environment.clone());
+        self.call_stack.pop();
         match (result, f.is_initializer) {
             (Ok(()) | Err(Unwinder::Return { .. }), true) => {
                 // In initializer, all returns (but not exceptions) are really 'this'.
                 let mut fake_token = f.declaration.name.clone();
-                fake_token.lexeme = "this";
-                environment.borrow().get_at(0, &fake_token)
+                fake_token.lexeme = Rc::from("this");
+                f.closure
+                    .borrow()
+                    .get_at(Slot { depth: 0, index: 0 }, &fake_token)
+            }
+            (Err(e @ (Unwinder::Err(_) | Unwinder::Throw { .. } | Unwinder::Exit { .. })), _) => {
+                Err(e)
+            }
+            (Err(Unwinder::Break { keyword } | Unwinder::Continue { keyword }), _) => {
+                Unwinder::bug(
+                    &keyword,
+                    "[resolver bug] Can't break or continue outside of a loop.",
+                )
             }
-            (Err(Unwinder::Err(e)), _) => Err(Unwinder::Err(e)),
             (Ok(()), _) => Ok(Literal::Nil.into()), // (omitted return)
             (Err(Unwinder::Return { keyword: _, value }), _) => Ok(value),
         }
     }
 
+    // The calls in progress, for tracebacks, the innermost being on the given
+    // line.
+    fn trace(&self, line: usize) -> Vec<CallSite> {
+        if self.call_stack.is_empty() {
+            return Vec::new();
+        }
+        let functions = iter::once("script").chain(self.call_stack.iter().map(|(f, _)| &**f));
+        let lines = self.call_stack.iter().map(|(_, line)| *line);
+        functions
+            .zip(lines.chain(iter::once(line)))
+            .map(|(function, line)| CallSite {
+                function: function.to_string(),
+                line,
+            })
+            .collect()
+    }
+
+    fn import(&mut self, node: &ImportStmt) -> Result<Object, Unwinder> {
+        let source = match self.modules.find(&node.path) {
+            Ok(Import::Loaded(module)) => return Ok(module),
+            Ok(Import::New(source)) => source,
+            Err(message) => {
+                return Err(Unwinder::Err(LoxError {
+                    code: ErrorCode::Import,
+                    ..runtime_error(&node.path_token, &message)
+                }))
+            }
+        };
+        let name = source.name.clone();
+        let text = self.modules.enter(source);
+        let globals = Rc::new(RefCell::new(Environment::Global(self.builtins.clone())));
+        let result = (|| {
+            let tokens = scanner::scan_tokens(&text)?;
+            let mut prog = parser::parse(tokens)?;
+            resolver::resolve(&mut prog)?;
+            Self::program_result(self.execute_stmts(&prog.stmts, globals.clone()))
+        })();
+        match result {
+            Ok(()) => {
+                let module: Object = Rc::new(Module { name, globals }).into();
+                self.modules.leave(module.clone());
+                Ok(module)
+            }
+            Err(error) if error.code == ErrorCode::Exit => {
+                self.modules.abandon();
+                Err(Unwinder::Exit { status: error.exit })
+            }
+            Err(error) => {
+                let path = Snippet::new(&node.path_token.source, node.path_token.span);
+                Err(Unwinder::Err(self.modules.fail(&error, &path)))
+            }
+        }
+    }
+
     fn lookup_variable(
         &self,
-        resolved_depth: &Option<usize>,
-        name: &scanner::Token<'src>,
-    ) -> Result<Object<'ast, 'src>, Unwinder<'ast, 'src>> {
-        match resolved_depth {
-            Some(depth) => self.environment.borrow().get_at(*depth, name),
-            None => self.globals.borrow().get(name),
+        resolved_slot: Option<Slot>,
+        name: &scanner::Token,
+    ) -> Result<Object, Unwinder> {
+        match resolved_slot {
+            Some(slot) => self.environment.borrow().get_at(slot, name),
+            // Globals are those of the module the code is in, which is
+            // always the outermost environment.
+            None => self.environment.borrow().get(name),
         }
     }
 
     fn execute_stmts(
         &mut self,
-        stmts: &'ast Vec<Stmt<'src>>,
-        environment: Rc<RefCell<Environment<'ast, 'src>>>,
-    ) -> Result<(), Unwinder<'ast, 'src>> {
+        stmts: &[Stmt],
+        environment: Rc<RefCell<Environment>>,
+    ) -> Result<(), Unwinder> {
         let prev = self.environment.clone();
         self.environment = environment;
         for stmt in stmts {
@@ -341,17 +721,20 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
         Ok(())
     }
 
-    fn execute(&mut self, node: &'ast Stmt<'src>) -> Result<(), Unwinder<'ast, 'src>> {
+    fn execute(&mut self, node: &Stmt) -> Result<(), Unwinder> {
         match node {
             Stmt::Block(node) => {
                 let environment =
                     Rc::new(RefCell::new(Environment::child(self.environment.clone())));
                 self.execute_stmts(&node.stmts, environment)?;
             }
+            Stmt::Break(node) => Err(Unwinder::Break {
+                keyword: Box::new(node.keyword.clone()),
+            })?,
 
             Stmt::Class(node) => {
                 let superclass = if let Some(sup) = &node.superclass {
-                    let resolved = self.lookup_variable(&sup.resolved_depth, &sup.name)?;
+                    let resolved = self.lookup_variable(sup.resolved_slot, &sup.name)?;
                     match resolved {
                         Object::Class(c) => Ok(Some(c)),
                         _ => Unwinder::err(
@@ -363,10 +746,6 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                     Ok(None)
                 }?;
 
-                self.environment
-                    .borrow_mut()
-                    .define(node.name.lexeme, Literal::Nil.into());
-
                 let enclosing_environment = self.environment.clone();
                 if let Some(sup) = superclass.clone() {
                     let sup_environment =
@@ -375,45 +754,55 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                     self.environment
                         .borrow_mut()
                         .define("super", Object::Class(sup));
-                };
+                }
 
                 let mut methods = HashMap::new();
                 for method in &node.methods {
                     let function = Function {
-                        declaration: method,
-                        closure: self.environment.clone(),
-                        is_initializer: method.name.lexeme == "init",
+                        declaration: method.clone(),
+                        closure: self.capture_environment(),
+                        is_initializer: &*method.name.lexeme == "init",
                     };
                     methods.insert(method.name.lexeme.to_string(), function);
                 }
-                let class_ = Rc::new(RefCell::new(Class {
-                    name: &node.name,
-                    superclass: superclass.clone(),
-                    methods,
-                }))
-                .into();
+                let class_ = self
+                    .heap
+                    .borrow_mut()
+                    .track(RefCell::new(Class {
+                        name: node.name.clone(),
+                        superclass: superclass.clone(),
+                        methods,
+                    }))
+                    .into();
 
                 if superclass.clone().is_some() {
                     self.environment = enclosing_environment;
                 }
 
-                self.environment.borrow_mut().assign(&node.name, class_)?;
+                // Methods only look up the class by name once called, so it's
+                // fine to define it last.
+                self.environment
+                    .borrow_mut()
+                    .define(&node.name.lexeme, class_);
             }
 
+            Stmt::Continue(node) => Err(Unwinder::Continue {
+                keyword: Box::new(node.keyword.clone()),
+            })?,
             Stmt::Expr(node) => {
                 self.evaluate(&node.expr)?;
             }
 
             Stmt::Function(node) => {
                 let function = Function {
-                    declaration: node,
-                    closure: self.environment.clone(),
+                    declaration: node.clone(),
+                    closure: self.capture_environment(),
                     is_initializer: false,
                 }
                 .into();
                 self.environment
                     .borrow_mut()
-                    .define(node.name.lexeme, function);
+                    .define(&node.name.lexeme, function);
             }
 
             Stmt::If(node) => {
@@ -424,6 +813,12 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                     self.execute(e)?;
                 }
             }
+            Stmt::Import(node) => {
+                let module = self.import(node)?;
+                self.environment
+                    .borrow_mut()
+                    .define(&node.name.lexeme, module);
+            }
             Stmt::Print(node) => {
                 let value = self.evaluate(&node.expr)?;
                 let stringified = format!("{value}");
@@ -435,10 +830,53 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
                     None => Literal::Nil.into(),
                 };
                 Err(Unwinder::Return {
-                    keyword: &node.keyword,
+                    keyword: Box::new(node.keyword.clone()),
+                    value,
+                })?;
+            }
+            Stmt::Throw(node) => {
+                let value = self.evaluate(&node.value)?;
+                Err(Unwinder::Throw {
+                    keyword: Box::new(node.keyword.clone()),
                     value,
+                    trace: self.trace(node.keyword.line),
                 })?;
             }
+            Stmt::Try(node) => {
+                let environment =
+                    Rc::new(RefCell::new(Environment::child(self.environment.clone())));
+                let mut result = self.execute_stmts(&node.body, environment);
+                if let Some(catch) = &node.catch {
+                    let exception = match result {
+                        Err(Unwinder::Throw { ref value, .. }) => Some(value.clone()),
+                        // Our own bugs aren't the program's to handle.
+                        Err(Unwinder::Err(ref e)) if e.code != ErrorCode::Internal => {
+                            Some(Object::Error(Rc::new(e.clone())))
+                        }
+                        _ => None,
+                    };
+                    if let Some(exception) = exception {
+                        let environment =
+                            Rc::new(RefCell::new(Environment::child(self.environment.clone())));
+                        environment
+                            .borrow_mut()
+                            .define(&catch.name.lexeme, exception);
+                        result = self.execute_stmts(&catch.body, environment);
+                    }
+                }
+                // Nothing runs after exit(), not even finally blocks.
+                if matches!(result, Err(Unwinder::Exit { .. })) {
+                    return result;
+                }
+                // If the finally block itself exits early, that replaces
+                // whatever the rest of the statement was doing.
+                if let Some(finally) = &node.finally {
+                    let environment =
+                        Rc::new(RefCell::new(Environment::child(self.environment.clone())));
+                    self.execute_stmts(finally, environment)?;
+                }
+                result?;
+            }
             Stmt::Var(node) => {
                 let value = match &node.initializer {
                     Some(expr) => self.evaluate(expr)?,
@@ -447,20 +885,47 @@ impl<'ast, 'src: 'ast, F: FnMut(String)> Interpreter<'ast, 'src, F> {
 
                 self.environment
                     .borrow_mut()
-                    .define(node.name.lexeme, value);
+                    .define(&node.name.lexeme, value);
             }
             Stmt::While(node) => loop {
                 let cond = self.evaluate(&node.condition)?;
                 if !cond.is_truthy() {
                     break;
                 }
-                self.execute(&node.body)?;
+                match self.execute(&node.body) {
+                    Ok(()) | Err(Unwinder::Continue { .. }) => {}
+                    Err(Unwinder::Break { .. }) => break,
+                    Err(e) => return Err(e),
+                }
+                if let Some(increment) = &node.increment {
+                    self.evaluate(increment)?;
+                }
             },
         }
         Ok(())
     }
 }
 
+// Globals hold the functions defined in them, whose closures refer back to
+// their globals, so we break those cycles once we're done.
+impl<F: FnMut(String)> Drop for Interpreter<F> {
+    fn drop(&mut self) {
+        let mut all = vec![self.environment.clone()];
+        for module in self.modules.loaded() {
+            if let Object::Module(module) = module {
+                all.push(module.globals.clone());
+            }
+        }
+        for globals in all {
+            let values = match &mut *globals.borrow_mut() {
+                Environment::Global(values) => std::mem::take(values),
+                Environment::Local { .. } => continue,
+            };
+            drop(values);
+        }
+    }
+}
+
 #[cfg(test)]
 pub fn execute_for_tests(source: &str) -> Result<Vec<String>, LoxError> {
     let mut printed: Vec<String> = Vec::new();
@@ -469,48 +934,89 @@ pub fn execute_for_tests(source: &str) -> Result<Vec<String>, LoxError> {
     let mut prog = parser::parse(tokens)?;
     resolver::resolve(&mut prog)?;
     {
-        let globals = Rc::new(RefCell::new(Environment::new()));
-        globals.borrow_mut().define(
-            "clock",
-            BuiltinFunction {
-                arity: 0,
-                function: Rc::new(RefCell::new(move |_| {
-                    time += 1.0;
-                    Ok(Object::Literal(Literal::Number(time)))
-                })),
-                name: "clock".to_string(),
-            }
-            .into(),
+        let mut interpreter = Interpreter::new(
+            |s| printed.push(s),
+            move || {
+                time += 1.0;
+                time
+            },
         );
-        let mut interpreter = Interpreter {
-            printer: |s| printed.push(s),
-            globals: globals.clone(),
-            environment: globals.clone(),
-        };
         interpreter.execute_program(&prog)?;
     }
     Ok(printed)
 }
 
+// Each test program runs on both backends, which must agree exactly.
+#[cfg(test)]
+fn execute_on_each_backend(source: &str) -> [(&'static str, Result<Vec<String>, LoxError>); 2] {
+    [
+        ("interpreter", execute_for_tests(source)),
+        ("vm", vm::execute_for_tests(source)),
+    ]
+}
+
 #[cfg(test)]
 fn assert_prints(source: &str, expected: &[&str]) {
-    match execute_for_tests(source) {
-        Ok(a) => assert_eq!(
-            a,
-            expected.iter().map(|s| s.to_string()).collect::<Vec<_>>()
-        ),
-        Err(a) => assert!(false, "Expected {expected:?}, got error {a}"),
+    for (backend, result) in execute_on_each_backend(source) {
+        match result {
+            Ok(a) => assert_eq!(
+                a,
+                expected
+                    .iter()
+                    .map(std::string::ToString::to_string)
+                    .collect::<Vec<_>>(),
+                "on {backend}"
+            ),
+            Err(a) => panic!("Expected {expected:?}, got error {a} on {backend}"),
+        }
     }
 }
 
 #[cfg(test)]
 fn assert_errs(source: &str, expected: &str) {
-    match execute_for_tests(source) {
-        Ok(a) => assert!(false, "Expected error {expected}, got {a:?}"),
-        Err(a) => assert_eq!(a.to_string(), expected),
+    for (backend, result) in execute_on_each_backend(source) {
+        match result {
+            Ok(a) => panic!("Expected error {expected}, got {a:?} on {backend}"),
+            Err(a) => assert_eq!(a.to_string(), expected, "on {backend}"),
+        }
+    }
+}
+
+#[cfg(test)]
+fn assert_reports(source: &str, expected: &str) {
+    for (backend, result) in execute_on_each_backend(source) {
+        match result {
+            Ok(a) => panic!("Expected error {expected}, got {a:?} on {backend}"),
+            Err(a) => assert_eq!(a.report(), expected, "on {backend}"),
+        }
+    }
+}
+
+#[cfg(test)]
+fn assert_codes(source: &str, expected: &[ErrorCode]) {
+    for (backend, result) in execute_on_each_backend(source) {
+        match result {
+            Ok(a) => panic!("Expected error {expected:?}, got {a:?} on {backend}"),
+            Err(a) => {
+                let codes: Vec<_> = a.diagnostics().iter().map(|d| d.code).collect();
+                assert_eq!(codes, expected, "on {backend}");
+            }
+        }
     }
 }
 
+// Hands `run` a tree-walker that keeps what it prints, for tests that drive
+// it as a host would, and returns what `run` did along with the lines.
+#[cfg(test)]
+fn capture_prints<T>(
+    run: impl FnOnce(&mut Interpreter<&mut dyn FnMut(String)>) -> T,
+) -> (T, Vec<String>) {
+    let mut lines = Vec::new();
+    let mut printer = |s| lines.push(s);
+    let result = run(&mut Interpreter::with_printer(&mut printer));
+    (result, lines)
+}
+
 #[test]
 fn test_evaluate_simple_expr() {
     assert_prints("print 1 + 2;", &["3"]);
@@ -1155,3 +1661,1274 @@ fn test_super() {
         "[line 1] Error: Undefined property 'm'.",
     );
 }
+
+#[test]
+fn test_slots() {
+    // Locals at several depths and slots, including assignments through
+    // closures and classes declared in local scopes.
+    assert_prints(
+        r"
+            fun outer(a, b) {
+                var c = a + b;
+                fun middle(d) {
+                    var e = d * 2;
+                    {
+                        var f = 1;
+                        c = c + e + f;
+                        b = b + 1;
+                    }
+                    return c;
+                }
+                return middle;
+            }
+            var m = outer(1, 2);
+            print m(1);
+            print m(1);
+        ",
+        &["6", "9"],
+    );
+    assert_prints(
+        r"
+            {
+                var x = 1;
+                class C {
+                    init(y) { this.y = y + x; }
+                }
+                class D < C {
+                    init(y) { super.init(y); x = x + 1; }
+                }
+                var d = D(2);
+                print d.y;
+                print x;
+            }
+        ",
+        &["3", "2"],
+    );
+}
+
+#[test]
+fn test_gc() {
+    // Instances referring to themselves, directly or via a bound method.
+    assert_prints(
+        r"
+            class Node {}
+            for (var i = 0; i < 100; i = i + 1) {
+                var n = Node();
+                n.self = n;
+            }
+            print gc();
+            print gc();
+            print heapStats();
+        ",
+        &["100", "0", "1 live objects, 2 collections, 100 freed"],
+    );
+    assert_prints(
+        r"
+            class A {
+                init() { this.f = this.m; }
+                m() { return this; }
+            }
+            var kept = A();
+            for (var i = 0; i < 10; i = i + 1) {
+                A();
+            }
+            print gc();
+            print kept.f() == kept;
+        ",
+        &["20", "true"],
+    );
+    assert_prints(
+        r#"
+            for (var i = 0; i < 10; i = i + 1) {
+                var l = [];
+                l.push(l);
+                var m = {};
+                m["m"] = m;
+            }
+            print gc();
+        "#,
+        &["20"],
+    );
+    // The same, through a bound list or map method.
+    assert_prints(
+        r#"
+            for (var i = 0; i < 10; i = i + 1) {
+                var l = [];
+                l.push(l.push);
+                var m = {};
+                m["keys"] = m.keys;
+            }
+            print gc();
+        "#,
+        &["20"],
+    );
+    // Cycles get collected automatically, too.
+    assert_prints(
+        r"
+            class Node {}
+            for (var i = 0; i < 30000; i = i + 1) {
+                var n = Node();
+                n.self = n;
+            }
+            print gc() < 10000;
+        ",
+        &["true"],
+    );
+}
+
+#[test]
+fn test_gc_closures() {
+    // Call environments are only tracked once a closure captures them, as
+    // here, where the closure is stored back in its own environment. The
+    // tree-walker frees each such environment; the VM, each closure and the
+    // upvalue it closed over.
+    let source = r"
+        fun outer() {
+            var f;
+            fun inner() { return f; }
+            f = inner;
+            return inner;
+        }
+        for (var i = 0; i < 10; i = i + 1) {
+            outer();
+        }
+        var kept = outer();
+        print gc();
+        print kept() == kept;
+    ";
+    for (backend, result) in execute_on_each_backend(source) {
+        let freed = if backend == "vm" { "20" } else { "10" };
+        assert_eq!(result.unwrap(), vec![freed, "true"], "on {backend}");
+    }
+}
+
+#[test]
+fn test_lists() {
+    assert_prints(
+        r#"
+            var a = [1, "two", [3]];
+            print a;
+            print a[0] + a[2][0];
+            a[1] = 2;
+            print a;
+            print [];
+            print a == a;
+            print [1] == [1];
+            var b = [a];
+            a.push(b);
+            print b;
+        "#,
+        &[
+            "[1, \"two\", [3]]",
+            "4",
+            "[1, 2, [3]]",
+            "[]",
+            "true",
+            "false",
+            "[[1, 2, [3], [...]]]",
+        ],
+    );
+    assert_prints(
+        r"
+            var a = [];
+            a.push(1);
+            a.push(3);
+            a.insert(1, 2);
+            a.insert(3, 4);
+            print a;
+            print a.len();
+            print a.pop();
+            print a.remove(0);
+            print a;
+            var push = a.push;
+            push(5);
+            print a;
+        ",
+        &["[1, 2, 3, 4]", "4", "4", "1", "[2, 3]", "[2, 3, 5]"],
+    );
+    assert_prints(
+        r"
+            var a = [0, 0, 0];
+            var i = 0;
+            a[i = i + 1] = i;
+            print a;
+        ",
+        &["[0, 1, 0]"],
+    );
+    assert_errs(
+        "print [1][1];",
+        "[line 1] Error: List index 1 out of range.",
+    );
+    assert_errs(
+        "print [1][-1];",
+        "[line 1] Error: List index -1 out of range.",
+    );
+    assert_errs(
+        "print [1][0.5];",
+        "[line 1] Error: List index must be a whole number.",
+    );
+    assert_errs(
+        r#"print [1]["a"];"#,
+        "[line 1] Error: List index must be a number, got 'a'.",
+    );
+    assert_errs("[1][1] = 2;", "[line 1] Error: List index 1 out of range.");
+    assert_errs(
+        "[1].insert(0.5, 2);",
+        "[line 1] Error: List index must be a whole number.",
+    );
+    assert_errs(
+        "[].insert(1, 2);",
+        "[line 1] Error: List index 1 out of range.",
+    );
+    assert_errs("[].pop();", "[line 1] Error: Can't pop from an empty list.");
+    assert_errs(
+        "[].push();",
+        "[line 1] Error: Expected 1 arguments but got 0.",
+    );
+    assert_errs("[].size();", "[line 1] Error: Undefined property 'size'.");
+    assert_errs(
+        "print 1[0];",
+        "[line 1] Error: Only lists and maps can be indexed, got '1'.",
+    );
+    assert_errs(
+        "[].a = 1;",
+        "[line 1] Error: Only instances have fields, got '[]'.",
+    );
+    assert_errs(
+        "[]();",
+        "[line 1] Error: Can only call functions and classes, got '[]'.",
+    );
+}
+
+#[test]
+fn test_maps() {
+    assert_prints(
+        r#"
+            var m = {"b": 1, "a": [2], 3: "c"};
+            print m;
+            print m["a"][0] + m["b"];
+            m["b"] = 4;
+            m[nil] = true;
+            m[false] = nil;
+            print m;
+            print {};
+            print m == m;
+            print {} == {};
+        "#,
+        &[
+            r#"{"b": 1, "a": [2], 3: "c"}"#,
+            "3",
+            r#"{"b": 4, "a": [2], 3: "c", nil: true, false: nil}"#,
+            "{}",
+            "true",
+            "false",
+        ],
+    );
+    // Keys are equal exactly when == says they are.
+    assert_prints(
+        r#"
+            var m = {1: "number", "1": "string", true: "bool"};
+            print m[1];
+            print m["1"];
+            print m[true];
+            m[-0] = "zero";
+            print m[0];
+            print m.len();
+        "#,
+        &["number", "string", "bool", "zero", "4"],
+    );
+    assert_prints(
+        r#"
+            var m = {"a": 1, "b": 2, "c": 3};
+            print m.keys();
+            print m.values();
+            print m.has("b");
+            print m.delete("b");
+            print m.delete("b");
+            print m.has("b");
+            m["b"] = 4;
+            print m;
+            print m.keys().len();
+            m["self"] = m;
+            print m;
+        "#,
+        &[
+            r#"["a", "b", "c"]"#,
+            "[1, 2, 3]",
+            "true",
+            "true",
+            "false",
+            "false",
+            r#"{"a": 1, "c": 3, "b": 4}"#,
+            "3",
+            r#"{"a": 1, "c": 3, "b": 4, "self": {...}}"#,
+        ],
+    );
+    assert_errs(
+        r#"print {"a": 1}["b"];"#,
+        r#"[line 1] Error: Undefined key "b"."#,
+    );
+    assert_errs(
+        "print {}[[]];",
+        "[line 1] Error: Map keys must be strings, numbers, booleans or nil, got '[]'.",
+    );
+    assert_errs(
+        "print {[]: 1};",
+        "[line 1] Error: Map keys must be strings, numbers, booleans or nil, got '[]'.",
+    );
+    assert_errs(
+        "var m = {}; m[0/0] = 1;",
+        "[line 1] Error: Map key can't be NaN.",
+    );
+    assert_errs(
+        "print {}.has({});",
+        "[line 1] Error: Map keys must be strings, numbers, booleans or nil, got '{}'.",
+    );
+    // At the start of a statement, a brace is a block.
+    assert_errs("{}.push;", "[line 1] Error at '.': Expect expression.");
+    assert_errs(
+        "print {}.push;",
+        "[line 1] Error: Undefined property 'push'.",
+    );
+    // Deleting as we go through the keys, which leaves gaps in the map and
+    // then squeezes them out again.
+    assert_prints(
+        r#"
+            var m = {};
+            for (var i = 0; i < 8; i = i + 1) {
+                m[i] = i * 10;
+            }
+            var keys = m.keys();
+            for (var i = 0; i < keys.len(); i = i + 1) {
+                if (keys[i] != 3 and keys[i] != 6) {
+                    m.delete(keys[i]);
+                }
+                if (m[6] != 60) {
+                    print "lost 6";
+                }
+            }
+            m[0] = "back";
+            print m;
+            print m[3] + m[6];
+            print m.len();
+        "#,
+        &[r#"{3: 30, 6: 60, 0: "back"}"#, "90", "3"],
+    );
+}
+
+#[test]
+fn test_break_continue() {
+    assert_prints(
+        "var i = 0; while (true) { i = i + 1; if (i > 3) break; print i; }",
+        &["1", "2", "3"],
+    );
+    // continue still runs a for loop's increment
+    assert_prints(
+        "for (var i = 0; i < 5; i = i + 1) { if (i == 1 or i == 3) continue; print i; }",
+        &["0", "2", "4"],
+    );
+    // only the innermost loop
+    assert_prints(
+        r"
+            for (var i = 0; i < 3; i = i + 1) {
+                for (var j = 0; j < 3; j = j + 1) {
+                    if (j > i) break;
+                    if (j == 1) continue;
+                    print i * 10 + j;
+                }
+            }
+        ",
+        &["0", "10", "20", "22"],
+    );
+    // out of nested blocks, whose variables closures can still see
+    assert_prints(
+        r"
+            var fs = [];
+            for (var i = 0; i < 3; i = i + 1) {
+                var a = i;
+                {
+                    var b = a * 2;
+                    fun f() { return a + b; }
+                    fs.push(f);
+                    if (i == 1) continue;
+                    if (i == 2) break;
+                }
+                print a;
+            }
+            for (var i = 0; i < fs.len(); i = i + 1) print fs[i]();
+        ",
+        &["0", "0", "3", "6"],
+    );
+    assert_errs(
+        "break;",
+        "[line 1] Error at 'break': Can't use 'break' outside of a loop.",
+    );
+    assert_errs(
+        "while (true) { fun f() { continue; } }",
+        "[line 1] Error at 'continue': Can't use 'continue' outside of a loop.",
+    );
+}
+
+#[test]
+fn test_exceptions() {
+    assert_prints(
+        r#"try { print 1; throw "oops"; print 2; } catch (e) { print e; }"#,
+        &["1", "oops"],
+    );
+    // runtime errors, from any depth
+    assert_prints(
+        r"
+            fun f(n) {
+                if (n == 0) return nil + 1;
+                return f(n - 1);
+            }
+            try {
+                f(3);
+            } catch (e) {
+                print e.message;
+                print e.line;
+            }
+            try { undefined; } catch (e) { print e.message; }
+            try { f(); } catch (e) { print e.message; }
+            try { [1][2]; } catch (e) { print e; }
+        ",
+        &[
+            "invalid types for addition",
+            "3",
+            "Undefined variable 'undefined'.",
+            "Expected 1 arguments but got 0.",
+            "<error: List index 2 out of range.>",
+        ],
+    );
+    // finally runs however we leave
+    assert_prints(
+        r#"
+            try { print 1; } finally { print "f1"; }
+            try { throw 2; } catch (e) { print e; } finally { print "f2"; }
+            try {
+                try { throw 3; } finally { print "f3"; }
+            } catch (e) {
+                print e;
+            }
+            try {
+                try { throw 4; } catch (e) { throw e + 1; } finally { print "f4"; }
+            } catch (e) {
+                print e;
+            }
+            fun f() {
+                var x = "outer";
+                try {
+                    var x = "inner";
+                    return x;
+                } finally {
+                    print x;
+                }
+            }
+            print f();
+            for (var i = 0; i < 3; i = i + 1) {
+                try {
+                    if (i == 0) continue;
+                    if (i == 1) break;
+                } finally {
+                    print i;
+                }
+            }
+        "#,
+        &[
+            "1", "f1", "2", "f2", "f3", "3", "f4", "5", "outer", "inner", "0", "1",
+        ],
+    );
+    // an early exit from finally wins
+    assert_prints(
+        r"
+            fun f() {
+                try { throw 1; } finally { return 2; }
+            }
+            print f();
+            fun g() {
+                try { return 1; } finally { return 2; }
+            }
+            print g();
+        ",
+        &["2", "2"],
+    );
+    // closures over the catch variable
+    assert_prints(
+        r#"
+            var f;
+            try { throw "caught"; } catch (e) { fun g() { return e; } f = g; }
+            print f();
+        "#,
+        &["caught"],
+    );
+    assert_errs(
+        r#"throw "oops";"#,
+        "[line 1] Error: Uncaught exception: oops.",
+    );
+    // Rethrowing a runtime error reports the original.
+    assert_errs(
+        "try { nil(); } catch (e) { throw e; }",
+        "[line 1] Error: Can only call functions and classes, got 'nil'.",
+    );
+    assert_errs(
+        "try {\n -nil; } finally { print 1; }",
+        "[line 2] Error: invalid type for negation",
+    );
+    assert_errs(
+        "try { nil(); } catch (e) { e.nope; }",
+        "[line 1] Error: Undefined property 'nope'.",
+    );
+}
+
+#[test]
+fn test_internal_errors_uncaught() {
+    // Skipping the resolver lets a break slip out of a function, which is
+    // our bug, not something for the program to catch.
+    let source = "fun f() { break; }\ntry { f(); } catch (e) { print \"caught\"; }";
+    let prog = parser::parse(scanner::scan_tokens(source).unwrap()).unwrap();
+    let (err, printed) = capture_prints(|lox| lox.execute_program(&prog).unwrap_err());
+    assert_eq!(
+        (err.code, err.to_string()),
+        (
+            ErrorCode::Internal,
+            "[line 1] Error: [resolver bug] Can't break or continue outside of a loop.".to_string()
+        )
+    );
+    assert!(printed.is_empty());
+}
+
+#[test]
+fn test_import() {
+    // Imports are relative to the importing file, so we give each module
+    // here an absolute path.
+    let dir = std::env::temp_dir().join(format!("lox-test-import-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let write = |name: &str, source: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, source).unwrap();
+        path.display().to_string()
+    };
+    let lib = write(
+        "lib.lox",
+        r#"
+            print "loading lib";
+            var x = 1;
+            fun getX() { return x; }
+            fun setX(value) { x = value; }
+        "#,
+    );
+    write("sub/inner.lox", "var y = 2;");
+    let outer = write(
+        "sub/outer.lox",
+        r#"import "inner.lox" as inner; var z = inner.y;"#,
+    );
+    let cycle = write("cycle.lox", r#"import "cycle.lox" as self;"#);
+    let broken = write("broken.lox", "var a = 1;\nnil();");
+    let throws = write("throws.lox", r#"throw "oops";"#);
+    let missing = dir.join("missing.lox").display().to_string();
+
+    assert_prints(
+        &format!(
+            r#"
+                import "{lib}" as lib;
+                import "{lib}" as again;
+                var x = "main";
+                print lib.x;
+                lib.setX(2);
+                print lib.getX();
+                print x;
+                print lib == again;
+            "#
+        ),
+        &["loading lib", "1", "2", "main", "true"],
+    );
+    assert_prints(
+        &format!(r#"import "{outer}" as m; print m.z; print m.inner;"#),
+        &[
+            "2",
+            &format!("<module {}>", dir.join("sub/inner.lox").display()),
+        ],
+    );
+    assert_errs(
+        &format!(r#"import "{lib}" as lib; lib.nope;"#),
+        "[line 1] Error: Undefined property 'nope'.",
+    );
+    assert_errs(
+        &format!(r#"import "{cycle}" as m;"#),
+        &format!(
+            "[line 1] Error: In module '{cycle}' imported from '<stdin>', line 1: \
+             Circular import of '{cycle}' from '{cycle}'."
+        ),
+    );
+    assert_errs(
+        &format!("\n\nimport \"{broken}\" as m;"),
+        &format!(
+            "[line 3] Error: In module '{broken}' imported from '<stdin>', line 2: \
+             Can only call functions and classes, got 'nil'."
+        ),
+    );
+    assert_errs(
+        &format!(r#"import "{throws}" as m;"#),
+        &format!(
+            "[line 1] Error: In module '{throws}' imported from '<stdin>', line 1: \
+             Uncaught exception: oops."
+        ),
+    );
+    // Failures in nested imports read as a chain of them, and a module's
+    // parse errors come all together.
+    let nested = write("nested.lox", &format!("import \"{broken}\" as b;"));
+    let unparsable = write("unparsable.lox", "print (;\nvar = 1;");
+    assert_errs(
+        &format!("import \"{nested}\" as m;"),
+        &format!(
+            "[line 1] Error: In module '{nested}' imported from '<stdin>', line 1: \
+             In module '{broken}' imported from '{nested}', line 2: \
+             Can only call functions and classes, got 'nil'."
+        ),
+    );
+    assert_errs(
+        &format!("import \"{unparsable}\" as n;"),
+        &format!(
+            "[line 1] Error: In module '{unparsable}' imported from '<stdin>', line 1 at ';': \
+             Expect expression.\n\
+             [line 1] Error: In module '{unparsable}' imported from '<stdin>', line 2 at '=': \
+             Expect variable name."
+        ),
+    );
+    // Tracebacks go on into the module, and through any it imports.
+    let calls = write("calls.lox", "fun f() {\n  nil();\n}\nf();");
+    let imports = write("imports.lox", &format!("\nimport \"{calls}\" as c;"));
+    assert_reports(
+        &format!("import \"{imports}\" as m;"),
+        &format!(
+            "Traceback (most recent call last):\n  [line 1] in script\n  \
+             [line 2] in module '{imports}'\n  [line 4] in module '{calls}'\n  \
+             [line 2] in f\n\
+             [line 1] Error: In module '{imports}' imported from '<stdin>', line 2: \
+             In module '{calls}' imported from '{imports}', line 2: \
+             Can only call functions and classes, got 'nil'.\n\
+             1 | import \"{imports}\" as m;\n  |        {}",
+            "^".repeat(imports.len() + 2)
+        ),
+    );
+    // Reports show the import's path as the code at fault.
+    assert_reports(
+        &format!("\n\nimport \"{broken}\" as m;"),
+        &format!(
+            "[line 3] Error: In module '{broken}' imported from '<stdin>', line 2: \
+             Can only call functions and classes, got 'nil'.\n\
+             3 | import \"{broken}\" as m;\n  |        {}",
+            "^".repeat(broken.len() + 2)
+        ),
+    );
+    // Builtin modules win over files of the same name, which are still there
+    // by another path.
+    write("file", "var mine = true;");
+    let shadows = write(
+        "shadows.lox",
+        r#"import "file" as builtin; import "./file" as mine;"#,
+    );
+    assert_prints(
+        &format!(r#"import "{shadows}" as m; print m.builtin; print m.mine.mine;"#),
+        &["<module file>", "true"],
+    );
+    // The main script is part of the cycle too, and so doesn't run again.
+    let main = write(
+        "main.lox",
+        "print \"running main\";\nimport \"cycle_b.lox\" as b;",
+    );
+    let cycle_b = write("cycle_b.lox", r#"import "main.lox" as main;"#);
+    let expected = format!(
+        "[line 2] Error: In module '{cycle_b}' imported from '{main}', line 1: \
+         Circular import of '{main}' from '{cycle_b}'."
+    );
+    let source = std::fs::read_to_string(&main).unwrap();
+    let mut prog = parser::parse(scanner::scan_tokens(&source).unwrap()).unwrap();
+    resolver::resolve(&mut prog).unwrap();
+    let (err, printed) = capture_prints(|lox| {
+        lox.set_script(Path::new(&main));
+        lox.execute_program(&prog).unwrap_err()
+    });
+    assert_eq!(err.to_string(), expected);
+    assert_eq!(printed, ["running main"]);
+    let mut vm = vm::vm();
+    vm.set_script(Path::new(&main));
+    assert_eq!(vm.interpret(&prog).unwrap_err().to_string(), expected);
+    // The rest of the message comes from the OS.
+    for (backend, result) in execute_on_each_backend(&format!(r#"import "{missing}" as m;"#)) {
+        let message = result.unwrap_err().to_string();
+        let expected = format!("[line 1] Error: Can't import '{missing}' from '<stdin>': ");
+        assert!(message.starts_with(&expected), "{message} on {backend}");
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_lambda() {
+    assert_prints(
+        r"
+            fun apply(f, x) { return f(x); }
+            print apply(fun (n) { return n * 2; }, 21);
+            var add = fun (a, b) { return a + b; };
+            print add(1, 2);
+            print add;
+            fun () { print 3; }();
+        ",
+        &["42", "3", "<function lambda>", "3"],
+    );
+    // closures capture like named functions
+    assert_prints(
+        r"
+            fun counter() {
+                var n = 0;
+                return fun () { n = n + 1; return n; };
+            }
+            var c = counter();
+            c();
+            print c();
+            var fs = [];
+            for (var i = 0; i < 3; i = i + 1) {
+                var j = i;
+                fs.push(fun () { return j; });
+            }
+            print fs[0]() + fs[2]();
+        ",
+        &["2", "2"],
+    );
+    // and the collector frees them the same way, as in test_gc_closures
+    let source = r"
+        fun outer() {
+            var f;
+            f = fun () { return f; };
+            return f;
+        }
+        for (var i = 0; i < 10; i = i + 1) {
+            outer();
+        }
+        var kept = outer();
+        print gc();
+        print kept() == kept;
+    ";
+    for (backend, result) in execute_on_each_backend(source) {
+        let freed = if backend == "vm" { "20" } else { "10" };
+        assert_eq!(result.unwrap(), vec![freed, "true"], "on {backend}");
+    }
+    assert_errs(
+        "var f = fun () { return; }; f(1);",
+        "[line 1] Error: Expected 0 arguments but got 1.",
+    );
+    assert_errs(
+        "while (true) { fun () { break; }; }",
+        "[line 1] Error at 'break': Can't use 'break' outside of a loop.",
+    );
+}
+
+#[test]
+fn test_traceback() {
+    assert_reports(
+        "fun g(x) {\n  return x + nil;\n}\nfun f() { return g(1); }\n\nf();",
+        "Traceback (most recent call last):\n  [line 6] in script\n  [line 4] in f\n  \
+         [line 2] in g\n[line 2] Error: invalid types for addition\n\
+         2 |   return x + nil;\n  |          ^^^^^^^",
+    );
+    // methods, initializers, natives and lambdas
+    assert_reports(
+        "class A {\n  init() { this.m(); }\n  m() { fun () { clock(1); }(); }\n}\nA();",
+        "Traceback (most recent call last):\n  [line 5] in script\n  [line 2] in init\n  \
+         [line 3] in m\n  [line 3] in lambda\n[line 3] Error: Expected 0 arguments but got 1.\n\
+         3 |   m() { fun () { clock(1); }(); }\n  |                  ^^^^^^^^",
+    );
+    assert_reports(
+        "fun f() {\n  throw \"oops\";\n}\nf();",
+        "Traceback (most recent call last):\n  [line 4] in script\n  [line 2] in f\n\
+         [line 2] Error: Uncaught exception: oops.\n2 |   throw \"oops\";\n  |   ^^^^^",
+    );
+    // A rethrown error keeps the trace from where it happened.
+    assert_reports(
+        "fun f() { nil(); }\ntry { f(); } catch (e) {\n  throw e;\n}",
+        "Traceback (most recent call last):\n  [line 2] in script\n  [line 1] in f\n\
+         [line 1] Error: Can only call functions and classes, got 'nil'.\n\
+         1 | fun f() { nil(); }\n  |           ^^^^^",
+    );
+    // Errors outside any function have no traceback.
+    assert_reports(
+        "nil();",
+        "[line 1] Error: Can only call functions and classes, got 'nil'.\n1 | nil();\n  | ^^^^^",
+    );
+}
+
+#[test]
+fn test_stack_overflow() {
+    let recurse = "fun f(n) {\n  return f(n + 1);\n}\n";
+    assert_errs(
+        &format!("{recurse}f(0);"),
+        "[line 2] Error: Stack overflow.",
+    );
+    assert_reports(
+        &format!("{recurse}fun g() {{ f(0); }}\ng();"),
+        &format!(
+            "Traceback (most recent call last):\n  [line 5] in script\n  [line 4] in g\n\
+             {}  [Previous line repeated 996 more times]\n[line 2] Error: Stack overflow.\n\
+             2 |   return f(n + 1);\n  |          ^^^^^^^^",
+            "  [line 2] in f\n".repeat(3),
+        ),
+    );
+    // It's catchable, and we can carry on afterwards.
+    assert_prints(
+        r"
+            var depth = 0;
+            fun f() { depth = depth + 1; f(); }
+            try { f(); } catch (e) { print e.message; }
+            print depth;
+            f = nil;
+            fun g(n) { if (n > 0) return 1 + g(n - 1); return 0; }
+            print g(999);
+        ",
+        &["Stack overflow.", "1000", "999"],
+    );
+    // Hosts can set the limit.
+    let tokens = scanner::scan_tokens("fun f(n) { if (n > 0) f(n - 1); }\nf(10);").unwrap();
+    let mut prog = parser::parse(tokens).unwrap();
+    resolver::resolve(&mut prog).unwrap();
+    for limit in [10, 11] {
+        let mut interpreter = interpreter();
+        interpreter.set_max_depth(limit);
+        let mut vm = vm::vm();
+        vm.set_max_depth(limit);
+        let results = [interpreter.execute_program(&prog), vm.interpret(&prog)];
+        for result in results {
+            match limit {
+                10 => assert_eq!(
+                    result.unwrap_err().to_string(),
+                    "[line 1] Error: Stack overflow."
+                ),
+                _ => assert!(result.is_ok()),
+            }
+        }
+    }
+    // Far deeper than the test thread's 2MB of stack would allow, had we
+    // not grown it.
+    let tokens = scanner::scan_tokens("fun f(n) { if (n > 0) f(n - 1); }\nf(3000);").unwrap();
+    let mut prog = parser::parse(tokens).unwrap();
+    resolver::resolve(&mut prog).unwrap();
+    let mut interpreter = interpreter();
+    interpreter.set_max_depth(100_000);
+    assert!(interpreter.execute_program(&prog).is_ok());
+}
+
+#[test]
+fn test_error_snippets() {
+    // The whole failing sub-expression is underlined.
+    assert_reports(
+        "var x = 1;\nprint 2 * (x + nil) - 1;",
+        "[line 2] Error: invalid types for addition\n\
+         2 | print 2 * (x + nil) - 1;\n  |            ^^^^^^^",
+    );
+    assert_reports(
+        "var l = [1];\nprint l[5];",
+        "[line 2] Error: List index 5 out of range.\n2 | print l[5];\n  |       ^^^^",
+    );
+    // Columns count characters, and tabs are kept so the carets line up.
+    assert_reports(
+        "print \"\u{e9}\";\t-nil;",
+        "[line 1] Error: invalid type for negation\n\
+         1 | print \"\u{e9}\";\t-nil;\n  |           \t^^^^",
+    );
+    // Every parse error gets its own snippet.
+    assert_reports(
+        "var = 1;\nprint (1;",
+        "[line 1] Error at '=': Expect variable name.\n1 | var = 1;\n  |     ^\n\
+         [line 2] Error at ';': Expect ')' after expression.\n2 | print (1;\n  |         ^",
+    );
+    assert_reports(
+        "print 1 @ 2;",
+        "[line 1] Error: Unexpected character: '@'.\n1 | print 1 @ 2;\n  |         ^",
+    );
+}
+
+#[test]
+fn test_diagnostics() {
+    assert_codes(
+        "print 1 @ 2 # 3;",
+        &[ErrorCode::Lexical, ErrorCode::Lexical],
+    );
+    assert_codes(
+        "var = 1;\nprint (1;",
+        &[ErrorCode::Syntax, ErrorCode::Syntax],
+    );
+    assert_codes("return 1;", &[ErrorCode::Resolution]);
+    assert_codes("print -nil;", &[ErrorCode::Runtime]);
+    assert_codes("fun f() { f(); } f();", &[ErrorCode::StackOverflow]);
+    assert_codes("throw 1;", &[ErrorCode::UncaughtException]);
+    // Rethrowing an error keeps its code.
+    assert_codes(
+        "try { nil(); } catch (e) { throw e; }",
+        &[ErrorCode::Runtime],
+    );
+    assert_codes(r#"import "nope.lox" as nope;"#, &[ErrorCode::Import]);
+    for (backend, result) in execute_on_each_backend("fun f() {\n  -nil;\n}\nf();") {
+        let diagnostics = result.unwrap_err().diagnostics();
+        assert_eq!(
+            diagnostics[0].to_json().to_string(),
+            r#"{"code":"E0100","column":3,"line":2,"message":"invalid type for negation","#
+                .to_string()
+                + r#""notes":["[line 4] in script","[line 2] in f"],"severity":"error","#
+                + r#""span":{"end":16,"start":12}}"#,
+            "on {backend}"
+        );
+    }
+}
+
+#[test]
+fn test_host_api() {
+    let ((), printed) = capture_prints(|lox| {
+        lox.define_function("double", |n: f64| n * 2.0);
+        assert_eq!(lox.eval("double(21);").unwrap(), 42.0.into());
+        assert_eq!(lox.eval("var x = 1;").unwrap(), Literal::Nil.into());
+        // Definitions are kept from one eval to the next.
+        lox.eval(r#"var greeting = "hi"; fun greet(name) { print greeting + " " + name; }"#)
+            .unwrap();
+        lox.set_global("greeting", "hello");
+        lox.eval(r#"greet("host");"#).unwrap();
+        assert_eq!(lox.get_global("x").unwrap(), 1.0.into());
+        assert_eq!(lox.get_global("nope"), None);
+        // Host functions' errors are Lox errors.
+        assert_eq!(
+            lox.eval(r#"double("a");"#).unwrap_err().to_string(),
+            "[line 1] Error: Expected a number for argument 1 of 'double', got 'a'."
+        );
+        lox.eval("try { double(nil); } catch (e) { print e.message; }")
+            .unwrap();
+        assert_eq!(
+            lox.eval("1 +").unwrap_err().to_string(),
+            "[line 1] Error at end: Expect expression."
+        );
+    });
+    assert_eq!(
+        printed,
+        [
+            "hello host",
+            "Expected a number for argument 1 of 'double', got 'nil'."
+        ]
+    );
+
+    assert_eq!(
+        Object::from(Some("a")),
+        Literal::String("a".to_string()).into()
+    );
+    assert_eq!(Object::from(None::<bool>), Literal::Nil.into());
+    assert_eq!(String::try_from(Object::from("a")), Ok("a".to_string()));
+    assert_eq!(bool::try_from(Object::from(true)), Ok(true));
+    assert_eq!(
+        bool::try_from(Object::from(1.0)),
+        Err("Expected a boolean, got '1'.".to_string())
+    );
+}
+
+#[test]
+fn test_drop_frees_globals() {
+    // Global functions and classes refer back to the globals they're in, so
+    // dropping a backend has to break those cycles, or everything in its
+    // globals would leak, e.g. readLine(), and so its input (`witness`).
+    let witness: Rc<[u8]> = Rc::from(&b"line"[..]);
+    let source = "fun f() { return f; } class A { m() { return A; } } var a = A();";
+    {
+        let mut lox = interpreter();
+        lox.set_input(std::io::Cursor::new(witness.clone()));
+        lox.eval(source).unwrap();
+        let mut vm = vm::vm();
+        vm.set_input(std::io::Cursor::new(witness.clone()));
+        vm.interpret_source(source).unwrap();
+        assert_eq!(Rc::strong_count(&witness), 3);
+    }
+    assert_eq!(Rc::strong_count(&witness), 1);
+}
+
+#[test]
+fn test_typed_natives() {
+    let mut lox = interpreter();
+    lox.define_function("join", |a: String, b: Option<String>, c: bool| {
+        if c {
+            Err("c must be false".to_string())
+        } else {
+            Ok(a + &b.unwrap_or_default())
+        }
+    });
+    lox.define_function(
+        "either",
+        |a: Object, b: Object| if a.is_truthy() { a } else { b },
+    );
+    lox.define_function("nothing", || ());
+    assert_eq!(lox.eval(r#"join("a", "b", false);"#).unwrap(), "ab".into());
+    assert_eq!(lox.eval(r#"join("a", nil, false);"#).unwrap(), "a".into());
+    assert_eq!(lox.eval("either([1], 3);").unwrap().to_string(), "[1]");
+    assert_eq!(lox.eval("either(nil, 3);").unwrap(), 3.0.into());
+    assert_eq!(lox.eval("nothing();").unwrap(), Literal::Nil.into());
+    assert_eq!(lox.eval("join;").unwrap().to_string(), "<function join>");
+    for (source, error) in [
+        (r#"join("a", "b", true);"#, "c must be false"),
+        (
+            r#"join("a", 1, false);"#,
+            "Expected a string or nil for argument 2 of 'join', got '1'.",
+        ),
+        (r#"join("a");"#, "Expected 3 arguments but got 1."),
+        ("nothing(1);", "Expected 0 arguments but got 1."),
+    ] {
+        assert_eq!(
+            lox.eval(source).unwrap_err().to_string(),
+            format!("[line 1] Error: {error}")
+        );
+    }
+}
+
+#[test]
+fn test_eval_outlives_source() {
+    let mut lox = interpreter();
+    // Functions keep what they need of their code, including for errors.
+    let source = "fun half(n) {\n  return n / \"2\";\n}".to_string();
+    lox.eval(&source).unwrap();
+    drop(source);
+    assert_eq!(lox.eval("half;").unwrap().to_string(), "<function half>");
+    assert_eq!(
+        lox.eval("half(1);").unwrap_err().report(),
+        "Traceback (most recent call last):\n  \
+        [line 1] in script\n  \
+        [line 2] in half\n\
+        [line 2] Error: invalid types for division\n\
+        2 |   return n / \"2\";\n  \
+        |          ^^^^^^^"
+    );
+    // And once the interpreter is gone, so is the code, AST and all.
+    let mut prog = parser::parse(scanner::scan_tokens("fun f() { return f; }").unwrap()).unwrap();
+    resolver::resolve(&mut prog).unwrap();
+    let source = Rc::downgrade(&prog.source);
+    let Stmt::Function(declaration) = &prog.stmts[0] else {
+        panic!("expected a function, got {:?}", prog.stmts[0]);
+    };
+    let declaration = Rc::downgrade(declaration);
+    lox.execute_program(&prog).unwrap();
+    drop(prog);
+    assert!(source.upgrade().is_some() && declaration.upgrade().is_some());
+    drop(lox);
+    assert!(source.upgrade().is_none() && declaration.upgrade().is_none());
+}
+
+#[test]
+fn test_eval_line() {
+    let ((), printed) = capture_prints(|lox| {
+        for line in [
+            "1 + 2",
+            "var a = \"x\";\n",
+            "a + a\n",
+            "a;",
+            "print a;",
+            "[a]",
+        ] {
+            lox.eval_line(line).unwrap();
+        }
+    });
+    assert_eq!(printed, ["3", "xx", "x", r#"["x"]"#]);
+}
+
+#[test]
+fn test_dump_globals() {
+    let source = "var b = [1]; var a = \"x\"; fun f() {}";
+    let mut lox = interpreter();
+    lox.eval(source).unwrap();
+    let mut vm = vm::vm();
+    vm.interpret_source(source).unwrap();
+    let dump = lox.dump_globals();
+    assert_eq!(dump, vm.dump_globals());
+    // Sorted by name, among the builtins, which we leave out here.
+    let ours: Vec<_> = dump
+        .lines()
+        .filter(|line| ["a ", "b ", "f "].iter().any(|name| line.starts_with(name)))
+        .collect();
+    assert_eq!(ours, ["a = x", "b = [1]", "f = <function f>"]);
+}
+
+#[test]
+fn test_completion_names() {
+    let source = "class A { m() {} } class B < A { n() {} } var b = B(); b.f = 1;";
+    let mut lox = interpreter();
+    lox.eval(source).unwrap();
+    let mut vm = vm::vm();
+    vm.interpret_source(source).unwrap();
+    for (mut globals, mut properties) in [
+        (lox.global_names(), lox.property_names()),
+        (vm.global_names(), vm.property_names()),
+    ] {
+        globals.sort();
+        properties.sort();
+        assert!(globals.contains(&"b".to_string()), "{globals:?}");
+        assert!(globals.contains(&"clock".to_string()), "{globals:?}");
+        assert_eq!(properties, ["f", "m", "n"]);
+    }
+}
+
+#[test]
+fn test_exit() {
+    // Nothing runs after exit(), even in catch and finally blocks, or the
+    // code that imported the module exiting.
+    let dir = std::env::temp_dir().join(format!("lox-test-exit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let module = dir.join("exits.lox");
+    std::fs::write(&module, "exit(4);").unwrap();
+    let sources = [
+        (
+            "var a = 1; try { exit(3); } catch (e) { a = 2; } finally { a = 3; } a = 4;",
+            3,
+        ),
+        (
+            "var a = 1; fun f() { exit(0); } try { f(); } finally { a = 2; }",
+            0,
+        ),
+        (
+            &format!("var a = 1; import \"{}\" as m; a = 2;", module.display()),
+            4,
+        ),
+    ];
+    for (source, status) in sources {
+        let mut lox = interpreter();
+        let mut vm = vm::vm();
+        for (err, globals) in [
+            (lox.eval(source).unwrap_err(), lox.dump_globals()),
+            (vm.interpret_source(source).unwrap_err(), vm.dump_globals()),
+        ] {
+            assert_eq!((err.code, err.exit), (ErrorCode::Exit, status), "{source}");
+            assert!(globals.contains("\na = 1\n"), "{source}: {globals}");
+        }
+        // And the same again, now that it's been imported.
+        assert_eq!(lox.eval(source).unwrap_err().code, ErrorCode::Exit);
+        assert_eq!(
+            vm.interpret_source(source).unwrap_err().code,
+            ErrorCode::Exit
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    // Host functions can exit too.
+    let mut lox = interpreter();
+    lox.define_function("quit", || Err::<(), _>(NativeError::Exit(9)));
+    let err = lox.eval("try { quit(); } catch (e) {}").unwrap_err();
+    assert_eq!((err.code, err.exit), (ErrorCode::Exit, 9));
+    assert_errs(
+        "exit(1.5);",
+        "[line 1] Error: Exit status must be a whole number from 0 to 255, got 1.5.",
+    );
+    assert_errs(
+        "try { exit(256); } catch (e) { print e.message; } exit(-1);",
+        "[line 1] Error: Exit status must be a whole number from 0 to 255, got -1.",
+    );
+}
+
+#[test]
+fn test_getenv_and_args() {
+    assert_prints(
+        r#"print getenv("PATH") == nil; print getenv("LOX_TEST_UNSET_VARIABLE");"#,
+        &["false", "nil"],
+    );
+    let mut lox = interpreter();
+    lox.set_args(["a".to_string(), "b c".to_string()]);
+    assert_eq!(lox.eval("args;").unwrap().to_string(), r#"["a", "b c"]"#);
+    let mut vm = vm::vm();
+    vm.set_args(["a".to_string(), "b c".to_string()]);
+    vm.interpret_source("var copy = args;").unwrap();
+    assert!(vm.dump_globals().contains("\ncopy = [\"a\", \"b c\"]\n"));
+}
+
+#[test]
+fn test_read_input() {
+    let source = "var a = readLine(); var b = readLine(); var rest = readAll();
+        var c = readLine(); var d = readAll();";
+    let mut lox = interpreter();
+    lox.set_input(&b"one\r\ntwo\nthree\nfour"[..]);
+    lox.eval(source).unwrap();
+    let mut vm = vm::vm();
+    vm.set_input(&b"one\r\ntwo\nthree\nfour"[..]);
+    vm.interpret_source(source).unwrap();
+    for globals in [lox.dump_globals(), vm.dump_globals()] {
+        for expected in [
+            "\na = one\n",
+            "\nb = two\n",
+            "\nrest = three\nfour\n",
+            "\nc = nil\n",
+            "\nd = \n",
+        ] {
+            assert!(globals.contains(expected), "{globals}");
+        }
+    }
+    lox.set_input(&b"\xff\n"[..]);
+    vm.set_input(&b"\xff\n"[..]);
+    let expected = "[line 1] Error: Can't read input: stream did not contain valid UTF-8.";
+    assert_eq!(lox.eval("readLine();").unwrap_err().to_string(), expected);
+    assert_eq!(
+        vm.interpret_source("readLine();").unwrap_err().to_string(),
+        expected
+    );
+}
+
+#[test]
+fn test_files() {
+    let dir = std::env::temp_dir().join(format!("lox-test-files-{}", std::process::id()));
+    let dir = dir.display().to_string();
+    // The backends run one after the other, each cleaning up after itself.
+    assert_prints(
+        &format!(
+            r#"
+                import "file" as file;
+                var dir = "{dir}";
+                file.mkdir(dir + "/sub/deeper");
+                file.mkdir(dir + "/sub");
+                print file.exists(dir + "/notes.txt");
+                file.writeFile(dir + "/notes.txt", "one");
+                file.appendFile(dir + "/notes.txt", " two");
+                file.appendFile(dir + "/new.txt", "x");
+                print file.exists(dir + "/notes.txt");
+                print file.readFile(dir + "/notes.txt");
+                print file.listDir(dir);
+                try {{
+                    file.remove(dir + "/sub");
+                }} catch (e) {{
+                    print "not empty";
+                }}
+                file.remove(dir + "/sub/deeper");
+                file.remove(dir + "/sub");
+                file.remove(dir + "/notes.txt");
+                file.remove(dir + "/new.txt");
+                print file.listDir(dir);
+                file.remove(dir);
+                print file.exists(dir);
+            "#
+        ),
+        &[
+            "false",
+            "true",
+            "one two",
+            r#"["new.txt", "notes.txt", "sub"]"#,
+            "not empty",
+            "[]",
+            "false",
+        ],
+    );
+    // The rest of the message comes from the OS.
+    let missing = format!("{dir}/missing.txt");
+    let os_error = std::fs::read_to_string(&missing).unwrap_err();
+    assert_errs(
+        &format!(r#"import "file" as f; f.readFile("{missing}");"#),
+        &format!("[line 1] Error: Can't read '{missing}': {os_error}."),
+    );
+    let os_error = std::fs::read_dir(&missing).unwrap_err();
+    assert_errs(
+        &format!(r#"import "file" as f; f.listDir("{missing}");"#),
+        &format!("[line 1] Error: Can't list '{missing}': {os_error}."),
+    );
+    // They're only in the module, not every program's globals.
+    assert_errs(
+        "readFile;",
+        "[line 1] Error: Undefined variable 'readFile'.",
+    );
+    assert_prints(
+        r#"import "file" as a; import "file" as b; print a == b; print a;"#,
+        &["true", "<module file>"],
+    );
+}
@@ -0,0 +1,46 @@
+//! Lox, from Crafting Interpreters, as a library, for embedding in Rust
+//! programs. The main entry point is [`Interpreter`] (the tree-walker):
+//! create one, define host functions and globals on it, and `eval` Lox code,
+//! e.g.:
+//!
+//! ```
+//! let mut lox = crafting_interpreters::interpreter();
+//! lox.define_function("double", |n: f64| n * 2.0);
+//! let result = lox.eval("double(21);")?;
+//! # Ok::<(), crafting_interpreters::LoxError>(())
+//! ```
+//!
+//! The bytecode [`Vm`] runs the same language, for the command-line tool,
+//! but has no host API; it's exported only for that, and is unstable.
+
+mod ast;
+mod ast_printer;
+mod builtins;
+mod chunk;
+mod compiler;
+mod environment;
+mod error;
+mod file;
+mod gc;
+mod interpreter;
+mod list;
+mod map;
+mod module;
+mod native;
+mod object;
+mod parser;
+mod resolver;
+mod scanner;
+mod unwind;
+mod value;
+mod vm;
+
+pub use ast_printer::print_ast;
+pub use error::{Diagnostic, ErrorCode, LoxError, Severity, DEFAULT_MAX_DEPTH};
+pub use interpreter::{interpreter, Interpreter};
+pub use native::{FromValue, NativeError, NativeFunction, NativeResult};
+pub use object::{Literal, Object};
+pub use parser::is_incomplete;
+pub use resolver::check;
+pub use scanner::{keywords, print_tokens};
+pub use vm::{vm, Vm};
@@ -0,0 +1,223 @@
+use crate::gc::{self, Trace};
+use crate::map::{self, Map};
+use crate::native::NativeFn;
+use crate::object::Literal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+// Lists (and maps) work the same on both backends, so their behavior is
+// written once here, generic over the backend's value type.
+pub trait Element: Clone + fmt::Display + From<Literal> + From<Rc<RefCell<Vec<Self>>>> {
+    fn literal(&self) -> Option<&Literal>;
+
+    // Visits the tracked objects this value refers to; see gc::Trace.
+    fn trace(&self, visit: &mut dyn FnMut(*const ()));
+
+    // A native function with the given name and arity; see builtins::define.
+    fn native(name: &str, arity: usize, function: NativeFn<Self>) -> Self;
+
+    // A builtin module (e.g. "file") with the given members.
+    fn module(name: &str, members: HashMap<String, Self>) -> Self;
+}
+
+pub fn arity(method: &str) -> Option<usize> {
+    match method {
+        "len" | "pop" => Some(0),
+        "push" | "remove" => Some(1),
+        "insert" => Some(2),
+        _ => None,
+    }
+}
+
+// Converts a Lox value to an index into a list of the given length; when
+// inserting, the index may also be one past the end.
+fn index<T: Element>(index: &T, len: usize, inserting: bool) -> Result<usize, String> {
+    let Some(Literal::Number(n)) = index.literal() else {
+        return Err(format!("List index must be a number, got '{index}'."));
+    };
+    if n.fract() != 0.0 {
+        return Err("List index must be a whole number.".to_string());
+    }
+    let limit = if inserting { len + 1 } else { len };
+    // Casts saturate, so anything too big is still out of range.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let i = *n as usize;
+    if *n < 0.0 || i >= limit {
+        return Err(format!("List index {n} out of range."));
+    }
+    Ok(i)
+}
+
+pub fn get<T: Element>(list: &RefCell<Vec<T>>, i: &T) -> Result<T, String> {
+    let list = list.borrow();
+    let i = index(i, list.len(), false)?;
+    Ok(list[i].clone())
+}
+
+pub fn set<T: Element>(list: &RefCell<Vec<T>>, i: &T, value: T) -> Result<(), String> {
+    let mut list = list.borrow_mut();
+    let i = index(i, list.len(), false)?;
+    list[i] = value;
+    Ok(())
+}
+
+// Calls a method, whose arity the caller has already checked.
+pub fn call<T: Element>(
+    list: &RefCell<Vec<T>>,
+    method: &str,
+    mut arguments: Vec<T>,
+) -> Result<T, String> {
+    let mut list = list.borrow_mut();
+    match (method, arguments.len()) {
+        ("len", 0) => {
+            #[allow(clippy::cast_precision_loss)]
+            let len = list.len() as f64;
+            Ok(Literal::Number(len).into())
+        }
+        ("pop", 0) => list
+            .pop()
+            .ok_or_else(|| "Can't pop from an empty list.".to_string()),
+        ("push", 1) => {
+            list.push(arguments.remove(0));
+            Ok(Literal::Nil.into())
+        }
+        ("remove", 1) => {
+            let i = index(&arguments[0], list.len(), false)?;
+            Ok(list.remove(i))
+        }
+        ("insert", 2) => {
+            let i = index(&arguments[0], list.len(), true)?;
+            list.insert(i, arguments.remove(1));
+            Ok(Literal::Nil.into())
+        }
+        _ => Err(format!("Undefined property '{method}'.")),
+    }
+}
+
+// What a list or map method is bound to.
+#[derive(Debug, Clone)]
+pub enum Receiver<T> {
+    List(Rc<RefCell<Vec<T>>>),
+    Map(Rc<RefCell<Map<T>>>),
+}
+
+// A list or map method, bound to its list or map, e.g. `list.push`. Unlike a
+// native function's closure, the collector can see what it refers to.
+#[derive(Debug, Clone)]
+pub struct BoundNative<T> {
+    pub receiver: Receiver<T>,
+    pub method: String,
+    pub arity: usize,
+}
+
+impl<T: Element> BoundNative<T> {
+    // The named method of the given list or map, if it has one.
+    pub fn bind(receiver: Receiver<T>, method: &str) -> Option<Self> {
+        let arity = match receiver {
+            Receiver::List(_) => arity(method),
+            Receiver::Map(_) => map::arity(method),
+        }?;
+        Some(BoundNative {
+            receiver,
+            method: method.to_string(),
+            arity,
+        })
+    }
+
+    // Calls the method, whose arity the caller has already checked; see
+    // map::call for new_list.
+    pub fn call(&self, arguments: Vec<T>, new_list: impl FnOnce(Vec<T>) -> T) -> Result<T, String> {
+        match &self.receiver {
+            Receiver::List(list) => call(list, &self.method, arguments),
+            Receiver::Map(map) => map::call(map, &self.method, &arguments, new_list),
+        }
+    }
+
+    pub fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        match &self.receiver {
+            Receiver::List(list) => visit(gc::address(list)),
+            Receiver::Map(map) => visit(gc::address(map)),
+        }
+    }
+}
+
+// The same method of the same list or map.
+impl<T> PartialEq for BoundNative<T> {
+    fn eq(&self, other: &Self) -> bool {
+        let same_receiver = match (&self.receiver, &other.receiver) {
+            (Receiver::List(l), Receiver::List(r)) => Rc::ptr_eq(l, r),
+            (Receiver::Map(l), Receiver::Map(r)) => Rc::ptr_eq(l, r),
+            _ => false,
+        };
+        same_receiver && self.method == other.method
+    }
+}
+
+impl<T> fmt::Display for BoundNative<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<function {}>", &self.method)
+    }
+}
+
+// Like Display, but quoting strings, for values inside a collection.
+pub fn repr<T: Element>(value: &T) -> String {
+    match value.literal() {
+        Some(Literal::String(s)) => format!("{s:?}"),
+        _ => value.to_string(),
+    }
+}
+
+thread_local! {
+    // Collections we're in the middle of printing, so we can print one that
+    // contains itself as e.g. "[...]" rather than recursing forever.
+    static PRINTING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+// Formats a collection with the given function, or prints the placeholder
+// if we're already formatting it further up the stack.
+pub fn fmt_once(
+    address: *const (),
+    placeholder: &str,
+    f: &mut fmt::Formatter<'_>,
+    fmt_contents: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+) -> fmt::Result {
+    if PRINTING.with_borrow(|printing| printing.contains(&address)) {
+        return write!(f, "{placeholder}");
+    }
+    PRINTING.with_borrow_mut(|printing| printing.push(address));
+    let result = fmt_contents(f);
+    PRINTING.with_borrow_mut(Vec::pop);
+    result
+}
+
+pub fn fmt<T: Element>(list: &RefCell<Vec<T>>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt_once(gc::address_of(list), "[...]", f, |f| {
+        write!(f, "[")?;
+        for (i, value) in list.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", repr(value))?;
+        }
+        write!(f, "]")
+    })
+}
+
+impl<T: Element> Trace for RefCell<Vec<T>> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(list) = self.try_borrow() else {
+            return false;
+        };
+        for v in list.iter() {
+            v.trace(visit);
+        }
+        true
+    }
+
+    fn clear(&self) {
+        let values = std::mem::take(&mut *self.borrow_mut());
+        drop(values);
+    }
+}
@@ -1,87 +1,633 @@
-use crate::error::LoxError;
+use crafting_interpreters::{
+    check, interpreter, is_incomplete, keywords, print_ast, print_tokens, vm, ErrorCode,
+    Interpreter, LoxError, Vm, DEFAULT_MAX_DEPTH,
+};
+use itertools::Itertools;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
 use std::env;
 use std::fs;
-use std::io;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 
-mod ast;
-mod ast_printer;
-mod environment;
-mod error;
-mod interpreter;
-mod object;
-mod parser;
-mod resolver;
-mod scanner;
-mod unwind;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    TreeWalk,
+    Bytecode,
+}
+
+// How we print errors: for people, or as JSON (one diagnostic per line) for
+// tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Options {
+    backend: Backend,
+    max_depth: usize,
+    error_format: ErrorFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Run,
+    Check,
+    Tokens,
+    Ast,
+    Repl,
+    Help,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Command> {
+        match name {
+            "run" => Some(Command::Run),
+            "check" => Some(Command::Check),
+            "tokens" => Some(Command::Tokens),
+            "ast" => Some(Command::Ast),
+            "repl" => Some(Command::Repl),
+            "help" => Some(Command::Help),
+            _ => None,
+        }
+    }
+}
+
+// Where the script's code comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Input {
+    File(String),
+    Inline(String),
+    Stdin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cli {
+    command: Command,
+    input: Input,
+    // What follows the script, for it to see as `args`.
+    args: Vec<String>,
+    options: Options,
+}
+
+const USAGE: &str = "Usage: lox [options] [command] [script.lox | -e <code> | -] [args...]";
+
+const COMMANDS: &str = "\
+Commands:
+  run     run the script (the default, given one)
+  check   look for errors in the script, without running it
+  tokens  show how the script scans
+  ast     show how the script parses
+  repl    start a REPL (the default, given no script)
+  help    show this help
+
+Given no script (or -), commands read one from stdin. Whatever follows the
+script is passed to it, as the list `args`.
+
+Options:
+  -e <code>            take the script from the command line
+  --vm                 run on the bytecode VM, not the tree-walker
+  --max-depth=N        let calls nest N deep (default 1000)
+  --error-format=json  print errors as JSON, one per line";
 
 fn main() -> ExitCode {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => {
-            run_prompt();
-            ExitCode::SUCCESS
-        }
-        2 => {
-            let result = run_file(&args[1]);
-            match result {
-                Ok(()) => ExitCode::SUCCESS,
-                Err(err) => {
-                    println!("{err}");
-                    ExitCode::from(err.exit)
-                }
+    let Some(cli) = parse_args(env::args().skip(1)) else {
+        eprintln!("{USAGE}\nTry 'lox help' for more.");
+        return ExitCode::from(64);
+    };
+    let options = cli.options;
+    match cli.command {
+        Command::Repl => return run_prompt(options),
+        Command::Help => {
+            println!("{USAGE}\n\n{COMMANDS}");
+            return ExitCode::SUCCESS;
+        }
+        _ => (),
+    }
+    let script = match read_script(&cli.input) {
+        Ok(script) => script,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::from(66);
+        }
+    };
+    let result = match cli.command {
+        Command::Check => check(&script.source),
+        Command::Tokens => print_tokens(&script.source).map(|tokens| println!("{tokens}")),
+        Command::Ast => print_ast(&script.source).map(|ast| println!("{ast}")),
+        _ => run_file(&script, cli.args, options),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if err.code == ErrorCode::Exit => ExitCode::from(err.exit),
+        Err(err) => {
+            report(&err, options.error_format);
+            ExitCode::from(err.exit)
+        }
+    }
+}
+
+// Reads `lox [options] [command] [script] [args...]`, or returns None if
+// that's not what we were given. Options can go before or after the command,
+// but not after the script, since what's there is the script's.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Cli> {
+    let mut options = Options {
+        backend: Backend::TreeWalk,
+        max_depth: DEFAULT_MAX_DEPTH,
+        error_format: ErrorFormat::Human,
+    };
+    let mut command = None;
+    let mut input = None;
+    while let Some(arg) = args.next() {
+        if arg == "--vm" {
+            options.backend = Backend::Bytecode;
+        } else if let Some(depth) = arg.strip_prefix("--max-depth=") {
+            options.max_depth = depth.parse().ok()?;
+        } else if let Some(format) = arg.strip_prefix("--error-format=") {
+            options.error_format = match format {
+                "human" => ErrorFormat::Human,
+                "json" => ErrorFormat::Json,
+                _ => return None,
+            };
+        } else if arg == "-h" || arg == "--help" {
+            command = Some(Command::Help);
+        } else if arg == "-e" {
+            input = Some(Input::Inline(args.next()?));
+            break;
+        } else if arg == "-" {
+            input = Some(Input::Stdin);
+            break;
+        } else if arg.starts_with('-') {
+            return None;
+        } else if let (None, Some(name)) = (command, Command::from_name(&arg)) {
+            command = Some(name);
+        } else {
+            input = Some(Input::File(arg));
+            break;
+        }
+    }
+    let args: Vec<String> = args.collect();
+    let command = command.unwrap_or(match input {
+        Some(_) => Command::Run,
+        None => Command::Repl,
+    });
+    let input = match (command, input) {
+        (Command::Repl | Command::Help, Some(_)) => return None,
+        (Command::Check | Command::Tokens | Command::Ast, _) if !args.is_empty() => return None,
+        (_, input) => input.unwrap_or(Input::Stdin),
+    };
+    Some(Cli {
+        command,
+        input,
+        args,
+        options,
+    })
+}
+
+// A script's code, and the file it's from, if any.
+struct Script {
+    path: Option<PathBuf>,
+    source: String,
+    // Whether it came from stdin, leaving nothing there for readLine().
+    stdin: bool,
+}
+
+// What readLine() and readAll() read when the script came from stdin: an
+// error, rather than the end of the input, which would look like an empty
+// file.
+struct StdinUsed;
+
+impl StdinUsed {
+    fn error() -> io::Error {
+        io::Error::other("the script came from stdin")
+    }
+}
+
+impl Read for StdinUsed {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(StdinUsed::error())
+    }
+}
+
+impl BufRead for StdinUsed {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Err(StdinUsed::error())
+    }
+
+    fn consume(&mut self, _: usize) {}
+}
+
+fn read_script(input: &Input) -> Result<Script, String> {
+    match input {
+        Input::File(path) => match fs::read_to_string(path) {
+            Ok(source) => Ok(Script {
+                path: Some(PathBuf::from(path)),
+                source,
+                stdin: false,
+            }),
+            Err(err) => Err(format!("Can't read '{path}': {err}.")),
+        },
+        Input::Inline(source) => Ok(Script {
+            path: None,
+            source: source.clone(),
+            stdin: false,
+        }),
+        Input::Stdin => {
+            let mut source = String::new();
+            match io::stdin().read_to_string(&mut source) {
+                Ok(_) => Ok(Script {
+                    path: None,
+                    source,
+                    stdin: true,
+                }),
+                Err(err) => Err(format!("Can't read stdin: {err}.")),
+            }
+        }
+    }
+}
+
+fn run_file(script: &Script, args: Vec<String>, options: Options) -> Result<(), LoxError> {
+    match options.backend {
+        Backend::TreeWalk => {
+            let mut interpreter = interpreter();
+            if let Some(path) = &script.path {
+                interpreter.set_script(path);
+            }
+            if script.stdin {
+                interpreter.set_input(StdinUsed);
+            }
+            interpreter.set_max_depth(options.max_depth);
+            interpreter.set_args(args);
+            interpreter.eval(&script.source).map(|_| ())
+        }
+        Backend::Bytecode => {
+            let mut vm = vm();
+            if let Some(path) = &script.path {
+                vm.set_script(path);
             }
+            if script.stdin {
+                vm.set_input(StdinUsed);
+            }
+            vm.set_max_depth(options.max_depth);
+            vm.set_args(args);
+            vm.interpret_source(&script.source)
         }
-        _ => ExitCode::from(64),
     }
 }
 
-fn run_file(path: &str) -> Result<(), LoxError> {
-    let source = fs::read_to_string(path).unwrap();
-    let tokens = scanner::scan_tokens(&source)?;
-    let mut prog = parser::parse(tokens)?;
-    resolver::resolve(&mut prog)?;
-    let mut interpreter = interpreter::interpreter();
-    interpreter.execute_program(&prog)
+// What the REPL needs from a backend.
+trait Session {
+    // Runs a line typed at the prompt.
+    fn run_line(&mut self, source: &str) -> Result<(), LoxError>;
+    // Runs a whole file's worth of code.
+    fn run_source(&mut self, source: &str) -> Result<(), LoxError>;
+    // Tells the backend which file is running (if any), for its imports.
+    fn set_script(&mut self, path: Option<&Path>);
+    fn globals(&self) -> String;
+    fn global_names(&self) -> Vec<String>;
+    fn property_names(&self) -> Vec<String>;
 }
 
-fn read_line() -> Option<String> {
-    let stdin = io::stdin();
-    let mut buffer = String::new();
-    let size = stdin.read_line(&mut buffer).unwrap();
-    if size == 0 {
-        None
-    } else {
-        Some(buffer)
+impl<F: FnMut(String)> Session for Interpreter<F> {
+    fn run_line(&mut self, source: &str) -> Result<(), LoxError> {
+        self.eval_line(source)
+    }
+
+    fn run_source(&mut self, source: &str) -> Result<(), LoxError> {
+        self.eval(source).map(|_| ())
+    }
+
+    fn set_script(&mut self, path: Option<&Path>) {
+        match path {
+            Some(path) => self.set_script(path),
+            None => self.clear_script(),
+        }
+    }
+
+    fn globals(&self) -> String {
+        self.dump_globals()
+    }
+
+    fn global_names(&self) -> Vec<String> {
+        self.global_names()
+    }
+
+    fn property_names(&self) -> Vec<String> {
+        self.property_names()
     }
 }
 
-// Leaks the source because in a REPL we do actually need to keep
-// the source forever (for functions). Just leaking explicitly is
-// easier than trying to track a reference that's basically the
-// life of the program anyway.
-fn execute_and_leak_source<'ast, 'src: 'ast, F: FnMut(String)>(
-    interpreter: &mut interpreter::Interpreter<'ast, 'src, F>,
-    source: String,
-) -> Result<(), LoxError> {
-    let tokens = scanner::scan_tokens(String::leak(source))?;
-    let mut prog = parser::parse(tokens)?;
-    resolver::resolve(&mut prog)?;
-    interpreter.execute_program(Box::leak(Box::new(prog)))
+impl<F: FnMut(String)> Session for Vm<F> {
+    fn run_line(&mut self, source: &str) -> Result<(), LoxError> {
+        self.interpret_line(source)
+    }
+
+    fn run_source(&mut self, source: &str) -> Result<(), LoxError> {
+        self.interpret_source(source)
+    }
+
+    fn set_script(&mut self, path: Option<&Path>) {
+        match path {
+            Some(path) => self.set_script(path),
+            None => self.clear_script(),
+        }
+    }
+
+    fn globals(&self) -> String {
+        self.dump_globals()
+    }
+
+    fn global_names(&self) -> Vec<String> {
+        self.global_names()
+    }
+
+    fn property_names(&self) -> Vec<String> {
+        self.property_names()
+    }
 }
 
-fn run_prompt() {
-    let mut interpreter = interpreter::interpreter();
+fn new_session(options: Options) -> Box<dyn Session> {
+    match options.backend {
+        Backend::TreeWalk => {
+            let mut interpreter = interpreter();
+            interpreter.set_max_depth(options.max_depth);
+            interpreter.set_args([]);
+            Box::new(interpreter)
+        }
+        Backend::Bytecode => {
+            let mut vm = vm();
+            vm.set_max_depth(options.max_depth);
+            vm.set_args([]);
+            Box::new(vm)
+        }
+    }
+}
+
+const HELP: &str = "\
+:env            show the variables defined
+:ast <code>     show how code parses
+:tokens <code>  show how code scans
+:load <file>    run a file in this session
+:reset          start over, forgetting all definitions
+:help           show this help";
 
+// Completes keywords and globals at the prompt, or after a dot, the
+// properties of the globals' values.
+#[derive(Default)]
+struct Completions {
+    names: Vec<String>,
+    properties: Vec<String>,
+}
+
+impl Completions {
+    fn update(&mut self, session: &dyn Session) {
+        let names = keywords().map(str::to_string).chain(session.global_names());
+        self.names = names.sorted().dedup().collect();
+        self.properties = session
+            .property_names()
+            .into_iter()
+            .sorted()
+            .dedup()
+            .collect();
+    }
+}
+
+impl Completer for Completions {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|c: char| !c.is_alphanumeric() && c != '_')
+            .map_or(0, |i| i + 1);
+        let candidates = if before[..start].ends_with('.') {
+            &self.properties
+        } else {
+            &self.names
+        };
+        let word = &before[start..];
+        let matches = candidates.iter().filter(|name| name.starts_with(word));
+        Ok((start, matches.cloned().collect()))
+    }
+}
+
+impl Hinter for Completions {
+    type Hint = String;
+}
+
+impl Highlighter for Completions {}
+
+impl Validator for Completions {}
+
+impl Helper for Completions {}
+
+type LineEditor = Editor<Completions, DefaultHistory>;
+
+// Where we keep what's been typed at the prompt, across sessions.
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(".lox_history"))
+}
+
+fn run_prompt(options: Options) -> ExitCode {
+    let mut editor = match LineEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("Can't start the REPL: {err}.");
+            return ExitCode::FAILURE;
+        }
+    };
+    editor.set_helper(Some(Completions::default()));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There's none the first time.
+        let _ = editor.load_history(path);
+    }
+    let mut session = new_session(options);
+    let mut status = ExitCode::SUCCESS;
     loop {
-        let source = match read_line() {
-            None => return,
-            Some(s) => s,
+        if let Some(completions) = editor.helper_mut() {
+            completions.update(&*session);
+        }
+        let source = match read_entry(&mut editor) {
+            Ok(source) => source,
+            // Ctrl-C abandons what's been typed so far.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(_) => break,
+        };
+        if !source.trim().is_empty() {
+            let _ = editor.add_history_entry(source.as_str());
+        }
+        let result = if let Some(command) = source.trim().strip_prefix(':') {
+            run_command(command, &mut session, options)
+        } else {
+            session.run_line(&source)
         };
-        let result = execute_and_leak_source(&mut interpreter, source);
         match result {
             Ok(()) => (),
-            Err(err) => println!("{err}"),
+            Err(err) if err.code == ErrorCode::Exit => {
+                status = ExitCode::from(err.exit);
+                break;
+            }
+            Err(err) => report(&err, options.error_format),
+        }
+    }
+    if let Some(path) = &history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("Can't save history to '{}': {err}.", path.display());
+        }
+    }
+    status
+}
+
+// Reads what's typed at the prompt, over as many lines as it takes to
+// finish its statements, or until a blank line gives up on them.
+fn read_entry(editor: &mut LineEditor) -> rustyline::Result<String> {
+    let mut source = editor.readline("> ")?;
+    if source.trim_start().starts_with(':') {
+        return Ok(source);
+    }
+    while is_incomplete(&source) {
+        let line = editor.readline("... ")?;
+        if line.trim().is_empty() {
+            break;
+        }
+        source.push('\n');
+        source.push_str(&line);
+    }
+    Ok(source)
+}
+
+// Runs one of the REPL's colon commands, given without its colon.
+fn run_command(
+    command: &str,
+    session: &mut Box<dyn Session>,
+    options: Options,
+) -> Result<(), LoxError> {
+    let (name, argument) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let argument = argument.trim();
+    match name {
+        "env" => print!("{}", session.globals()),
+        "ast" => println!("{}", print_ast(argument)?),
+        "tokens" => println!("{}", print_tokens(argument)?),
+        "load" => match fs::read_to_string(argument) {
+            Ok(source) => {
+                // While it runs, its imports are relative to it.
+                session.set_script(Some(Path::new(argument)));
+                let result = session.run_source(&source);
+                session.set_script(None);
+                result?;
+            }
+            Err(err) => println!("Can't load '{argument}': {err}."),
+        },
+        "reset" => *session = new_session(options),
+        "help" => println!("{HELP}"),
+        _ => println!("Unknown command ':{name}'. Try :help."),
+    }
+    Ok(())
+}
+
+fn report(err: &LoxError, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Human => println!("{}", err.report()),
+        ErrorFormat::Json => {
+            for diagnostic in err.diagnostics() {
+                println!("{}", diagnostic.to_json());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_reset_frees_session() {
+    use std::rc::Rc;
+    for backend in [Backend::TreeWalk, Backend::Bytecode] {
+        let options = Options {
+            backend,
+            max_depth: DEFAULT_MAX_DEPTH,
+            error_format: ErrorFormat::Human,
         };
+        // A global function keeps the session's globals alive, readLine()
+        // and so its input (`witness`) included, until :reset frees them.
+        let witness: Rc<[u8]> = Rc::from(&b""[..]);
+        let input = io::Cursor::new(witness.clone());
+        let mut session: Box<dyn Session> = match backend {
+            Backend::TreeWalk => {
+                let mut interpreter = interpreter();
+                interpreter.set_input(input);
+                Box::new(interpreter)
+            }
+            Backend::Bytecode => {
+                let mut vm = vm();
+                vm.set_input(input);
+                Box::new(vm)
+            }
+        };
+        session.run_line("fun f() { return readLine; }").unwrap();
+        assert_eq!(Rc::strong_count(&witness), 2, "on {backend:?}");
+        run_command("reset", &mut session, options).unwrap();
+        assert_eq!(Rc::strong_count(&witness), 1, "on {backend:?}");
+    }
+}
+
+#[test]
+fn test_parse_args() {
+    let parse = |args: &[&str]| parse_args(args.iter().map(|arg| (*arg).to_string()));
+    let cli = parse(&["--vm", "ast", "--max-depth=5", "a.lox"]).unwrap();
+    assert_eq!(cli.command, Command::Ast);
+    assert_eq!(cli.input, Input::File("a.lox".to_string()));
+    assert_eq!(cli.options.backend, Backend::Bytecode);
+    assert_eq!(cli.options.max_depth, 5);
+    assert_eq!(parse(&["a.lox"]).unwrap().command, Command::Run);
+    // A script can be named like a command.
+    let cli = parse(&["run", "check"]).unwrap();
+    assert_eq!(cli.input, Input::File("check".to_string()));
+    assert_eq!(
+        parse(&["-e", "1;"]).unwrap().input,
+        Input::Inline("1;".to_string())
+    );
+    assert_eq!(parse(&["check"]).unwrap().input, Input::Stdin);
+    assert_eq!(parse(&[]).unwrap().command, Command::Repl);
+    assert_eq!(parse(&["repl", "a.lox"]), None);
+    let cli = parse(&["a.lox", "--vm", "b"]).unwrap();
+    assert_eq!(cli.args, ["--vm", "b"]);
+    assert_eq!(cli.options.backend, Backend::TreeWalk);
+    assert_eq!(parse(&["-e", "1;", "b"]).unwrap().args, ["b"]);
+    assert_eq!(parse(&["check", "a.lox", "b"]), None);
+    assert_eq!(parse(&["--max-depth=x"]), None);
+    assert_eq!(parse(&["-e"]), None);
+}
+
+#[test]
+fn test_read_after_stdin_script() {
+    for backend in [Backend::TreeWalk, Backend::Bytecode] {
+        let options = Options {
+            backend,
+            max_depth: DEFAULT_MAX_DEPTH,
+            error_format: ErrorFormat::Human,
+        };
+        for source in ["readLine();", "readAll();"] {
+            let script = Script {
+                path: None,
+                source: source.to_string(),
+                stdin: true,
+            };
+            let err = run_file(&script, Vec::new(), options).unwrap_err();
+            assert_eq!(err.message, "Can't read input: the script came from stdin.");
+        }
     }
 }
@@ -0,0 +1,194 @@
+use crate::gc::{self, Trace};
+use crate::list::{self, Element};
+use crate::object::Literal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+// Map keys are literals, compared as == would compare them.
+#[derive(Debug, Clone, PartialEq)]
+struct Key(Literal);
+
+// NaN is the only literal not equal to itself, and we don't allow it as a
+// key, so this is a true equivalence relation.
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(&self.0).hash(state);
+        match &self.0 {
+            // -0 == 0, so they must hash the same.
+            Literal::Number(n) if *n == 0.0 => 0.0f64.to_bits().hash(state),
+            Literal::Number(n) => n.to_bits().hash(state),
+            Literal::Bool(b) => b.hash(state),
+            Literal::String(s) => s.hash(state),
+            Literal::Nil => {}
+        }
+    }
+}
+
+fn key<T: Element>(value: &T) -> Result<Key, String> {
+    match value.literal() {
+        Some(Literal::Number(n)) if n.is_nan() => Err("Map key can't be NaN.".to_string()),
+        Some(literal) => Ok(Key(literal.clone())),
+        None => Err(format!(
+            "Map keys must be strings, numbers, booleans or nil, got '{value}'."
+        )),
+    }
+}
+
+// A hash map that remembers insertion order, so printing is deterministic.
+// Deleting an entry leaves a gap (rather than moving every later entry up),
+// which we squeeze out once gaps are most of the entries.
+#[derive(Debug)]
+pub struct Map<T> {
+    entries: Vec<Option<(Key, T)>>,
+    indices: HashMap<Key, usize>,
+}
+
+impl<T: Element> Map<T> {
+    pub fn new() -> Self {
+        Map {
+            entries: Vec::new(),
+            indices: HashMap::new(),
+        }
+    }
+
+    // The entries, in insertion order.
+    fn iter(&self) -> impl Iterator<Item = &(Key, T)> {
+        self.entries.iter().flatten()
+    }
+
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn get(&self, key_value: &T) -> Result<T, String> {
+        let key = key(key_value)?;
+        match self
+            .indices
+            .get(&key)
+            .and_then(|&i| self.entries[i].as_ref())
+        {
+            Some((_, value)) => Ok(value.clone()),
+            None => Err(format!("Undefined key {}.", list::repr(key_value))),
+        }
+    }
+
+    pub fn set(&mut self, key_value: &T, value: T) -> Result<(), String> {
+        let key = key(key_value)?;
+        if let Some(&i) = self.indices.get(&key) {
+            if let Some(entry) = &mut self.entries[i] {
+                entry.1 = value;
+            }
+        } else {
+            self.indices.insert(key.clone(), self.entries.len());
+            self.entries.push(Some((key, value)));
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, key_value: &T) -> Result<bool, String> {
+        let key = key(key_value)?;
+        let Some(i) = self.indices.remove(&key) else {
+            return Ok(false);
+        };
+        let entry = self.entries[i].take();
+        if self.entries.len() > 2 * self.len() {
+            self.compact();
+        }
+        // Drop the value last, in case that looks at the map.
+        drop(entry);
+        Ok(true)
+    }
+
+    // Removes the gaps deleted entries left.
+    fn compact(&mut self) {
+        self.entries.retain(Option::is_some);
+        for (i, (key, _)) in self.entries.iter().flatten().enumerate() {
+            self.indices.insert(key.clone(), i);
+        }
+    }
+}
+
+pub fn arity(method: &str) -> Option<usize> {
+    match method {
+        "keys" | "len" | "values" => Some(0),
+        "delete" | "has" => Some(1),
+        _ => None,
+    }
+}
+
+// Calls a method, whose arity the caller has already checked. Since lists
+// need to be allocated on the backend's heap, the caller also provides a
+// function to make one.
+pub fn call<T: Element>(
+    map: &RefCell<Map<T>>,
+    method: &str,
+    arguments: &[T],
+    new_list: impl FnOnce(Vec<T>) -> T,
+) -> Result<T, String> {
+    match (method, arguments) {
+        ("keys", []) => {
+            let keys = map
+                .borrow()
+                .iter()
+                .map(|(k, _)| k.0.clone().into())
+                .collect();
+            Ok(new_list(keys))
+        }
+        ("values", []) => {
+            let values = map.borrow().iter().map(|(_, v)| v.clone()).collect();
+            Ok(new_list(values))
+        }
+        ("len", []) => {
+            #[allow(clippy::cast_precision_loss)]
+            let len = map.borrow().len() as f64;
+            Ok(Literal::Number(len).into())
+        }
+        ("has", [k]) => {
+            let has = map.borrow().indices.contains_key(&key(k)?);
+            Ok(Literal::Bool(has).into())
+        }
+        ("delete", [k]) => {
+            let deleted = map.borrow_mut().delete(k)?;
+            Ok(Literal::Bool(deleted).into())
+        }
+        _ => Err(format!("Undefined property '{method}'.")),
+    }
+}
+
+pub fn fmt<T: Element>(map: &RefCell<Map<T>>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    list::fmt_once(gc::address_of(map), "{...}", f, |f| {
+        write!(f, "{{")?;
+        for (i, (k, v)) in map.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            let k: T = k.0.clone().into();
+            write!(f, "{}: {}", list::repr(&k), list::repr(v))?;
+        }
+        write!(f, "}}")
+    })
+}
+
+impl<T: Element> Trace for RefCell<Map<T>> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(map) = self.try_borrow() else {
+            return false;
+        };
+        for (_, v) in map.iter() {
+            v.trace(visit);
+        }
+        true
+    }
+
+    fn clear(&self) {
+        let mut map = self.borrow_mut();
+        let entries = std::mem::take(&mut map.entries);
+        map.indices.clear();
+        drop(map);
+        drop(entries);
+    }
+}
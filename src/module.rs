@@ -0,0 +1,192 @@
+use crate::error::{CallSite, ErrorCode, LoxError, Snippet};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Bookkeeping for import statements, shared by both backends, which each
+// run modules and make their namespace objects (of type T) themselves.
+//
+// Imports can only appear in top-level code, so the file an import is in is
+// always the module we're in the middle of running (or the main script).
+pub struct Modules<T> {
+    // The main script, if the program came from a file.
+    script: Option<PathBuf>,
+    // The same, canonicalized, since it's running too, so importing it
+    // (even indirectly) is circular.
+    script_path: Option<PathBuf>,
+    // Modules we're in the middle of running, innermost last.
+    loading: Vec<Source>,
+    loaded: HashMap<PathBuf, T>,
+    // Modules that come with the language (e.g. "file"), by name.
+    builtin: HashMap<String, T>,
+}
+
+// A module to run.
+pub struct Source {
+    // Canonicalized, so the same file always has the same key.
+    path: PathBuf,
+    // The path relative to where we're running, for messages.
+    pub name: String,
+    text: String,
+}
+
+pub enum Import<T> {
+    Loaded(T),
+    New(Source),
+}
+
+impl<T: Clone> Modules<T> {
+    pub fn new() -> Self {
+        Modules {
+            script: None,
+            script_path: None,
+            loading: Vec::new(),
+            loaded: HashMap::new(),
+            builtin: HashMap::new(),
+        }
+    }
+
+    // Has `import "<name>"` give the given namespace, rather than look for a
+    // file. A file of the same name is still there as e.g. "./<name>", since
+    // builtins win only when the path is exactly their name.
+    pub fn define(&mut self, name: &str, namespace: T) {
+        self.builtin.insert(name.to_string(), namespace);
+    }
+
+    pub fn set_script(&mut self, path: &Path) {
+        self.script = Some(path.to_path_buf());
+        self.script_path = fs::canonicalize(path).ok();
+    }
+
+    pub fn clear_script(&mut self) {
+        self.script = None;
+        self.script_path = None;
+    }
+
+    // The file we're currently running, for messages.
+    fn importer(&self) -> String {
+        match (self.loading.last(), &self.script) {
+            (Some(source), _) => source.name.clone(),
+            (None, Some(script)) => script.display().to_string(),
+            (None, None) => "<stdin>".to_string(),
+        }
+    }
+
+    // Finds the module an import statement refers to, relative to the file
+    // it's in. If it's new, the caller runs it between enter and leave.
+    pub fn find(&self, path: &str) -> Result<Import<T>, String> {
+        if let Some(module) = self.builtin.get(path) {
+            return Ok(Import::Loaded(module.clone()));
+        }
+        let importer = match (self.loading.last(), &self.script) {
+            (Some(source), _) => Some(PathBuf::from(&source.name)),
+            (None, script) => script.clone(),
+        };
+        let relative = match importer.as_deref().and_then(Path::parent) {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        };
+        let name = relative.display().to_string();
+        let cant_import = |err| format!("Can't import '{name}' from '{}': {err}.", self.importer());
+        let canonical = fs::canonicalize(&relative).map_err(cant_import)?;
+        if let Some(module) = self.loaded.get(&canonical) {
+            return Ok(Import::Loaded(module.clone()));
+        }
+        let running = self.loading.iter().map(|source| &source.path);
+        if running
+            .chain(&self.script_path)
+            .any(|path| *path == canonical)
+        {
+            return Err(format!(
+                "Circular import of '{name}' from '{}'.",
+                self.importer()
+            ));
+        }
+        let text = fs::read_to_string(&canonical).map_err(cant_import)?;
+        Ok(Import::New(Source {
+            path: canonical,
+            name,
+            text,
+        }))
+    }
+
+    // Starts running a module, returning its source.
+    pub fn enter(&mut self, mut source: Source) -> String {
+        let text = std::mem::take(&mut source.text);
+        self.loading.push(source);
+        text
+    }
+
+    // Finishes running the innermost module, caching its namespace.
+    pub fn leave(&mut self, namespace: T) {
+        let source = self.loading.pop().unwrap();
+        self.loaded.insert(source.path, namespace);
+    }
+
+    // Gives up on the innermost module, since the program is exiting.
+    pub fn abandon(&mut self) {
+        self.loading.pop();
+    }
+
+    // Gives up on the innermost module because of the given error (and any
+    // others with it), which we report at the import statement's path, given
+    // by the snippet. Any traceback goes on from there into the module.
+    // (Imports are only allowed at the top level, so no calls lead to them.)
+    pub fn fail(&mut self, error: &LoxError, at: &Snippet) -> LoxError {
+        let source = self.loading.pop().unwrap();
+        let importer = self.importer();
+        let wrap = |error: &LoxError| {
+            // The module's trace starts at its top level, which the import
+            // statement's line comes before.
+            let trace = match error.trace.split_first() {
+                Some((top, calls)) => {
+                    let import = CallSite {
+                        function: "script".to_string(),
+                        line: at.line,
+                    };
+                    let top = CallSite {
+                        function: format!("module '{}'", source.name),
+                        line: top.line,
+                    };
+                    [import, top]
+                        .into_iter()
+                        .chain(calls.iter().cloned())
+                        .collect()
+                }
+                None => Vec::new(),
+            };
+            LoxError {
+                // Bugs stay bugs, so nothing catches them.
+                code: if error.code == ErrorCode::Internal {
+                    ErrorCode::Internal
+                } else {
+                    ErrorCode::Import
+                },
+                line: at.line,
+                loc: String::new(),
+                exit: error.exit,
+                message: format!(
+                    "In module '{}' imported from '{importer}', line {}{}: {}",
+                    source.name, error.line, error.loc, error.message
+                ),
+                trace,
+                snippet: Some(Box::new(at.clone())),
+                more: Vec::new(),
+            }
+        };
+        LoxError {
+            more: error.more.iter().map(wrap).collect(),
+            ..wrap(error)
+        }
+    }
+
+    // How many modules we're in the middle of running.
+    pub fn running(&self) -> usize {
+        self.loading.len()
+    }
+
+    // The namespaces of every module we've run.
+    pub fn loaded(&self) -> impl Iterator<Item = &T> {
+        self.loaded.values()
+    }
+}
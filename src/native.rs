@@ -0,0 +1,228 @@
+use crate::list::Element;
+use crate::object::Literal;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Native functions written as plain Rust functions (closures, usually), with
+// typed parameters and results, e.g. `|n: f64| n * 2.0`. We work out the
+// arity from the parameters, and check and convert the arguments, which
+// works the same on either backend (values `V` are objects or VM values).
+
+/// The Rust types a native function can take as parameters.
+pub trait FromValue<V>: Sized {
+    /// For errors, e.g. "a number".
+    fn expected() -> String;
+
+    /// The value as this type.
+    ///
+    /// # Errors
+    ///
+    /// The value back again, if it isn't one.
+    fn from_value(value: V) -> Result<Self, V>;
+}
+
+impl<V: Element> FromValue<V> for f64 {
+    fn expected() -> String {
+        "a number".to_string()
+    }
+
+    fn from_value(value: V) -> Result<Self, V> {
+        match value.literal() {
+            Some(Literal::Number(n)) => Ok(*n),
+            _ => Err(value),
+        }
+    }
+}
+
+impl<V: Element> FromValue<V> for bool {
+    fn expected() -> String {
+        "a boolean".to_string()
+    }
+
+    fn from_value(value: V) -> Result<Self, V> {
+        match value.literal() {
+            Some(Literal::Bool(b)) => Ok(*b),
+            _ => Err(value),
+        }
+    }
+}
+
+impl<V: Element> FromValue<V> for String {
+    fn expected() -> String {
+        "a string".to_string()
+    }
+
+    fn from_value(value: V) -> Result<Self, V> {
+        match value.literal() {
+            Some(Literal::String(s)) => Ok(s.clone()),
+            _ => Err(value),
+        }
+    }
+}
+
+// None is nil.
+impl<V: Element, T: FromValue<V>> FromValue<V> for Option<T> {
+    fn expected() -> String {
+        format!("{} or nil", T::expected())
+    }
+
+    fn from_value(value: V) -> Result<Self, V> {
+        match value.literal() {
+            Some(Literal::Nil) => Ok(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+// Any value at all, unconverted.
+impl<V: Element> FromValue<V> for V {
+    fn expected() -> String {
+        "a value".to_string()
+    }
+
+    fn from_value(value: V) -> Result<Self, V> {
+        Ok(value)
+    }
+}
+
+// Converts a value, with an error fit to report as a Lox error if it's the
+// wrong type.
+pub fn convert<V: Element, T: FromValue<V>>(value: V) -> Result<T, String> {
+    T::from_value(value).map_err(|value| format!("Expected {}, got '{value}'.", T::expected()))
+}
+
+/// Why a native function failed: with a message, as a Lox runtime error, or
+/// (for `exit()`) because the program should stop, with this exit status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NativeError {
+    Message(String),
+    Exit(u8),
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::Message(message)
+    }
+}
+
+/// What a native function can return: a value, or the Rust types above, or
+/// (to fail with a Lox error) a Result of any of those, whose error is a
+/// message or a [`NativeError`].
+pub trait NativeResult<V> {
+    /// # Errors
+    ///
+    /// The function's failure, if it failed.
+    fn into_result(self) -> Result<V, NativeError>;
+}
+
+impl<V: Element> NativeResult<V> for f64 {
+    fn into_result(self) -> Result<V, NativeError> {
+        Ok(Literal::Number(self).into())
+    }
+}
+
+impl<V: Element> NativeResult<V> for bool {
+    fn into_result(self) -> Result<V, NativeError> {
+        Ok(Literal::Bool(self).into())
+    }
+}
+
+impl<V: Element> NativeResult<V> for String {
+    fn into_result(self) -> Result<V, NativeError> {
+        Ok(Literal::String(self).into())
+    }
+}
+
+// Nothing, i.e. nil.
+impl<V: Element> NativeResult<V> for () {
+    fn into_result(self) -> Result<V, NativeError> {
+        Ok(Literal::Nil.into())
+    }
+}
+
+impl<V: Element, T: NativeResult<V>> NativeResult<V> for Option<T> {
+    fn into_result(self) -> Result<V, NativeError> {
+        match self {
+            Some(value) => value.into_result(),
+            None => Ok(Literal::Nil.into()),
+        }
+    }
+}
+
+impl<V: Element, T: NativeResult<V>> NativeResult<V> for Result<T, String> {
+    fn into_result(self) -> Result<V, NativeError> {
+        self.map_err(NativeError::from)
+            .and_then(NativeResult::into_result)
+    }
+}
+
+impl<V: Element, T: NativeResult<V>> NativeResult<V> for Result<T, NativeError> {
+    fn into_result(self) -> Result<V, NativeError> {
+        self.and_then(NativeResult::into_result)
+    }
+}
+
+impl<V: Element> NativeResult<V> for V {
+    fn into_result(self) -> Result<V, NativeError> {
+        Ok(self)
+    }
+}
+
+// A native function as the backends store it, taking its arguments (as many
+// as its arity) as a Vec.
+pub type NativeFn<V> = Rc<RefCell<dyn FnMut(Vec<V>) -> Result<V, NativeError>>>;
+
+/// A Rust function callable from Lox. `Args` is the tuple of its parameter
+/// types, which just tells apart the implementations for each arity.
+pub trait NativeFunction<V, Args> {
+    const ARITY: usize;
+
+    /// Calls the function with exactly ARITY arguments (callers check that
+    /// first).
+    ///
+    /// # Errors
+    ///
+    /// Any arguments of the wrong type, or the function's own failure.
+    fn call(&mut self, name: &str, arguments: Vec<V>) -> Result<V, NativeError>;
+}
+
+fn argument<V: Element, T: FromValue<V>>(
+    name: &str,
+    position: usize,
+    value: V,
+) -> Result<T, String> {
+    T::from_value(value).map_err(|value| {
+        format!(
+            "Expected {} for argument {position} of '{name}', got '{value}'.",
+            T::expected()
+        )
+    })
+}
+
+macro_rules! native_function {
+    ($($arg:ident: $type_:ident),*) => {
+        impl<V: Element, F, R: NativeResult<V>, $($type_: FromValue<V>),*>
+            NativeFunction<V, ($($type_,)*)> for F
+        where
+            F: FnMut($($type_),*) -> R,
+        {
+            const ARITY: usize = <[&str]>::len(&[$(stringify!($arg)),*]);
+
+            #[allow(unused_mut, unused_variables)]
+            fn call(&mut self, name: &str, arguments: Vec<V>) -> Result<V, NativeError> {
+                let mut arguments = arguments.into_iter().zip(1..);
+                $(
+                    let (value, position) = arguments.next().unwrap();
+                    let $arg = argument::<V, $type_>(name, position, value)?;
+                )*
+                self($($arg),*).into_result()
+            }
+        }
+    };
+}
+
+native_function!();
+native_function!(a: A);
+native_function!(a: A, b: B);
+native_function!(a: A, b: B, c: C);
+native_function!(a: A, b: B, c: C, d: D);
@@ -1,15 +1,19 @@
 use crate::ast;
-use crate::environment::Environment;
+use crate::environment::{self, Environment};
 use crate::error::LoxError;
+use crate::gc::{self, Heap, Trace};
+use crate::list::{self, BoundNative, Element, Receiver};
+use crate::map::{self, Map};
+use crate::native;
 use crate::scanner;
 use crate::unwind::Unwinder;
 use derive_more::From;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
-use std::ptr;
 use std::rc::Rc;
 
+/// A number, boolean, string or nil.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Number(f64),
@@ -31,6 +35,7 @@ impl fmt::Display for Literal {
 }
 
 impl Literal {
+    #[must_use]
     pub fn is_truthy(&self) -> bool {
         match self {
             Literal::Nil => false,
@@ -40,75 +45,89 @@ impl Literal {
     }
 }
 
+/// A Lox value, as the tree-walker has it.
+///
+/// Only `Literal`, `List`, `Map` and `Error` are meant for hosts to look
+/// inside. The others hold the interpreter's internals (e.g. a function's
+/// syntax tree and environment), so match on them only to tell what kind of
+/// value you have: what's inside them is unstable, and may change in any
+/// release.
 #[derive(Debug, Clone, From)]
-pub enum Object<'ast, 'src: 'ast> {
+pub enum Object {
     Literal(Literal),
-    BuiltinFunction(BuiltinFunction<'ast, 'src>),
-    Function(Function<'ast, 'src>),
-    Class(Rc<RefCell<Class<'ast, 'src>>>),
-    Instance(Rc<RefCell<Instance<'ast, 'src>>>),
+    BuiltinFunction(BuiltinFunction),
+    BoundNative(BoundNative<Object>),
+    Function(Function),
+    Class(Rc<RefCell<Class>>),
+    Instance(Rc<RefCell<Instance>>),
+    List(Rc<RefCell<Vec<Object>>>),
+    Map(Rc<RefCell<Map<Object>>>),
+    /// A runtime error, once caught.
+    Error(Rc<LoxError>),
+    Module(Rc<Module>),
 }
 
 #[derive(Clone)]
-pub struct BuiltinFunction<'ast, 'src> {
+pub struct BuiltinFunction {
     pub arity: usize,
-    pub function:
-        Rc<RefCell<dyn FnMut(Vec<Object<'ast, 'src>>) -> Result<Object<'ast, 'src>, LoxError>>>,
+    pub function: native::NativeFn<Object>,
     pub name: String,
 }
 
-impl fmt::Debug for BuiltinFunction<'_, '_> {
+impl fmt::Debug for BuiltinFunction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<function {} (arity {})>", &self.name, &self.arity)
     }
 }
 
-impl fmt::Display for BuiltinFunction<'_, '_> {
+impl fmt::Display for BuiltinFunction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<function {}>", &self.name)
     }
 }
 
 #[derive(Clone)]
-pub struct Function<'ast, 'src> {
-    pub declaration: &'ast ast::FunctionStmt<'src>,
-    pub closure: Rc<RefCell<Environment<'ast, 'src>>>,
+pub struct Function {
+    pub declaration: Rc<ast::FunctionStmt>,
+    pub closure: Rc<RefCell<Environment>>,
     pub is_initializer: bool,
 }
 
-impl<'ast, 'src> Function<'ast, 'src> {
-    pub fn bind(&self, instance: Rc<RefCell<Instance<'ast, 'src>>>) -> Self {
+impl Function {
+    pub fn bind(&self, instance: Rc<RefCell<Instance>>, heap: &mut Heap) -> Self {
         let mut environment = Environment::child(self.closure.clone());
         environment.define("this", instance.into());
+        let closure = Rc::new(RefCell::new(environment));
+        environment::track(&closure, heap);
         Function {
-            declaration: self.declaration,
-            closure: Rc::new(RefCell::new(environment)),
+            declaration: self.declaration.clone(),
+            closure,
             is_initializer: self.is_initializer,
         }
     }
 }
 
-impl fmt::Debug for Function<'_, '_> {
+impl fmt::Debug for Function {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<function {}>", &self.declaration.name.lexeme)
     }
 }
 
-impl fmt::Display for Function<'_, '_> {
+impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<function {}>", &self.declaration.name.lexeme)
     }
 }
 
 #[derive(Debug)]
-pub struct Class<'ast, 'src> {
-    pub name: &'ast scanner::Token<'src>,
-    pub superclass: Option<Rc<RefCell<Class<'ast, 'src>>>>,
-    pub methods: HashMap<String, Function<'ast, 'src>>,
+pub struct Class {
+    pub name: scanner::Token,
+    pub superclass: Option<Rc<RefCell<Class>>>,
+    pub methods: HashMap<String, Function>,
 }
 
-impl<'ast, 'src> Class<'ast, 'src> {
-    pub fn find_method(&self, name: &str) -> Option<Function<'ast, 'src>> {
+impl Class {
+    pub fn find_method(&self, name: &str) -> Option<Function> {
         if let Some(method) = self.methods.get(name) {
             Some(method.clone())
         } else if let Some(sup) = &self.superclass {
@@ -117,82 +136,288 @@ impl<'ast, 'src> Class<'ast, 'src> {
             None
         }
     }
+
+    // Every method's name, including inherited ones.
+    pub fn method_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.methods.keys().cloned().collect();
+        if let Some(sup) = &self.superclass {
+            names.extend(sup.borrow().method_names());
+        }
+        names
+    }
 }
 
-impl fmt::Display for Class<'_, '_> {
+impl fmt::Display for Class {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<class {}>", &self.name.lexeme)
     }
 }
 
 #[derive(Debug)]
-pub struct Instance<'ast, 'src> {
-    pub class_: Rc<RefCell<Class<'ast, 'src>>>,
-    pub fields: HashMap<String, Object<'ast, 'src>>,
+pub struct Instance {
+    pub class_: Rc<RefCell<Class>>,
+    pub fields: HashMap<String, Object>,
 }
 
-fn instance_get_field<'ast, 'src>(
-    inst: &Rc<RefCell<Instance<'ast, 'src>>>,
-    name: &scanner::Token<'src>,
-) -> Option<Object<'ast, 'src>> {
-    inst.borrow().fields.get(name.lexeme).cloned()
+fn instance_get_field(inst: &Rc<RefCell<Instance>>, name: &scanner::Token) -> Option<Object> {
+    inst.borrow().fields.get(&*name.lexeme).cloned()
 }
 
-fn instance_get_method<'ast, 'src>(
-    inst: &Rc<RefCell<Instance<'ast, 'src>>>,
-    name: &scanner::Token<'src>,
-) -> Option<Function<'ast, 'src>> {
-    inst.borrow().class_.borrow().find_method(name.lexeme)
+fn instance_get_method(inst: &Rc<RefCell<Instance>>, name: &scanner::Token) -> Option<Function> {
+    inst.borrow().class_.borrow().find_method(&name.lexeme)
 }
 
 // TODO: possible to refactor types to make this a method?
-pub fn instance_get<'ast, 'src>(
-    inst: Rc<RefCell<Instance<'ast, 'src>>>,
-    name: &scanner::Token<'src>,
-) -> Result<Object<'ast, 'src>, Unwinder<'ast, 'src>> {
+pub fn instance_get(
+    inst: Rc<RefCell<Instance>>,
+    name: &scanner::Token,
+    heap: &mut Heap,
+) -> Result<Object, Unwinder> {
     if let Some(obj) = instance_get_field(&inst, name) {
         Ok(obj)
     } else if let Some(method) = instance_get_method(&inst, name) {
-        Ok(method.bind(inst).into())
+        Ok(method.bind(inst, heap).into())
     } else {
-        Unwinder::err(name, &format!("Undefined property '{}'.", name.lexeme))
+        Unwinder::err(name, &format!("Undefined property '{}'.", &name.lexeme))
+    }
+}
+
+// Binds a list or map method.
+fn native_get(receiver: Receiver<Object>, name: &scanner::Token) -> Result<Object, Unwinder> {
+    match BoundNative::bind(receiver, &name.lexeme) {
+        Some(method) => Ok(method.into()),
+        None => Unwinder::err(name, &format!("Undefined property '{}'.", &name.lexeme)),
     }
 }
 
-impl<'ast, 'src> Instance<'ast, 'src> {
-    pub fn set(&mut self, name: &scanner::Token<'src>, value: Object<'ast, 'src>) {
+pub fn list_get(list: Rc<RefCell<Vec<Object>>>, name: &scanner::Token) -> Result<Object, Unwinder> {
+    native_get(Receiver::List(list), name)
+}
+
+pub fn map_get(map: Rc<RefCell<Map<Object>>>, name: &scanner::Token) -> Result<Object, Unwinder> {
+    native_get(Receiver::Map(map), name)
+}
+
+// An imported module's namespace.
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub globals: Rc<RefCell<Environment>>,
+}
+
+pub fn module_get(module: &Module, name: &scanner::Token) -> Result<Object, Unwinder> {
+    module
+        .globals
+        .borrow()
+        .get(name)
+        .or_else(|_| Unwinder::err(name, &format!("Undefined property '{}'.", &name.lexeme)))
+}
+
+impl Instance {
+    pub fn set(&mut self, name: &scanner::Token, value: Object) {
         self.fields.insert(name.lexeme.to_string(), value);
     }
 }
 
-impl fmt::Display for Instance<'_, '_> {
+impl fmt::Display for Instance {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<instance of {}>", &self.class_.borrow().name.lexeme)
     }
 }
 
-impl<'ast, 'src: 'ast> fmt::Display for Object<'ast, 'src> {
+impl fmt::Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Object::Literal(v) => write!(f, "{v}"),
             Object::BuiltinFunction(v) => v.fmt(f),
+            Object::BoundNative(v) => v.fmt(f),
             Object::Function(v) => v.fmt(f),
             Object::Class(c) => c.borrow().fmt(f),
             Object::Instance(i) => i.borrow().fmt(f),
+            Object::List(l) => list::fmt(l, f),
+            Object::Map(m) => map::fmt(m, f),
+            Object::Error(e) => write!(f, "<error: {}>", e.message),
+            Object::Module(m) => write!(f, "<module {}>", m.name),
         }
     }
 }
 
-impl<'ast, 'src: 'ast> Object<'ast, 'src> {
+impl Object {
+    #[must_use]
     pub fn is_truthy(&self) -> bool {
         match self {
             Object::Literal(v) => v.is_truthy(),
             _ => true,
         }
     }
+
+    /// `self[index]`, for lists and maps.
+    ///
+    /// # Errors
+    ///
+    /// The message for a Lox runtime error, e.g. if the index is out of
+    /// range, or `self` can't be indexed.
+    pub fn get_index(&self, index: &Self) -> Result<Self, String> {
+        match self {
+            Object::List(l) => list::get(l, index),
+            Object::Map(m) => m.borrow().get(index),
+            _ => Err(format!("Only lists and maps can be indexed, got '{self}'.")),
+        }
+    }
+
+    /// `self[index] = value`, for lists and maps.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Object::get_index`].
+    pub fn set_index(&self, index: &Self, value: Self) -> Result<(), String> {
+        match self {
+            Object::List(l) => list::set(l, index, value),
+            Object::Map(m) => m.borrow_mut().set(index, value),
+            _ => Err(format!("Only lists and maps can be indexed, got '{self}'.")),
+        }
+    }
+}
+
+// Conversions from Rust values, for hosts.
+impl From<f64> for Object {
+    fn from(value: f64) -> Self {
+        Literal::Number(value).into()
+    }
+}
+
+impl From<bool> for Object {
+    fn from(value: bool) -> Self {
+        Literal::Bool(value).into()
+    }
+}
+
+impl From<String> for Object {
+    fn from(value: String) -> Self {
+        Literal::String(value).into()
+    }
+}
+
+impl From<&str> for Object {
+    fn from(value: &str) -> Self {
+        Literal::String(value.to_string()).into()
+    }
+}
+
+// None is nil.
+impl<T: Into<Object>> From<Option<T>> for Object {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Literal::Nil.into(), Into::into)
+    }
+}
+
+// And back again. The errors are fit to report as Lox errors.
+impl TryFrom<Object> for f64 {
+    type Error = String;
+
+    fn try_from(value: Object) -> Result<Self, String> {
+        native::convert(value)
+    }
+}
+
+impl TryFrom<Object> for bool {
+    type Error = String;
+
+    fn try_from(value: Object) -> Result<Self, String> {
+        native::convert(value)
+    }
+}
+
+impl TryFrom<Object> for String {
+    type Error = String;
+
+    fn try_from(value: Object) -> Result<Self, String> {
+        native::convert(value)
+    }
+}
+
+impl Element for Object {
+    fn literal(&self) -> Option<&Literal> {
+        match self {
+            Object::Literal(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        match self {
+            // Modules live as long as the interpreter, so we needn't trace
+            // them.
+            Object::Literal(_)
+            | Object::BuiltinFunction(_)
+            | Object::Error(_)
+            | Object::Module(_) => {}
+            Object::BoundNative(b) => b.trace(visit),
+            Object::Function(f) => visit(gc::address(&f.closure)),
+            Object::Class(c) => visit(gc::address(c)),
+            Object::Instance(i) => visit(gc::address(i)),
+            Object::List(l) => visit(gc::address(l)),
+            Object::Map(m) => visit(gc::address(m)),
+        }
+    }
+
+    fn native(name: &str, arity: usize, function: native::NativeFn<Self>) -> Self {
+        BuiltinFunction {
+            arity,
+            function,
+            name: name.to_string(),
+        }
+        .into()
+    }
+
+    fn module(name: &str, members: HashMap<String, Self>) -> Self {
+        Rc::new(Module {
+            name: name.to_string(),
+            globals: Rc::new(RefCell::new(Environment::Global(members))),
+        })
+        .into()
+    }
 }
 
-impl<'ast, 'src: 'ast> PartialEq for Object<'ast, 'src> {
+impl Trace for RefCell<Class> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(class) = self.try_borrow() else {
+            return false;
+        };
+        if let Some(sup) = &class.superclass {
+            visit(gc::address(sup));
+        }
+        for method in class.methods.values() {
+            visit(gc::address(&method.closure));
+        }
+        true
+    }
+
+    fn clear(&self) {
+        let mut class = self.borrow_mut();
+        let contents = (class.superclass.take(), std::mem::take(&mut class.methods));
+        drop(class);
+        drop(contents);
+    }
+}
+
+impl Trace for RefCell<Instance> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(inst) = self.try_borrow() else {
+            return false;
+        };
+        visit(gc::address(&inst.class_));
+        inst.fields.values().for_each(|v| v.trace(visit));
+        true
+    }
+
+    fn clear(&self) {
+        let fields = std::mem::take(&mut self.borrow_mut().fields);
+        drop(fields);
+    }
+}
+
+impl PartialEq for Object {
     fn eq(&self, other: &Self) -> bool {
         #[allow(clippy::match_same_arms)]
         match (self, other) {
@@ -202,12 +427,23 @@ impl<'ast, 'src: 'ast> PartialEq for Object<'ast, 'src> {
                 l.arity == r.arity && Rc::ptr_eq(&l.function, &r.function) && l.name == r.name
             }
             (Object::BuiltinFunction(_), _) | (_, Object::BuiltinFunction(_)) => false,
-            (Object::Function(l), Object::Function(r)) => ptr::eq(l.declaration, r.declaration),
+            (Object::BoundNative(l), Object::BoundNative(r)) => l == r,
+            (Object::BoundNative(_), _) | (_, Object::BoundNative(_)) => false,
+            (Object::Function(l), Object::Function(r)) => {
+                Rc::ptr_eq(&l.declaration, &r.declaration)
+            }
             (Object::Function { .. }, _) | (_, Object::Function { .. }) => false,
             (Object::Class(l), Object::Class(r)) => Rc::ptr_eq(l, r),
             (Object::Class(_), _) | (_, Object::Class(_)) => false,
             (Object::Instance(l), Object::Instance(r)) => Rc::ptr_eq(l, r),
-            // (Object::Instance(l), _) | (_, Object::Instance(r)) => false,
+            (Object::Instance(_), _) | (_, Object::Instance(_)) => false,
+            (Object::List(l), Object::List(r)) => Rc::ptr_eq(l, r),
+            (Object::List(_), _) | (_, Object::List(_)) => false,
+            (Object::Map(l), Object::Map(r)) => Rc::ptr_eq(l, r),
+            (Object::Map(_), _) | (_, Object::Map(_)) => false,
+            (Object::Error(l), Object::Error(r)) => Rc::ptr_eq(l, r),
+            (Object::Error(_), _) | (_, Object::Error(_)) => false,
+            (Object::Module(l), Object::Module(r)) => Rc::ptr_eq(l, r),
         }
     }
 }
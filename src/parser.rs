@@ -4,34 +4,65 @@ use crate::ast_printer;
 use crate::error;
 use crate::error::LoxError;
 use crate::object::Literal;
-#[cfg(test)]
 use crate::scanner;
 use crate::scanner::{Token, TokenType};
-#[cfg(test)]
-use itertools::Itertools;
+use std::rc::Rc;
 
-struct Parser<'src> {
-    tokens: Vec<Token<'src>>,
+struct Parser {
+    tokens: Vec<Token>,
     current: usize,
     errors: Vec<LoxError>,
+    // Whether the code was typed at the REPL, where an expression can end
+    // the input without its semicolon, to print its value.
+    repl: bool,
+    // Whether we made an error at the end of the code, i.e. it stopped
+    // partway through something.
+    hit_end: bool,
 }
 
-pub fn parse<'src>(tokens: Vec<Token<'src>>) -> Result<Program<'src>, Vec<LoxError>> {
-    let mut parser = Parser {
-        tokens,
-        current: 0,
-        errors: Vec::new(),
-    };
-    let result = parser.program();
-    if parser.errors.len() > 0 {
-        Err(parser.errors)
-    } else {
-        Ok(result)
+pub fn parse(tokens: Vec<Token>) -> Result<Program, LoxError> {
+    parse_with(tokens, false)
+}
+
+// Parses a line typed at the REPL, where a final expression without a
+// semicolon becomes a print statement, so that typing `1 + 2` shows 3.
+pub fn parse_repl(tokens: Vec<Token>) -> Result<Program, LoxError> {
+    parse_with(tokens, true)
+}
+
+/// Whether a line typed at the REPL stops partway through (e.g. inside a
+/// block, or a string), so that we should read more before running it.
+#[must_use]
+pub fn is_incomplete(source: &str) -> bool {
+    match scanner::scan_tokens(source) {
+        Err(_) => scanner::ends_in_string(source),
+        Ok(tokens) => {
+            let mut parser = Parser::new(tokens, true);
+            parser.program();
+            parser.hit_end
+        }
     }
 }
 
-impl<'src> Parser<'src> {
-    fn program(&mut self) -> Program<'src> {
+fn parse_with(tokens: Vec<Token>, repl: bool) -> Result<Program, LoxError> {
+    let mut parser = Parser::new(tokens, repl);
+    let result = parser.program();
+    error::check_errors(parser.errors)?;
+    Ok(result)
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>, repl: bool) -> Self {
+        Parser {
+            tokens,
+            current: 0,
+            errors: Vec::new(),
+            repl,
+            hit_end: false,
+        }
+    }
+
+    fn program(&mut self) -> Program {
         let mut declarations = Vec::new();
         while !self.is_at_end() {
             let declaration = self.declaration();
@@ -46,28 +77,32 @@ impl<'src> Parser<'src> {
 
         Program {
             stmts: declarations,
+            source: self.tokens[self.tokens.len() - 1].source.clone(),
         }
     }
 
-    fn declaration(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn declaration(&mut self) -> Result<Stmt, LoxError> {
         if self.match_(&[TokenType::Var]) {
             self.var_declaration()
         } else if self.match_(&[TokenType::Class]) {
             self.class_declaration()
-        } else if self.match_(&[TokenType::Fun]) {
-            Ok(self.function("function")?.into())
+        } else if self.check(TokenType::Fun) && !self.check_next(TokenType::LeftParen) {
+            self.advance();
+            Ok(Rc::new(self.function("function")?).into())
+        } else if self.match_(&[TokenType::Import]) {
+            self.import_declaration()
         } else {
             self.statement()
         }
     }
 
-    fn class_declaration(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn class_declaration(&mut self) -> Result<Stmt, LoxError> {
         let name = self.consume(TokenType::Identifier, "Expect class name.")?;
         let superclass = if self.match_(&[TokenType::Less]) {
             self.consume(TokenType::Identifier, "Expect superclass name.")?;
             Some(Box::new(VariableExpr {
                 name: self.previous(),
-                resolved_depth: None,
+                resolved_slot: None,
             }))
         } else {
             None
@@ -76,7 +111,7 @@ impl<'src> Parser<'src> {
         self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
         let mut methods = Vec::new();
         while !self.is_at_end() && !self.check(TokenType::RightBrace) {
-            methods.push(self.function("method")?);
+            methods.push(Rc::new(self.function("method")?));
         }
         self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
         Ok(ClassStmt {
@@ -87,7 +122,26 @@ impl<'src> Parser<'src> {
         .into())
     }
 
-    fn var_declaration(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn import_declaration(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous();
+        let path_token = self.consume(
+            TokenType::StringLiteral,
+            "Expect module path after 'import'.",
+        )?;
+        self.consume(TokenType::As, "Expect 'as' after module path.")?;
+        let name = self.consume(TokenType::Identifier, "Expect module name after 'as'.")?;
+        self.consume(TokenType::Semicolon, "Expect ';' after import.")?;
+        let path = path_token.lexeme[1..path_token.lexeme.len() - 1].to_string();
+        Ok(ImportStmt {
+            keyword,
+            path_token,
+            path,
+            name,
+        }
+        .into())
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt, LoxError> {
         let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
         let initializer = if self.match_(&[TokenType::Equal]) {
             Some(Box::new(self.expression()?))
@@ -101,7 +155,7 @@ impl<'src> Parser<'src> {
         Ok(VarStmt { name, initializer }.into())
     }
 
-    fn statement(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn statement(&mut self) -> Result<Stmt, LoxError> {
         if self.match_(&[TokenType::LeftBrace]) {
             self.block_statement()
         } else if self.match_(&[TokenType::If]) {
@@ -114,12 +168,24 @@ impl<'src> Parser<'src> {
             self.print_statement()
         } else if self.match_(&[TokenType::Return]) {
             self.return_statement()
+        } else if self.match_(&[TokenType::Throw]) {
+            self.throw_statement()
+        } else if self.match_(&[TokenType::Try]) {
+            self.try_statement()
+        } else if self.match_(&[TokenType::Break]) {
+            let keyword = self.previous();
+            self.consume(TokenType::Semicolon, "Expect ';' after 'break'.")?;
+            Ok(BreakStmt { keyword }.into())
+        } else if self.match_(&[TokenType::Continue]) {
+            let keyword = self.previous();
+            self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.")?;
+            Ok(ContinueStmt { keyword }.into())
         } else {
             self.expression_statement()
         }
     }
 
-    fn print_statement(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn print_statement(&mut self) -> Result<Stmt, LoxError> {
         let value = self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
         Ok(PrintStmt {
@@ -128,7 +194,7 @@ impl<'src> Parser<'src> {
         .into())
     }
 
-    fn return_statement(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn return_statement(&mut self) -> Result<Stmt, LoxError> {
         let keyword = self.previous();
         let value = if self.check(TokenType::Semicolon) {
             None
@@ -139,7 +205,49 @@ impl<'src> Parser<'src> {
         Ok(ReturnStmt { keyword, value }.into())
     }
 
-    fn if_statement(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn throw_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous();
+        let value = Box::new(self.expression()?);
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.")?;
+        Ok(ThrowStmt { keyword, value }.into())
+    }
+
+    fn try_statement(&mut self) -> Result<Stmt, LoxError> {
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+        let catch = if self.match_(&[TokenType::Catch]) {
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+            let name = self.consume(TokenType::Identifier, "Expect exception variable name.")?;
+            self.consume(
+                TokenType::RightParen,
+                "Expect ')' after exception variable.",
+            )?;
+            self.consume(TokenType::LeftBrace, "Expect '{' before catch body.")?;
+            Some(CatchClause {
+                name,
+                body: self.block()?,
+            })
+        } else {
+            None
+        };
+        let finally = if self.match_(&[TokenType::Finally]) {
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.")?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+        if catch.is_none() && finally.is_none() {
+            return Err(self.error("Expect 'catch' or 'finally' after try block."));
+        }
+        Ok(TryStmt {
+            body,
+            catch,
+            finally,
+        }
+        .into())
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, LoxError> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
         let condition = Box::new(self.expression()?);
         self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
@@ -159,16 +267,21 @@ impl<'src> Parser<'src> {
         .into())
     }
 
-    fn while_statement(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn while_statement(&mut self) -> Result<Stmt, LoxError> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
         let condition = Box::new(self.expression()?);
         self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
 
         let body = Box::new(self.statement()?);
-        Ok(WhileStmt { condition, body }.into())
+        Ok(WhileStmt {
+            condition,
+            body,
+            increment: None,
+        }
+        .into())
     }
 
-    fn for_statement(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn for_statement(&mut self) -> Result<Stmt, LoxError> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
 
         let initializer = if self.match_(&[TokenType::Semicolon]) {
@@ -182,6 +295,7 @@ impl<'src> Parser<'src> {
         let condition = if self.check(TokenType::Semicolon) {
             LiteralExpr {
                 value: Literal::Bool(true),
+                span: self.peek().span,
             }
             .into()
         } else {
@@ -198,23 +312,10 @@ impl<'src> Parser<'src> {
 
         let body = self.statement()?;
 
-        let body_with_increment = match increment {
-            Some(expr) => BlockStmt {
-                stmts: vec![
-                    body,
-                    ExprStmt {
-                        expr: Box::new(expr),
-                    }
-                    .into(),
-                ],
-            }
-            .into(),
-            None => body,
-        };
-
         let body_with_condition = WhileStmt {
             condition: Box::new(condition),
-            body: Box::new(body_with_increment),
+            body: Box::new(body),
+            increment: increment.map(Box::new),
         }
         .into();
 
@@ -229,7 +330,7 @@ impl<'src> Parser<'src> {
         Ok(body_with_initializer)
     }
 
-    fn block(&mut self) -> Result<Vec<Stmt<'src>>, LoxError> {
+    fn block(&mut self) -> Result<Vec<Stmt>, LoxError> {
         let mut stmts = Vec::new();
         while !self.is_at_end() && !self.check(TokenType::RightBrace) {
             stmts.push(self.declaration()?);
@@ -238,15 +339,21 @@ impl<'src> Parser<'src> {
         Ok(stmts)
     }
 
-    fn block_statement(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn block_statement(&mut self) -> Result<Stmt, LoxError> {
         Ok(BlockStmt {
             stmts: self.block()?,
         }
         .into())
     }
 
-    fn expression_statement(&mut self) -> Result<Stmt<'src>, LoxError> {
+    fn expression_statement(&mut self) -> Result<Stmt, LoxError> {
         let value = self.expression()?;
+        if self.repl && self.is_at_end() {
+            return Ok(PrintStmt {
+                expr: Box::new(value),
+            }
+            .into());
+        }
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
         Ok(ExprStmt {
             expr: Box::new(value),
@@ -254,21 +361,24 @@ impl<'src> Parser<'src> {
         .into())
     }
 
-    fn function(&mut self, kind: &str) -> Result<FunctionStmt<'src>, LoxError> {
+    fn function(&mut self, kind: &str) -> Result<FunctionStmt, LoxError> {
         let name = self.consume(TokenType::Identifier, &format!("Expect {kind} name."))?;
         self.consume(
             TokenType::LeftParen,
             &format!("Expect '(' after {kind} name."),
         )?;
+        self.function_rest(name, kind)
+    }
+
+    // Parses a function's parameters and body, after the '('.
+    fn function_rest(&mut self, name: Token, kind: &str) -> Result<FunctionStmt, LoxError> {
         let mut parameters = Vec::new();
         // TODO: abstract into some kind of parse-list-while loop?
         if !self.check(TokenType::RightParen) {
             loop {
                 if parameters.len() >= 255 {
-                    self.errors.push(error::parse_error(
-                        &self.peek(),
-                        "Can't have more than 255 parameters.",
-                    ));
+                    let error = self.error("Can't have more than 255 parameters.");
+                    self.errors.push(error);
                 }
 
                 parameters.push(self.consume(TokenType::Identifier, "Expect parameter name.")?);
@@ -291,15 +401,15 @@ impl<'src> Parser<'src> {
         })
     }
 
-    fn expression(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn expression(&mut self) -> Result<Expr, LoxError> {
         self.assignment()
     }
 
     fn binary_expression(
         &mut self,
         tokens: &[TokenType],
-        next: &mut dyn FnMut(&mut Self) -> Result<Expr<'src>, LoxError>,
-    ) -> Result<Expr<'src>, LoxError> {
+        next: &mut dyn FnMut(&mut Self) -> Result<Expr, LoxError>,
+    ) -> Result<Expr, LoxError> {
         let mut expr = next(self)?;
         while self.match_(tokens) {
             let operator = self.previous();
@@ -315,7 +425,7 @@ impl<'src> Parser<'src> {
         Ok(expr)
     }
 
-    fn assignment(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn assignment(&mut self) -> Result<Expr, LoxError> {
         let expr = self.or()?;
         if self.match_(&[TokenType::Equal]) {
             let equals = self.previous();
@@ -324,7 +434,7 @@ impl<'src> Parser<'src> {
                 Expr::Variable(var) => Ok(AssignExpr {
                     name: var.name,
                     value: Box::new(value),
-                    resolved_depth: None,
+                    resolved_slot: None,
                 }
                 .into()),
                 Expr::Get(get) => Ok(SetExpr {
@@ -333,6 +443,13 @@ impl<'src> Parser<'src> {
                     value: Box::new(value),
                 }
                 .into()),
+                Expr::Index(index) => Ok(SetIndexExpr {
+                    object: index.object,
+                    bracket: index.bracket,
+                    index: index.index,
+                    value: Box::new(value),
+                }
+                .into()),
                 _ => Err(error::parse_error(&equals, "Invalid assignment target.")),
             }
         } else {
@@ -340,7 +457,7 @@ impl<'src> Parser<'src> {
         }
     }
 
-    fn or(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn or(&mut self) -> Result<Expr, LoxError> {
         let mut expr = self.and()?;
         while self.match_(&[TokenType::Or]) {
             let operator = self.previous();
@@ -355,7 +472,7 @@ impl<'src> Parser<'src> {
         Ok(expr)
     }
 
-    fn and(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn and(&mut self) -> Result<Expr, LoxError> {
         let mut expr = self.equality()?;
         while self.match_(&[TokenType::And]) {
             let operator = self.previous();
@@ -370,14 +487,14 @@ impl<'src> Parser<'src> {
         Ok(expr)
     }
 
-    fn equality(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn equality(&mut self) -> Result<Expr, LoxError> {
         self.binary_expression(
             &[TokenType::BangEqual, TokenType::EqualEqual],
             &mut Parser::comparison,
         )
     }
 
-    fn comparison(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn comparison(&mut self) -> Result<Expr, LoxError> {
         self.binary_expression(
             &[
                 TokenType::Greater,
@@ -389,19 +506,19 @@ impl<'src> Parser<'src> {
         )
     }
 
-    fn term(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn term(&mut self) -> Result<Expr, LoxError> {
         self.binary_expression(&[TokenType::Minus, TokenType::Plus], &mut |self_| {
             self_.factor()
         })
     }
 
-    fn factor(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn factor(&mut self) -> Result<Expr, LoxError> {
         self.binary_expression(&[TokenType::Slash, TokenType::Star], &mut |self_| {
             self_.unary()
         })
     }
 
-    fn unary(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn unary(&mut self) -> Result<Expr, LoxError> {
         if self.match_(&[TokenType::Bang, TokenType::Minus]) {
             let operator = self.previous();
             let right = self.unary()?;
@@ -415,7 +532,7 @@ impl<'src> Parser<'src> {
         return self.call();
     }
 
-    fn call(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn call(&mut self) -> Result<Expr, LoxError> {
         let mut expr = self.primary()?;
         loop {
             if self.match_(&[TokenType::LeftParen]) {
@@ -428,6 +545,17 @@ impl<'src> Parser<'src> {
                     name,
                 }
                 .into();
+            } else if self.match_(&[TokenType::LeftBracket]) {
+                let bracket = self.previous();
+                let index = self.expression()?;
+                let end = self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+                expr = IndexExpr {
+                    span: expr.span().to(end.span),
+                    object: Box::new(expr),
+                    bracket,
+                    index: Box::new(index),
+                }
+                .into();
             } else {
                 break;
             }
@@ -436,15 +564,13 @@ impl<'src> Parser<'src> {
         Ok(expr)
     }
 
-    fn finish_call(&mut self, callee: Expr<'src>) -> Result<Expr<'src>, LoxError> {
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, LoxError> {
         let mut arguments = Vec::new();
         if !self.check(TokenType::RightParen) {
             loop {
                 if arguments.len() >= 255 {
-                    self.errors.push(error::parse_error(
-                        &self.peek(),
-                        "Can't have more than 255 arguments.",
-                    ));
+                    let error = self.error("Can't have more than 255 arguments.");
+                    self.errors.push(error);
                 }
                 arguments.push(self.expression()?);
                 if !self.match_(&[TokenType::Comma]) {
@@ -463,25 +589,29 @@ impl<'src> Parser<'src> {
         .into())
     }
 
-    fn primary(&mut self) -> Result<Expr<'src>, LoxError> {
+    fn primary(&mut self) -> Result<Expr, LoxError> {
         if self.match_(&[TokenType::False]) {
             Ok(LiteralExpr {
                 value: Literal::Bool(false),
+                span: self.previous().span,
             }
             .into())
         } else if self.match_(&[TokenType::True]) {
             Ok(LiteralExpr {
                 value: Literal::Bool(true),
+                span: self.previous().span,
             }
             .into())
         } else if self.match_(&[TokenType::Nil]) {
             Ok(LiteralExpr {
                 value: Literal::Nil,
+                span: self.previous().span,
             }
             .into())
         } else if self.match_(&[TokenType::Number]) {
             Ok(LiteralExpr {
                 value: Literal::Number(self.previous().lexeme.parse().unwrap()),
+                span: self.previous().span,
             }
             .into())
         } else if self.match_(&[TokenType::StringLiteral]) {
@@ -489,6 +619,7 @@ impl<'src> Parser<'src> {
             let val = &lexeme[1..lexeme.len() - 1];
             Ok(LiteralExpr {
                 value: Literal::String(val.to_string()),
+                span: self.previous().span,
             }
             .into())
         } else if self.match_(&[TokenType::Super]) {
@@ -498,30 +629,83 @@ impl<'src> Parser<'src> {
             Ok(SuperExpr {
                 keyword,
                 method,
-                resolved_depth: None,
+                resolved_slot: None,
+            }
+            .into())
+        } else if self.match_(&[TokenType::Fun]) {
+            let keyword = self.previous();
+            self.consume(TokenType::LeftParen, "Expect '(' after 'fun'.")?;
+            let name = Token {
+                lexeme: Rc::from("lambda"),
+                ..keyword
+            };
+            let declaration = self.function_rest(name, "function")?;
+            Ok(FunctionExpr {
+                declaration: Rc::new(declaration),
+                span: keyword.span.to(self.previous().span),
             }
             .into())
         } else if self.match_(&[TokenType::This]) {
             Ok(ThisExpr {
                 keyword: self.previous(),
-                resolved_depth: None,
+                resolved_slot: None,
             }
             .into())
         } else if self.match_(&[TokenType::Identifier]) {
             Ok(VariableExpr {
                 name: self.previous(),
-                resolved_depth: None,
+                resolved_slot: None,
+            }
+            .into())
+        } else if self.match_(&[TokenType::LeftBracket]) {
+            let bracket = self.previous();
+            let mut elements = Vec::new();
+            if !self.check(TokenType::RightBracket) {
+                loop {
+                    elements.push(self.expression()?);
+                    if !self.match_(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            let end = self.consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+            Ok(ListExpr {
+                span: bracket.span.to(end.span),
+                bracket,
+                elements,
+            }
+            .into())
+        } else if self.match_(&[TokenType::LeftBrace]) {
+            let brace = self.previous();
+            let mut entries = Vec::new();
+            if !self.check(TokenType::RightBrace) {
+                loop {
+                    let key = self.expression()?;
+                    self.consume(TokenType::Colon, "Expect ':' after map key.")?;
+                    entries.push((key, self.expression()?));
+                    if !self.match_(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            let end = self.consume(TokenType::RightBrace, "Expect '}' after map entries.")?;
+            Ok(MapExpr {
+                span: brace.span.to(end.span),
+                brace,
+                entries,
             }
             .into())
         } else if self.match_(&[TokenType::LeftParen]) {
+            let paren = self.previous();
             let expr = self.expression()?;
-            self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+            let end = self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
             Ok(GroupingExpr {
                 expr: Box::new(expr),
+                span: paren.span.to(end.span),
             }
             .into())
         } else {
-            Err(error::parse_error(&self.peek(), "Expect expression."))
+            Err(self.error("Expect expression."))
         }
     }
 
@@ -536,14 +720,21 @@ impl<'src> Parser<'src> {
         })
     }
 
-    fn consume(&mut self, type_: TokenType, message: &str) -> Result<Token<'src>, LoxError> {
+    fn consume(&mut self, type_: TokenType, message: &str) -> Result<Token, LoxError> {
         if self.check(type_) {
             Ok(self.advance())
         } else {
-            Err(error::parse_error(&self.peek(), message))
+            Err(self.error(message))
         }
     }
 
+    // An error at the next token.
+    fn error(&mut self, message: &str) -> LoxError {
+        let token = self.peek();
+        self.hit_end |= token.type_ == TokenType::EOF;
+        error::parse_error(&token, message)
+    }
+
     fn synchronize(&mut self) {
         self.advance();
 
@@ -555,11 +746,16 @@ impl<'src> Parser<'src> {
             match self.peek().type_ {
                 TokenType::Class
                 | TokenType::Fun
+                | TokenType::Import
                 | TokenType::Var
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Throw
+                | TokenType::Try
                 | TokenType::Return => return,
                 _ => {}
             }
@@ -572,7 +768,13 @@ impl<'src> Parser<'src> {
         !self.is_at_end() && self.peek().type_ == type_
     }
 
-    fn advance(&mut self) -> Token<'src> {
+    fn check_next(&self, type_: TokenType) -> bool {
+        self.tokens
+            .get(self.current + 1)
+            .is_some_and(|token| token.type_ == type_)
+    }
+
+    fn advance(&mut self) -> Token {
         if !self.is_at_end() {
             self.current += 1;
         }
@@ -583,17 +785,17 @@ impl<'src> Parser<'src> {
         self.peek().type_ == TokenType::EOF
     }
 
-    fn peek(&self) -> Token<'src> {
+    fn peek(&self) -> Token {
         self.tokens[self.current].clone()
     }
 
-    fn previous(&self) -> Token<'src> {
+    fn previous(&self) -> Token {
         self.tokens[self.current - 1].clone()
     }
 }
 
 #[cfg(test)]
-pub fn must_parse<'src>(input: &'src str) -> Program<'src> {
+pub fn must_parse(input: &str) -> Program {
     parse(scanner::scan_tokens(input).unwrap()).unwrap()
 }
 
@@ -604,18 +806,8 @@ fn assert_parses_to(input: &str, printed: &str) {
 
 #[cfg(test)]
 fn assert_parse_error(input: &str, messages: &[&str]) {
-    let errs = parse(scanner::scan_tokens(input).unwrap()).unwrap_err();
-    assert_eq!(
-        errs.len(),
-        messages.len(),
-        "expected {} errors, got {}: {}",
-        messages.len(),
-        errs.len(),
-        errs.into_iter().map(|e| e.to_string()).join("\n"),
-    );
-    for (a, e) in errs.into_iter().zip(messages) {
-        assert_eq!(&a.to_string(), e)
-    }
+    let err = parse(scanner::scan_tokens(input).unwrap()).unwrap_err();
+    assert_eq!(err.to_string(), messages.join("\n"));
 }
 
 #[test]
@@ -734,7 +926,10 @@ fn test_parser_while() {
 fn test_parser_for() {
     assert_parses_to("for (;;) 1;", "(while (true) (expr (1)))");
     assert_parses_to("for (;false;) 1;", "(while (false) (expr (1)))");
-    assert_parses_to("for (;i < 2;i = i + 1) 1;", "(while (< (variable i) (2)) (block\n\t(expr (1))\n\t(expr (assign i (+ (variable i) (1))))\n))");
+    assert_parses_to(
+        "for (;i < 2;i = i + 1) 1;",
+        "(while (< (variable i) (2)) (expr (1)) (assign i (+ (variable i) (1))))",
+    );
     assert_parses_to(
         "for (var i = 0;false;) 1;",
         "(block\n\t(var i (0))\n\t(while (false) (expr (1)))\n)",
@@ -783,7 +978,15 @@ fn test_parser_function() {
         "(fun f a (\n\t(print (variable a))\n))",
     );
 
-    assert_parse_error("fun();", &["[line 1] Error at '(': Expect function name."]);
+    assert_parse_error(
+        "fun 1() {}",
+        &["[line 1] Error at '1': Expect function name."],
+    );
+    // Without a name, it's a lambda.
+    assert_parse_error(
+        "fun();",
+        &["[line 1] Error at ';': Expect '{' before function body."],
+    );
     assert_parse_error(
         "fun f;",
         &["[line 1] Error at ';': Expect '(' after function name."],
@@ -885,3 +1088,163 @@ fn test_parser_superclass() {
         ],
     );
 }
+
+#[test]
+fn test_parser_lists() {
+    assert_parses_to("[];", "(expr (list))");
+    assert_parses_to("[1, a, [2]];", "(expr (list (1) (variable a) (list (2))))");
+    assert_parses_to(
+        "a[1][b];",
+        "(expr (index (index (variable a) (1)) (variable b)))",
+    );
+    assert_parses_to("a[1] = 2;", "(expr (set-index (variable a) (1) (2)))");
+    assert_parses_to(
+        "a.b[1].c;",
+        "(expr (get (index (get (variable a) b) (1)) c))",
+    );
+
+    assert_parse_error(
+        "[1, 2;",
+        &["[line 1] Error at ';': Expect ']' after list elements."],
+    );
+    assert_parse_error("a[1;", &["[line 1] Error at ';': Expect ']' after index."]);
+    assert_parse_error("[1,];", &["[line 1] Error at ']': Expect expression."]);
+}
+
+#[test]
+fn test_parser_maps() {
+    assert_parses_to("print {};", "(print (map))");
+    assert_parses_to(
+        r#"print {"a": 1, b: {}};"#,
+        "(print (map (a) (1) (variable b) (map)))",
+    );
+    assert_parses_to(r#"m["a"] = 1;"#, "(expr (set-index (variable m) (a) (1)))");
+    // At the start of a statement, a brace is a block.
+    assert_parses_to(
+        "{}", "(block
+)",
+    );
+
+    assert_parse_error(
+        r#"print {"a" 1};"#,
+        &["[line 1] Error at '1': Expect ':' after map key."],
+    );
+    assert_parse_error(
+        r#"print {"a": 1;"#,
+        &["[line 1] Error at ';': Expect '}' after map entries."],
+    );
+}
+
+#[test]
+fn test_parser_break_continue() {
+    assert_parses_to(
+        "while (true) { break; continue; }",
+        "(while (true) (block\n\t(break)\n\t(continue)\n))",
+    );
+    assert_parse_error(
+        "break",
+        &["[line 1] Error at end: Expect ';' after 'break'."],
+    );
+    assert_parse_error(
+        "continue 1;",
+        &["[line 1] Error at '1': Expect ';' after 'continue'."],
+    );
+}
+
+#[test]
+fn test_parser_try() {
+    assert_parses_to(
+        "try { throw 1; } catch (e) { print e; } finally { 2; }",
+        "(try\n\t(throw (1))\n) (catch e\n\t(print (variable e))\n) (finally\n\t(expr (2))\n)",
+    );
+    assert_parses_to("try {} finally {}", "(try\n) (finally\n)");
+    assert_parse_error(
+        "try {} print 1;",
+        &["[line 1] Error at 'print': Expect 'catch' or 'finally' after try block."],
+    );
+    assert_parse_error(
+        "try {} catch e {}",
+        &["[line 1] Error at 'e': Expect '(' after 'catch'."],
+    );
+    assert_parse_error("throw;", &["[line 1] Error at ';': Expect expression."]);
+}
+
+#[test]
+fn test_parser_import() {
+    assert_parses_to(r#"import "lib.lox" as lib;"#, "(import lib.lox lib)");
+    assert_parse_error(
+        "import lib;",
+        &["[line 1] Error at 'lib': Expect module path after 'import'."],
+    );
+    assert_parse_error(
+        r#"import "lib.lox";"#,
+        &["[line 1] Error at ';': Expect 'as' after module path."],
+    );
+}
+
+#[test]
+fn test_parser_lambda() {
+    assert_parses_to(
+        "var f = fun (a, b) { return a; };",
+        "(var f (fun lambda a b (\n\t(return (variable a))\n)))",
+    );
+    assert_parses_to("fun () {}();", "(expr (call (fun lambda (\n))))");
+    assert_parse_error(
+        "var f = fun a;",
+        &["[line 1] Error at 'a': Expect '(' after 'fun'."],
+    );
+}
+
+#[test]
+fn test_parser_repl() {
+    let parse_line =
+        |input| ast_printer::print(&parse_repl(scanner::scan_tokens(input).unwrap()).unwrap());
+    assert_eq!(parse_line("1 + 2"), "(print (+ (1) (2)))");
+    assert_eq!(parse_line("1 + 2\n"), "(print (+ (1) (2)))");
+    assert_eq!(parse_line("1 + 2;"), "(expr (+ (1) (2)))");
+    assert_eq!(
+        parse_line("var a = 1; a"),
+        "(var a (1))\n(print (variable a))"
+    );
+    assert_eq!(parse_line("print 1;"), "(print (1))");
+    // Only at the very end.
+    let err = parse_repl(scanner::scan_tokens("1 2;").unwrap()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "[line 1] Error at '2': Expect ';' after value."
+    );
+    let err = parse_repl(scanner::scan_tokens("{ 1 }").unwrap()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "[line 1] Error at '}': Expect ';' after value."
+    );
+}
+
+#[test]
+fn test_is_incomplete() {
+    for source in [
+        "fun f() {\n",
+        "class A {\n  f() {}\n",
+        "print (1 +\n",
+        "var s = \"a\n",
+        "1 +",
+        "if (a)",
+        "print @ \"a\n",
+        "{ print 1 }\nfun f() {",
+    ] {
+        assert!(is_incomplete(source), "{source:?}");
+    }
+    for source in [
+        "",
+        "1 + 2",
+        "fun f() {}\n",
+        "print 1;",
+        "var s = \"a\nb\";",
+        "1 2",
+        "}",
+        "@",
+        "print 1 }",
+    ] {
+        assert!(!is_incomplete(source), "{source:?}");
+    }
+}
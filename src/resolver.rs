@@ -1,7 +1,9 @@
 use crate::ast::*;
-use crate::error::{parse_error, LoxError};
-use crate::scanner::Token;
+use crate::error::{self, resolve_error, LoxError};
+use crate::parser;
+use crate::scanner::{self, Token};
 use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum FunctionType {
@@ -18,44 +20,62 @@ enum ClassType {
     Subclass,
 }
 
-struct Resolver<'src> {
-    scopes: Vec<HashMap<&'src str, bool>>,
+#[derive(Debug, Clone, Copy)]
+struct Local {
+    defined: bool,
+    // Position in the scope, matching the order the interpreter will
+    // define it at runtime.
+    index: usize,
+}
+
+struct Resolver {
+    scopes: Vec<HashMap<Rc<str>, Local>>,
     errors: Vec<LoxError>,
     current_function: FunctionType,
     current_class: ClassType,
+    in_loop: bool,
 }
 
-pub fn resolve<'src>(prog: &mut Program<'src>) -> Result<(), Vec<LoxError>> {
+pub fn resolve(prog: &mut Program) -> Result<(), LoxError> {
     let mut resolver = Resolver::new();
     resolver.resolve_program(prog);
-    if resolver.errors.len() == 0 {
-        Ok(())
-    } else {
-        Err(resolver.errors)
-    }
+    error::check_errors(resolver.errors)
+}
+
+/// Finds the errors in some code that we can without running it: those in
+/// scanning, parsing and resolving.
+///
+/// # Errors
+///
+/// The first error found, with any others in its `more`.
+pub fn check(source: &str) -> Result<(), LoxError> {
+    let tokens = scanner::scan_tokens(source)?;
+    let mut prog = parser::parse(tokens)?;
+    resolve(&mut prog)
 }
 
-impl<'src> Resolver<'src> {
+impl Resolver {
     fn new() -> Self {
         Resolver {
             scopes: Vec::new(),
             errors: Vec::new(),
             current_function: FunctionType::None,
             current_class: ClassType::None,
+            in_loop: false,
         }
     }
 
-    fn resolve_program(&mut self, node: &mut Program<'src>) {
+    fn resolve_program(&mut self, node: &mut Program) {
         self.resolve_stmts(&mut node.stmts);
     }
 
-    fn resolve_stmts(&mut self, stmts: &mut [Stmt<'src>]) {
+    fn resolve_stmts(&mut self, stmts: &mut [Stmt]) {
         for stmt in stmts {
             self.resolve_stmt(stmt);
         }
     }
 
-    fn resolve_stmt(&mut self, stmt: &mut Stmt<'src>) {
+    fn resolve_stmt(&mut self, stmt: &mut Stmt) {
         match stmt {
             // Interesting statements
             Stmt::Block(node) => {
@@ -73,8 +93,10 @@ impl<'src> Resolver<'src> {
                 if let Some(ref mut sup) = &mut node.superclass {
                     self.current_class = ClassType::Subclass;
                     if sup.name.lexeme == node.name.lexeme {
-                        self.errors
-                            .push(parse_error(&sup.name, "A class can't inherit from itself."));
+                        self.errors.push(resolve_error(
+                            &sup.name,
+                            "A class can't inherit from itself.",
+                        ));
                     } else {
                         self.resolve_variable(sup);
                     }
@@ -82,22 +104,18 @@ impl<'src> Resolver<'src> {
 
                 if node.superclass.is_some() {
                     self.begin_scope();
-                    if let Some(scope) = self.scopes.last_mut() {
-                        scope.insert("super", true);
-                    }
+                    self.define_implicit("super");
                 }
 
                 self.begin_scope();
-                // TODO: refactor define and use?
-                if let Some(scope) = self.scopes.last_mut() {
-                    scope.insert("this", true);
-                }
+                self.define_implicit("this");
 
                 for method in &mut node.methods {
+                    let method = unshared(method);
                     self.resolve_function(
                         &method.parameters,
                         &mut method.body,
-                        if method.name.lexeme == "init" {
+                        if &*method.name.lexeme == "init" {
                             FunctionType::Initializer
                         } else {
                             FunctionType::Method
@@ -114,21 +132,32 @@ impl<'src> Resolver<'src> {
                 self.current_class = enclosing_class;
             }
             Stmt::Function(node) => {
+                let node = unshared(node);
                 self.declare(&node.name);
                 self.define(&node.name);
 
                 self.resolve_function(&node.parameters, &mut node.body, FunctionType::Function);
             }
+            Stmt::Import(node) => {
+                if !self.scopes.is_empty() {
+                    self.errors.push(resolve_error(
+                        &node.keyword,
+                        "Can only import from top-level code.",
+                    ));
+                }
+                self.declare(&node.name);
+                self.define(&node.name);
+            }
             Stmt::Return(node) => {
                 if self.current_function == FunctionType::None {
-                    self.errors.push(parse_error(
+                    self.errors.push(resolve_error(
                         &node.keyword,
                         "Can't return from top-level code.",
                     ));
                 }
                 if let Some(ref mut value) = &mut node.value {
                     if self.current_function == FunctionType::Initializer {
-                        self.errors.push(parse_error(
+                        self.errors.push(resolve_error(
                             &node.keyword,
                             "Can't return a value from an initializer.",
                         ));
@@ -136,6 +165,49 @@ impl<'src> Resolver<'src> {
                     self.resolve_expr(value);
                 }
             }
+            Stmt::Break(node) => {
+                if !self.in_loop {
+                    self.errors.push(resolve_error(
+                        &node.keyword,
+                        "Can't use 'break' outside of a loop.",
+                    ));
+                }
+            }
+            Stmt::Continue(node) => {
+                if !self.in_loop {
+                    self.errors.push(resolve_error(
+                        &node.keyword,
+                        "Can't use 'continue' outside of a loop.",
+                    ));
+                }
+            }
+            Stmt::Try(node) => {
+                self.begin_scope();
+                self.resolve_stmts(&mut node.body);
+                self.end_scope();
+                if let Some(ref mut catch) = &mut node.catch {
+                    self.begin_scope();
+                    self.declare(&catch.name);
+                    self.define(&catch.name);
+                    self.resolve_stmts(&mut catch.body);
+                    self.end_scope();
+                }
+                if let Some(ref mut finally) = &mut node.finally {
+                    self.begin_scope();
+                    self.resolve_stmts(finally);
+                    self.end_scope();
+                }
+            }
+            Stmt::While(node) => {
+                self.resolve_expr(&mut node.condition);
+                let enclosing_loop = self.in_loop;
+                self.in_loop = true;
+                self.resolve_stmt(&mut node.body);
+                self.in_loop = enclosing_loop;
+                if let Some(ref mut increment) = &mut node.increment {
+                    self.resolve_expr(increment);
+                }
+            }
             Stmt::Var(node) => {
                 self.declare(&node.name);
                 if let Some(ref mut init) = &mut node.initializer {
@@ -158,21 +230,22 @@ impl<'src> Resolver<'src> {
             Stmt::Print(node) => {
                 self.resolve_expr(&mut node.expr);
             }
-            Stmt::While(node) => {
-                self.resolve_expr(&mut node.condition);
-                self.resolve_stmt(&mut node.body);
+            Stmt::Throw(node) => {
+                self.resolve_expr(&mut node.value);
             }
         }
     }
 
     fn resolve_function(
         &mut self,
-        parameters: &Vec<Token<'src>>,
-        body: &mut [Stmt<'src>],
+        parameters: &Vec<Token>,
+        body: &mut [Stmt],
         type_: FunctionType,
     ) {
         let enclosing_function = self.current_function;
+        let enclosing_loop = self.in_loop;
         self.current_function = type_;
+        self.in_loop = false;
         self.begin_scope();
 
         for parameter in parameters {
@@ -183,53 +256,65 @@ impl<'src> Resolver<'src> {
 
         self.end_scope();
         self.current_function = enclosing_function;
+        self.in_loop = enclosing_loop;
     }
 
-    fn resolve_variable(&mut self, node: &mut VariableExpr<'src>) {
+    fn resolve_variable(&mut self, node: &mut VariableExpr) {
         if let Some(scope) = self.scopes.last() {
-            if scope.get(node.name.lexeme) == Some(&false) {
-                self.errors.push(parse_error(
+            if scope
+                .get(&*node.name.lexeme)
+                .is_some_and(|local| !local.defined)
+            {
+                self.errors.push(resolve_error(
                     &node.name,
                     "Can't read local variable in its own initializer.",
                 ));
             }
         }
 
-        self.resolve_local(&mut node.resolved_depth, &node.name);
+        self.resolve_local(&mut node.resolved_slot, &node.name);
     }
 
-    fn resolve_expr(&mut self, expr: &mut Expr<'src>) {
+    fn resolve_expr(&mut self, expr: &mut Expr) {
         match expr {
             // Interesting expressions
             Expr::Variable(node) => {
                 self.resolve_variable(node);
             }
+            Expr::Function(node) => {
+                let declaration = unshared(&mut node.declaration);
+                self.resolve_function(
+                    &declaration.parameters,
+                    &mut declaration.body,
+                    FunctionType::Function,
+                );
+            }
             Expr::Assign(node) => {
                 self.resolve_expr(&mut node.value);
-                self.resolve_local(&mut node.resolved_depth, &node.name);
+                self.resolve_local(&mut node.resolved_slot, &node.name);
             }
             Expr::Super(node) => {
                 match self.current_class {
-                    ClassType::None => self.errors.push(parse_error(
+                    ClassType::None => self.errors.push(resolve_error(
                         &node.keyword,
                         "Can't use 'super' outside of a class.",
                     )),
-                    ClassType::Class => self.errors.push(parse_error(
+                    ClassType::Class => self.errors.push(resolve_error(
                         &node.keyword,
                         "Can't use 'super' in a class with no superclass.",
                     )),
                     ClassType::Subclass => {}
                 }
-                self.resolve_local(&mut node.resolved_depth, &node.keyword);
+                self.resolve_local(&mut node.resolved_slot, &node.keyword);
             }
             Expr::This(node) => {
                 if self.current_class == ClassType::None {
-                    self.errors.push(parse_error(
+                    self.errors.push(resolve_error(
                         &node.keyword,
                         "Can't use 'this' outside of a class.",
                     ));
                 }
-                self.resolve_local(&mut node.resolved_depth, &node.keyword);
+                self.resolve_local(&mut node.resolved_slot, &node.keyword);
             }
 
             // Just walk
@@ -249,25 +334,48 @@ impl<'src> Resolver<'src> {
             Expr::Grouping(node) => {
                 self.resolve_expr(&mut node.expr);
             }
+            Expr::Index(node) => {
+                self.resolve_expr(&mut node.object);
+                self.resolve_expr(&mut node.index);
+            }
+            Expr::List(node) => {
+                for element in &mut node.elements {
+                    self.resolve_expr(element);
+                }
+            }
             Expr::Literal(_) => {}
             Expr::Logical(node) => {
                 self.resolve_expr(&mut node.left);
                 self.resolve_expr(&mut node.right);
             }
+            Expr::Map(node) => {
+                for (key, value) in &mut node.entries {
+                    self.resolve_expr(key);
+                    self.resolve_expr(value);
+                }
+            }
             Expr::Set(node) => {
                 self.resolve_expr(&mut node.object);
                 self.resolve_expr(&mut node.value);
             }
+            Expr::SetIndex(node) => {
+                self.resolve_expr(&mut node.object);
+                self.resolve_expr(&mut node.index);
+                self.resolve_expr(&mut node.value);
+            }
             Expr::Unary(node) => {
                 self.resolve_expr(&mut node.right);
             }
         }
     }
 
-    fn resolve_local(&self, depth_field: &mut Option<usize>, name: &Token<'src>) {
-        for (i, scope) in self.scopes.iter().rev().enumerate() {
-            if scope.contains_key(name.lexeme) {
-                *depth_field = Some(i);
+    fn resolve_local(&self, slot_field: &mut Option<Slot>, name: &Token) {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(local) = scope.get(&*name.lexeme) {
+                *slot_field = Some(Slot {
+                    depth,
+                    index: local.index,
+                });
                 break;
             }
         }
@@ -281,23 +389,67 @@ impl<'src> Resolver<'src> {
         self.scopes.pop();
     }
 
-    fn declare(&mut self, name: &Token<'src>) {
+    fn declare(&mut self, name: &Token) {
         if let Some(scope) = self.scopes.last_mut() {
-            if scope.contains_key(name.lexeme) {
-                self.errors.push(parse_error(
+            if scope.contains_key(&*name.lexeme) {
+                self.errors.push(resolve_error(
                     name,
                     "Already a variable with this name in this scope.",
                 ));
             }
-            scope.insert(name.lexeme, false);
+            let index = scope.len();
+            scope.insert(
+                name.lexeme.clone(),
+                Local {
+                    defined: false,
+                    index,
+                },
+            );
         }
     }
 
-    fn define(&mut self, name: &Token<'src>) {
+    fn define(&mut self, name: &Token) {
+        if let Some(local) = self
+            .scopes
+            .last_mut()
+            .and_then(|scope| scope.get_mut(&*name.lexeme))
+        {
+            local.defined = true;
+        }
+    }
+
+    // For variables like 'this' that the interpreter defines on its own.
+    fn define_implicit(&mut self, name: &str) {
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name.lexeme, true);
+            let index = scope.len();
+            scope.insert(
+                Rc::from(name),
+                Local {
+                    defined: true,
+                    index,
+                },
+            );
         }
     }
 }
 
+// Function declarations are shared with the functions made from them, but
+// not until the program runs, after we're done with them.
+fn unshared(declaration: &mut Rc<FunctionStmt>) -> &mut FunctionStmt {
+    Rc::get_mut(declaration).expect("function declaration shared before resolving")
+}
+
 // TODO: how to test directly? (esp. without writing another traversal...)
+
+#[test]
+fn test_check() {
+    assert!(check("var a = 1; print a;").is_ok());
+    // Runtime errors are fine, since we don't run anything.
+    assert!(check("print nil + 1;").is_ok());
+    assert_eq!(
+        check("{ var a = a; }").unwrap_err().to_string(),
+        "[line 1] Error at 'a': Can't read local variable in its own initializer."
+    );
+    assert!(check("print (;").is_err());
+    assert!(check("\"x").is_err());
+}
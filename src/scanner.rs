@@ -1,46 +1,67 @@
-use crate::error::LoxError;
+use crate::error::{self, ErrorCode, LoxError, Snippet};
+use itertools::Itertools;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
 use TokenType::*;
 
-pub struct Scanner<'src> {
-    source: &'src str,
+pub struct Scanner {
+    source: Rc<str>,
     start: usize,
     current: usize,
     line: usize,
+    // The column (in characters) we're at.
+    column: usize,
+    // Where the current token began, since strings can span lines.
+    start_line: usize,
+    start_column: usize,
+    errors: Vec<LoxError>,
+    // Whether the source ended inside a string.
+    unterminated: bool,
 }
 
-impl<'src> Scanner<'src> {
-    fn scan_tokens(&mut self) -> Result<Vec<Token<'src>>, LoxError> {
+impl Scanner {
+    fn scan_tokens(&mut self) -> Vec<Token> {
         let mut tokens = Vec::new();
         while !self.is_at_end() {
             self.start = self.current;
-            if let Some(tok) = self.scan_token()? {
+            self.start_line = self.line;
+            self.start_column = self.column;
+            if let Some(tok) = self.scan_token() {
                 tokens.push(tok);
             }
         }
 
         tokens.push(Token {
             type_: EOF,
-            lexeme: "",
+            lexeme: Rc::from(""),
             line: self.line,
+            column: self.column,
+            source: self.source.clone(),
+            span: Span {
+                start: self.current,
+                end: self.current,
+            },
         });
-        Ok(tokens)
+        tokens
     }
 
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
 
-    fn scan_token(&mut self) -> Result<Option<Token<'src>>, LoxError> {
+    fn scan_token(&mut self) -> Option<Token> {
         let c = self.advance();
-        Ok(match c {
+        match c {
             b'(' => self.token(LeftParen),
             b')' => self.token(RightParen),
             b'{' => self.token(LeftBrace),
             b'}' => self.token(RightBrace),
+            b'[' => self.token(LeftBracket),
+            b']' => self.token(RightBracket),
             b',' => self.token(Comma),
+            b':' => self.token(Colon),
             b'.' => self.token(Dot),
             b'-' => self.token(Minus),
             b'+' => self.token(Plus),
@@ -71,26 +92,43 @@ impl<'src> Scanner<'src> {
                 }
             }
             b' ' | b'\r' | b'\t' | b'\n' => None,
-            b'"' => self.string()?,
+            b'"' => self.string(),
             c => {
                 if is_digit(c) {
                     self.number()
                 } else if is_alpha(c) {
                     self.identifier()
                 } else {
-                    self.err(format!("Unexpected character: '{c}'."))?
+                    // Skip the rest of the character, so we only complain
+                    // about it once.
+                    self.advance_all(is_utf8_continuation);
+                    let character = &self.source[self.start..self.current];
+                    self.err(format!("Unexpected character: '{character}'."));
+                    None
                 }
             }
-        })
+        }
     }
 
-    fn err(&mut self, message: String) -> Result<Option<Token<'src>>, LoxError> {
-        Err(LoxError {
-            line: self.line,
+    // Records an error about the current token. We carry on scanning after
+    // it, to find any others.
+    fn err(&mut self, message: String) {
+        self.errors.push(LoxError {
+            code: ErrorCode::Lexical,
+            line: self.start_line,
             loc: String::new(),
             exit: 65,
             message,
-        })
+            trace: Vec::new(),
+            snippet: Some(Box::new(Snippet::new(
+                &self.source,
+                Span {
+                    start: self.start,
+                    end: self.current,
+                },
+            ))),
+            more: Vec::new(),
+        });
     }
 
     fn peek(&mut self) -> Option<u8> {
@@ -102,7 +140,7 @@ impl<'src> Scanner<'src> {
     }
 
     fn peek_next(&mut self) -> Option<u8> {
-        if self.current + 1 > self.source.len() {
+        if self.current + 1 >= self.source.len() {
             None
         } else {
             Some(self.source.as_bytes()[self.current + 1])
@@ -113,6 +151,9 @@ impl<'src> Scanner<'src> {
         let ch = self.peek().unwrap();
         if ch == b'\n' {
             self.line += 1;
+            self.column = 1;
+        } else if !is_utf8_continuation(ch) {
+            self.column += 1;
         }
         self.current += 1;
         ch
@@ -135,24 +176,32 @@ impl<'src> Scanner<'src> {
         }
     }
 
-    fn token(&self, type_: TokenType) -> Option<Token<'src>> {
+    fn token(&self, type_: TokenType) -> Option<Token> {
         Some(Token {
             type_,
-            lexeme: &self.source[self.start..self.current],
-            line: self.line,
+            lexeme: Rc::from(&self.source[self.start..self.current]),
+            line: self.start_line,
+            column: self.start_column,
+            source: self.source.clone(),
+            span: Span {
+                start: self.start,
+                end: self.current,
+            },
         })
     }
 
-    fn string(&mut self) -> Result<Option<Token<'src>>, LoxError> {
+    fn string(&mut self) -> Option<Token> {
         self.advance_all(|c| c != b'"');
         if !self.match_(b'"') {
-            self.err("Unterminated string".to_string())?;
+            self.unterminated = true;
+            self.err("Unterminated string".to_string());
+            return None;
         }
 
-        Ok(self.token(StringLiteral))
+        self.token(StringLiteral)
     }
 
-    fn number(&mut self) -> Option<Token<'src>> {
+    fn number(&mut self) -> Option<Token> {
         self.advance_all(is_digit);
         let decimal = self.peek() == Some(b'.') && self.peek_next().is_some_and(is_digit);
         if decimal {
@@ -162,7 +211,7 @@ impl<'src> Scanner<'src> {
         self.token(Number)
     }
 
-    fn identifier(&mut self) -> Option<Token<'src>> {
+    fn identifier(&mut self) -> Option<Token> {
         self.advance_all(is_alpha_numeric);
 
         let type_ = KEYWORDS
@@ -185,26 +234,100 @@ fn is_alpha_numeric(c: u8) -> bool {
     is_digit(c) || is_alpha(c)
 }
 
-pub fn scan_tokens<'src>(source: &'src str) -> Result<Vec<Token<'src>>, LoxError> {
-    let mut scanner = Scanner {
-        source,
+fn is_utf8_continuation(c: u8) -> bool {
+    c & 0xC0 == 0x80
+}
+
+fn scanner(source: &str) -> Scanner {
+    Scanner {
+        source: Rc::from(source),
         start: 0,
         current: 0,
         line: 1,
-    };
-    scanner.scan_tokens()
+        column: 1,
+        start_line: 1,
+        start_column: 1,
+        errors: Vec::new(),
+        unterminated: false,
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Token<'src> {
+pub fn scan_tokens(source: &str) -> Result<Vec<Token>, LoxError> {
+    let mut scanner = scanner(source);
+    let tokens = scanner.scan_tokens();
+    error::check_errors(scanner.errors)?;
+    Ok(tokens)
+}
+
+// Whether the code stops inside a string, which more of it (e.g. the REPL's
+// next line) might close.
+pub fn ends_in_string(source: &str) -> bool {
+    let mut scanner = scanner(source);
+    scanner.scan_tokens();
+    scanner.unterminated
+}
+
+/// The tokens in some code, one per line after where they start, as the
+/// REPL's :tokens shows them.
+///
+/// # Errors
+///
+/// Any errors scanning the code.
+pub fn print_tokens(source: &str) -> Result<String, LoxError> {
+    let tokens = scan_tokens(source)?;
+    Ok(tokens
+        .iter()
+        .map(|token| format!("{}:{} {token}", token.line, token.column))
+        .join("\n"))
+}
+
+#[derive(Clone)]
+pub struct Token {
     pub type_: TokenType,
-    pub lexeme: &'src str,
+    pub lexeme: Rc<str>,
     pub line: usize,
+    // Where the token starts on its line, counting characters from 1.
+    pub column: usize,
+    // The whole source the token came from, and where it is in it, so that
+    // errors can show the code around it. Shared by every token from it.
+    pub source: Rc<str>,
+    pub span: Span,
 }
 
-impl<'src> fmt::Display for Token<'src> {
+// Leaves out the source, which would swamp everything else.
+#[allow(clippy::missing_fields_in_debug)]
+impl fmt::Debug for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?} {}", self.type_, self.lexeme)
+        f.debug_struct("Token")
+            .field("type_", &self.type_)
+            .field("lexeme", &self.lexeme)
+            .field("line", &self.line)
+            .field("column", &self.column)
+            .field("span", &self.span)
+            .finish()
+    }
+}
+
+// A range of bytes in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // From the start of this span to the end of another, later one.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {}", self.type_, &self.lexeme)
     }
 }
 
@@ -215,7 +338,10 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -240,19 +366,27 @@ pub enum TokenType {
 
     // Keywords.
     And,
+    As,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
+    Finally,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -263,24 +397,37 @@ pub enum TokenType {
 static KEYWORDS: Lazy<HashMap<&str, TokenType>> = Lazy::new(|| {
     let mut m = HashMap::new();
     m.insert("and", And);
+    m.insert("as", As);
+    m.insert("break", Break);
+    m.insert("catch", Catch);
     m.insert("class", Class);
+    m.insert("continue", Continue);
     m.insert("else", Else);
     m.insert("false", False);
+    m.insert("finally", Finally);
     m.insert("for", For);
     m.insert("fun", Fun);
     m.insert("if", If);
+    m.insert("import", Import);
     m.insert("nil", Nil);
     m.insert("or", Or);
     m.insert("print", Print);
     m.insert("return", Return);
     m.insert("super", Super);
     m.insert("this", This);
+    m.insert("throw", Throw);
     m.insert("true", True);
+    m.insert("try", Try);
     m.insert("var", Var);
     m.insert("while", While);
     m
 });
 
+/// The reserved words, for the REPL to complete.
+pub fn keywords() -> impl Iterator<Item = &'static str> {
+    KEYWORDS.keys().copied()
+}
+
 #[test]
 fn test_scanner() {
     insta::assert_debug_snapshot!(scan_tokens("(){},.-+;* // (symbols)"));
@@ -296,4 +443,55 @@ fn test_scanner() {
     insta::assert_debug_snapshot!(scan_tokens("and class class_ else false for fun"));
     insta::assert_debug_snapshot!(scan_tokens("if if_ nil null or print return super"));
     insta::assert_debug_snapshot!(scan_tokens("this true var while class and fun"));
+    insta::assert_debug_snapshot!(scan_tokens("[1, a][0]"));
+    insta::assert_debug_snapshot!(scan_tokens("{\"a\": 1}"));
+    insta::assert_debug_snapshot!(scan_tokens("break continue breaks"));
+    insta::assert_debug_snapshot!(scan_tokens("try catch finally throw"));
+    insta::assert_debug_snapshot!(scan_tokens(r#"import "lib.lox" as lib;"#));
+}
+
+#[test]
+fn test_multiline_string_error() {
+    // Reported where the string starts, like its snippet, not where we gave
+    // up on it.
+    let error = scan_tokens("print 1;\nvar s = \"ab\ncd\n").unwrap_err();
+    assert_eq!(error.line, 2);
+    assert_eq!(error.snippet.as_ref().unwrap().line, 2);
+    assert_eq!(
+        error.report(),
+        "[line 2] Error: Unterminated string\n2 | var s = \"ab\n  |         ^^^"
+    );
+}
+
+#[test]
+fn test_trailing_dot() {
+    // The dot isn't part of the number, and there's nothing after it to
+    // look at.
+    let tokens = scan_tokens("1.").unwrap();
+    let types: Vec<_> = tokens.iter().map(|token| token.type_).collect();
+    assert_eq!(types, [Number, Dot, EOF]);
+}
+
+#[test]
+fn test_scanner_errors() {
+    let error = scan_tokens("var a = 1 @ 2;\nprint #é;\n\"abc").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        [
+            "[line 1] Error: Unexpected character: '@'.",
+            "[line 2] Error: Unexpected character: '#'.",
+            "[line 2] Error: Unexpected character: 'é'.",
+            "[line 3] Error: Unterminated string",
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn test_print_tokens() {
+    assert_eq!(
+        print_tokens("print\n  a;").unwrap(),
+        "1:1 Print print\n2:3 Identifier a\n2:4 Semicolon ;\n2:5 EOF "
+    );
+    assert!(print_tokens("@").is_err());
 }
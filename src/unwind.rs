@@ -1,25 +1,60 @@
-use crate::error::{runtime_error, LoxError};
+use crate::ast::Expr;
+use crate::error::{expr_error, internal_error, runtime_error, CallSite, ErrorCode, LoxError};
 use crate::object::Object;
 use crate::scanner;
 
 #[derive(Debug)]
-pub enum Unwinder<'ast, 'src: 'ast> {
+pub enum Unwinder {
     Return {
-        keyword: &'ast scanner::Token<'src>,
-        value: Object<'ast, 'src>,
+        keyword: Box<scanner::Token>,
+        value: Object,
+    },
+    Break {
+        keyword: Box<scanner::Token>,
+    },
+    Continue {
+        keyword: Box<scanner::Token>,
+    },
+    // A value thrown by a throw statement. Runtime errors can be caught too,
+    // but stay as Err until they are.
+    Throw {
+        keyword: Box<scanner::Token>,
+        value: Object,
+        // The calls in progress at the throw, in case nothing catches it.
+        trace: Vec<CallSite>,
     },
     Err(LoxError),
+    // The program called exit(), which nothing catches (not even finally
+    // blocks), so we unwind all the way out.
+    Exit {
+        status: u8,
+    },
 }
 
-impl<'ast, 'src: 'ast> Unwinder<'ast, 'src> {
+impl Unwinder {
     pub fn err<T>(token: &scanner::Token, message: &str) -> Result<T, Self> {
         Err(Self::Err(runtime_error(token, message)))
     }
 
-    pub fn promote<T>(result: Result<T, LoxError>) -> Result<T, Self> {
-        match result {
-            Ok(r) => Ok(r),
-            Err(e) => Err(Self::Err(e)),
+    // An error that means there's a bug in the interpreter.
+    pub fn bug<T>(token: &scanner::Token, message: &str) -> Result<T, Self> {
+        Err(Self::Err(internal_error(token, message)))
+    }
+
+    pub fn expr_err<T>(token: &scanner::Token, expr: &Expr, message: &str) -> Result<T, Self> {
+        Err(Self::Err(expr_error(token, expr, message)))
+    }
+
+    // The error to report for a thrown value nothing caught. Rethrowing a
+    // caught runtime error reports it just as if it had never been caught.
+    pub fn uncaught(keyword: &scanner::Token, value: &Object, trace: Vec<CallSite>) -> LoxError {
+        match value {
+            Object::Error(e) => (**e).clone(),
+            _ => LoxError {
+                code: ErrorCode::UncaughtException,
+                trace,
+                ..runtime_error(keyword, &format!("Uncaught exception: {value}."))
+            },
         }
     }
 }
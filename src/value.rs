@@ -0,0 +1,295 @@
+use crate::chunk::Function;
+use crate::error::LoxError;
+use crate::gc::{self, Trace};
+use crate::list::{self, BoundNative, Element};
+use crate::map::{self, Map};
+use crate::native::NativeFn;
+use crate::object::Literal;
+use derive_more::From;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+// Runtime values for the bytecode VM. These mirror object::Object, but
+// own all their data rather than borrowing from the AST.
+#[derive(Debug, Clone, From)]
+pub enum Value {
+    Literal(Literal),
+    Native(Native),
+    BoundNative(BoundNative<Value>),
+    Closure(Rc<Closure>),
+    BoundMethod(Rc<BoundMethod>),
+    Class(Rc<RefCell<Class>>),
+    Instance(Rc<RefCell<Instance>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map<Value>>>),
+    // A runtime error, once caught.
+    Error(Rc<LoxError>),
+    Module(Rc<Module>),
+}
+
+// Each module has its own globals, which its closures share.
+pub type Globals = Rc<RefCell<HashMap<String, Value>>>;
+
+#[derive(Clone)]
+pub struct Native {
+    pub arity: usize,
+    pub function: NativeFn<Value>,
+    pub name: String,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<function {} (arity {})>", &self.name, &self.arity)
+    }
+}
+
+impl fmt::Display for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<function {}>", &self.name)
+    }
+}
+
+#[derive(Debug)]
+pub enum Upvalue {
+    // Index of the captured variable's stack slot, while it's still live.
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    pub globals: Globals,
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Rc<RefCell<Instance>>,
+    pub method: Rc<Closure>,
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<String, Rc<Closure>>,
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<class {}>", &self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class_: Rc<RefCell<Class>>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<instance of {}>", &self.class_.borrow().name)
+    }
+}
+
+// An imported module's namespace.
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub globals: Globals,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Literal(v) => write!(f, "{v}"),
+            Value::Native(v) => v.fmt(f),
+            Value::BoundNative(v) => v.fmt(f),
+            Value::Closure(c) => c.function.fmt(f),
+            Value::BoundMethod(b) => b.method.function.fmt(f),
+            Value::Class(c) => c.borrow().fmt(f),
+            Value::Instance(i) => i.borrow().fmt(f),
+            Value::List(l) => list::fmt(l, f),
+            Value::Map(m) => map::fmt(m, f),
+            Value::Error(e) => write!(f, "<error: {}>", e.message),
+            Value::Module(m) => write!(f, "<module {}>", m.name),
+        }
+    }
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Literal(v) => v.is_truthy(),
+            _ => true,
+        }
+    }
+
+    pub fn get_index(&self, index: &Self) -> Result<Self, String> {
+        match self {
+            Value::List(l) => list::get(l, index),
+            Value::Map(m) => m.borrow().get(index),
+            _ => Err(format!("Only lists and maps can be indexed, got '{self}'.")),
+        }
+    }
+
+    pub fn set_index(&self, index: &Self, value: Self) -> Result<(), String> {
+        match self {
+            Value::List(l) => list::set(l, index, value),
+            Value::Map(m) => m.borrow_mut().set(index, value),
+            _ => Err(format!("Only lists and maps can be indexed, got '{self}'.")),
+        }
+    }
+
+    // Like object::Function, closures and bound methods compare equal
+    // whenever they share a declaration.
+    fn function(&self) -> Option<&Rc<Function>> {
+        match self {
+            Value::Closure(c) => Some(&c.function),
+            Value::BoundMethod(b) => Some(&b.method.function),
+            _ => None,
+        }
+    }
+}
+
+impl Element for Value {
+    fn literal(&self) -> Option<&Literal> {
+        match self {
+            Value::Literal(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        match self {
+            // Modules live as long as the VM, so we needn't trace them.
+            Value::Literal(_) | Value::Native(_) | Value::Error(_) | Value::Module(_) => {}
+            Value::BoundNative(b) => b.trace(visit),
+            Value::Closure(c) => visit(gc::address(c)),
+            Value::BoundMethod(b) => visit(gc::address(b)),
+            Value::Class(c) => visit(gc::address(c)),
+            Value::Instance(i) => visit(gc::address(i)),
+            Value::List(l) => visit(gc::address(l)),
+            Value::Map(m) => visit(gc::address(m)),
+        }
+    }
+
+    fn native(name: &str, arity: usize, function: NativeFn<Self>) -> Self {
+        Native {
+            arity,
+            function,
+            name: name.to_string(),
+        }
+        .into()
+    }
+
+    fn module(name: &str, members: HashMap<String, Self>) -> Self {
+        Rc::new(Module {
+            name: name.to_string(),
+            globals: Rc::new(RefCell::new(members)),
+        })
+        .into()
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        if let (Some(l), Some(r)) = (self.function(), other.function()) {
+            return Rc::ptr_eq(l, r);
+        }
+        match (self, other) {
+            (Value::Literal(l), Value::Literal(r)) => l == r,
+            (Value::Native(l), Value::Native(r)) => {
+                l.arity == r.arity && Rc::ptr_eq(&l.function, &r.function) && l.name == r.name
+            }
+            (Value::BoundNative(l), Value::BoundNative(r)) => l == r,
+            (Value::Class(l), Value::Class(r)) => Rc::ptr_eq(l, r),
+            (Value::Instance(l), Value::Instance(r)) => Rc::ptr_eq(l, r),
+            (Value::List(l), Value::List(r)) => Rc::ptr_eq(l, r),
+            (Value::Map(l), Value::Map(r)) => Rc::ptr_eq(l, r),
+            (Value::Error(l), Value::Error(r)) => Rc::ptr_eq(l, r),
+            (Value::Module(l), Value::Module(r)) => Rc::ptr_eq(l, r),
+            _ => false,
+        }
+    }
+}
+
+// Closures and bound methods are immutable, so we can't clear them; but any
+// cycle through them also passes through an upvalue, instance or class.
+impl Trace for Closure {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        for upvalue in &self.upvalues {
+            visit(gc::address(upvalue));
+        }
+        true
+    }
+
+    fn clear(&self) {}
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        visit(gc::address(&self.receiver));
+        visit(gc::address(&self.method));
+        true
+    }
+
+    fn clear(&self) {}
+}
+
+impl Trace for RefCell<Upvalue> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(upvalue) = self.try_borrow() else {
+            return false;
+        };
+        if let Upvalue::Closed(value) = &*upvalue {
+            value.trace(visit);
+        }
+        true
+    }
+
+    fn clear(&self) {
+        let mut upvalue = self.borrow_mut();
+        if let Upvalue::Closed(value) = &mut *upvalue {
+            let value = std::mem::replace(value, Literal::Nil.into());
+            drop(upvalue);
+            drop(value);
+        }
+    }
+}
+
+impl Trace for RefCell<Class> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(class) = self.try_borrow() else {
+            return false;
+        };
+        for method in class.methods.values() {
+            visit(gc::address(method));
+        }
+        true
+    }
+
+    fn clear(&self) {
+        let methods = std::mem::take(&mut self.borrow_mut().methods);
+        drop(methods);
+    }
+}
+
+impl Trace for RefCell<Instance> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        let Ok(inst) = self.try_borrow() else {
+            return false;
+        };
+        visit(gc::address(&inst.class_));
+        inst.fields.values().for_each(|v| v.trace(visit));
+        true
+    }
+
+    fn clear(&self) {
+        let fields = std::mem::take(&mut self.borrow_mut().fields);
+        drop(fields);
+    }
+}
@@ -0,0 +1,1007 @@
+use crate::ast::Program;
+use crate::builtins::{self, Input};
+use crate::chunk::{Chunk, Function, Op};
+use crate::compiler;
+use crate::environment;
+use crate::error::{exit_error, CallSite, ErrorCode, LoxError, Snippet, DEFAULT_MAX_DEPTH};
+use crate::file;
+use crate::gc::Heap;
+use crate::list::{BoundNative, Receiver};
+use crate::map::Map;
+use crate::module::{Import, Modules};
+use crate::native::NativeError;
+use crate::object::Literal;
+use crate::parser;
+use crate::resolver;
+use crate::scanner;
+use crate::value::{BoundMethod, Class, Closure, Globals, Instance, Module, Upvalue, Value};
+use itertools::Itertools;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use std::rc::Rc;
+
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    // Index of the frame's slot zero on the value stack.
+    slots: usize,
+    // If the frame is running a module's top-level code, the module's name;
+    // it returns the module's namespace.
+    module: Option<String>,
+}
+
+// An active try statement.
+struct Handler {
+    // The number of frames and stack height to unwind to.
+    frames: usize,
+    stack: usize,
+    ip: usize,
+}
+
+/// The bytecode VM, which runs the same language as [`Interpreter`], for
+/// the command-line tool. It has no host API, and its interface is unstable:
+/// it may change in any release.
+///
+/// [`Interpreter`]: crate::Interpreter
+pub struct Vm<F: FnMut(String)> {
+    printer: F,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: Globals,
+    // Each module starts with its own copy of these globals.
+    builtins: HashMap<String, Value>,
+    // Upvalues still pointing into the stack, sorted by slot.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    handlers: Vec<Handler>,
+    heap: Rc<RefCell<Heap>>,
+    modules: Modules<Value>,
+    max_depth: usize,
+    input: Rc<RefCell<Input>>,
+}
+
+/// A VM whose print statements write to stdout. Unstable, like [`Vm`].
+#[must_use]
+pub fn vm() -> Vm<impl FnMut(String)> {
+    Vm::new(|s| println!("{s}"), builtins::now_sec)
+}
+
+impl<F: FnMut(String)> Vm<F> {
+    fn new(printer: F, clock: impl FnMut() -> f64 + 'static) -> Self {
+        let heap = Rc::new(RefCell::new(Heap::new()));
+        let input = Rc::new(RefCell::new(Input::Stdin));
+        let builtins = builtins::globals(&heap, clock, &input);
+        let mut modules = Modules::new();
+        modules.define("file", file::module(&heap));
+        Vm {
+            printer,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: Rc::new(RefCell::new(builtins.clone())),
+            builtins,
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            heap,
+            modules,
+            max_depth: DEFAULT_MAX_DEPTH,
+            input,
+        }
+    }
+
+    /// As for [`Interpreter::set_script`](crate::Interpreter::set_script).
+    pub fn set_script(&mut self, path: &Path) {
+        self.modules.set_script(path);
+    }
+
+    /// As for [`Interpreter::clear_script`](crate::Interpreter::clear_script).
+    pub fn clear_script(&mut self) {
+        self.modules.clear_script();
+    }
+
+    /// As for [`Interpreter::set_max_depth`](crate::Interpreter::set_max_depth).
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// As for [`Interpreter::set_input`](crate::Interpreter::set_input).
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        *self.input.borrow_mut() = Input::Reader(Box::new(input));
+    }
+
+    /// As for [`Interpreter::set_args`](crate::Interpreter::set_args).
+    pub fn set_args(&mut self, args: impl IntoIterator<Item = String>) {
+        let args: Value = builtins::string_list(&self.heap, args);
+        self.builtins.insert("args".to_string(), args.clone());
+        self.globals.borrow_mut().insert("args".to_string(), args);
+    }
+
+    /// Compiles and runs some Lox code. Definitions are kept, for later code
+    /// to use.
+    ///
+    /// # Errors
+    ///
+    /// Any errors in the code, found before or while running it. If the
+    /// code calls `exit`, an error with code [`ErrorCode::Exit`].
+    pub fn interpret_source(&mut self, source: &str) -> Result<(), LoxError> {
+        let function = compile_source(source)?;
+        self.run_script(function)
+    }
+
+    /// Runs a line typed at the REPL, printing the value of an expression
+    /// typed without its semicolon.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Vm::interpret_source`].
+    pub fn interpret_line(&mut self, source: &str) -> Result<(), LoxError> {
+        let tokens = scanner::scan_tokens(source)?;
+        let mut prog = parser::parse_repl(tokens)?;
+        resolver::resolve(&mut prog)?;
+        self.interpret(&prog)
+    }
+
+    /// The globals, as the REPL's :env shows them, in the same form as the
+    /// tree-walker's environment.
+    pub fn dump_globals(&self) -> String {
+        environment::dump_globals(&self.globals.borrow())
+    }
+
+    /// The names of the globals, for the REPL to complete.
+    pub fn global_names(&self) -> Vec<String> {
+        self.globals.borrow().keys().cloned().collect()
+    }
+
+    /// The properties (fields, methods and module members) of the globals'
+    /// values, for the REPL to complete after a dot.
+    pub fn property_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for value in self.globals.borrow().values() {
+            match value {
+                Value::Instance(instance) => {
+                    let instance = instance.borrow();
+                    names.extend(instance.fields.keys().cloned());
+                    names.extend(instance.class_.borrow().methods.keys().cloned());
+                }
+                Value::Module(module) => names.extend(module.globals.borrow().keys().cloned()),
+                _ => {}
+            }
+        }
+        names
+    }
+
+    /// Compiles and runs a program that's already been parsed and resolved.
+    ///
+    /// # Errors
+    ///
+    /// Any compile or runtime error, or [`ErrorCode::Exit`] if it calls
+    /// `exit`.
+    pub(crate) fn interpret(&mut self, prog: &Program) -> Result<(), LoxError> {
+        let function = compiler::compile(prog)?;
+        self.run_script(function)
+    }
+
+    fn run_script(&mut self, function: Function) -> Result<(), LoxError> {
+        self.push_script(function, self.globals.clone(), None);
+        let result = self.run();
+        if result.is_err() {
+            // Leave things clean for the next REPL line.
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+            self.handlers.clear();
+        }
+        result
+    }
+
+    // Starts running a script (or module's) top-level code.
+    fn push_script(&mut self, function: Function, globals: Globals, module: Option<String>) {
+        let closure = Rc::new(Closure {
+            function: Rc::new(function),
+            upvalues: Vec::new(),
+            globals,
+        });
+        self.push(closure.clone().into());
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - 1,
+            module,
+        });
+    }
+
+    fn run(&mut self) -> Result<(), LoxError> {
+        while !self.frames.is_empty() {
+            if let Err(err) = self.step() {
+                // An uncaught throw has already unwound everything.
+                if self.frames.is_empty() {
+                    return Err(err);
+                }
+                // Nothing catches exit(), not even finally blocks, and
+                // nothing catches our own bugs either.
+                if matches!(err.code, ErrorCode::Exit | ErrorCode::Internal) {
+                    for frame in self.frames.drain(..) {
+                        if frame.module.is_some() {
+                            self.modules.abandon();
+                        }
+                    }
+                    return Err(err);
+                }
+                self.unwind(Value::Error(Rc::new(err)))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Executes a single instruction.
+    fn step(&mut self) -> Result<(), LoxError> {
+        let op = self.read_op();
+        match op {
+            Op::Constant(index) => {
+                let value = self.chunk().constants[index as usize].clone();
+                self.push(value.into());
+            }
+            Op::Nil => self.push(Literal::Nil.into()),
+            Op::True => self.push(Literal::Bool(true).into()),
+            Op::False => self.push(Literal::Bool(false).into()),
+            Op::Pop => {
+                self.pop();
+            }
+            Op::GetLocal(slot) => {
+                let value = self.stack[self.frame().slots + slot as usize].clone();
+                self.push(value);
+            }
+            Op::SetLocal(slot) => {
+                let index = self.frame().slots + slot as usize;
+                self.stack[index] = self.peek(0).clone();
+            }
+            Op::GetGlobal(index) => {
+                let name = &self.chunk().names[index as usize];
+                let value = self.frame().closure.globals.borrow().get(name).cloned();
+                match value {
+                    Some(value) => self.push(value),
+                    None => return Err(self.undefined(index)),
+                }
+            }
+            Op::DefineGlobal(index) => {
+                let name = self.chunk().names[index as usize].clone();
+                let value = self.pop();
+                self.frame()
+                    .closure
+                    .globals
+                    .borrow_mut()
+                    .insert(name, value);
+            }
+            Op::SetGlobal(index) => {
+                let value = self.peek(0).clone();
+                let name = self.chunk().names[index as usize].clone();
+                match self.frame().closure.globals.borrow_mut().get_mut(&name) {
+                    Some(global) => *global = value,
+                    None => return Err(self.undefined(index)),
+                }
+            }
+            Op::GetUpvalue(index) => {
+                let upvalue = self.frame().closure.upvalues[index as usize].clone();
+                let value = match &*upvalue.borrow() {
+                    Upvalue::Open(slot) => self.stack[*slot].clone(),
+                    Upvalue::Closed(value) => value.clone(),
+                };
+                self.push(value);
+            }
+            Op::SetUpvalue(index) => {
+                let value = self.peek(0).clone();
+                let upvalue = self.frame().closure.upvalues[index as usize].clone();
+                let mut upvalue = upvalue.borrow_mut();
+                match &mut *upvalue {
+                    Upvalue::Open(slot) => self.stack[*slot] = value,
+                    Upvalue::Closed(closed) => *closed = value,
+                }
+            }
+            Op::GetProperty(index) => {
+                let value = match self.peek(0).clone() {
+                    Value::Instance(instance) => {
+                        let name = &self.chunk().names[index as usize];
+                        let field = instance.borrow().fields.get(name).cloned();
+                        if let Some(value) = field {
+                            value
+                        } else {
+                            let class_ = instance.borrow().class_.clone();
+                            self.bind_method(&class_, instance, index)?
+                        }
+                    }
+                    Value::List(list) => self.native_method(Receiver::List(list), index)?,
+                    Value::Map(map) => self.native_method(Receiver::Map(map), index)?,
+                    Value::Error(error) => {
+                        let name = &self.chunk().names[index as usize];
+                        match error.property(name) {
+                            Some(value) => value.into(),
+                            None => {
+                                return Err(self.error(&format!("Undefined property '{name}'.")))
+                            }
+                        }
+                    }
+                    Value::Module(module) => {
+                        let name = &self.chunk().names[index as usize];
+                        let value = module.globals.borrow().get(name).cloned();
+                        match value {
+                            Some(value) => value,
+                            None => {
+                                return Err(self.error(&format!("Undefined property '{name}'.")))
+                            }
+                        }
+                    }
+                    object => {
+                        let message = format!("Only instances have properties, got '{object}'.");
+                        return Err(self.error(&message));
+                    }
+                };
+                self.pop();
+                self.push(value);
+            }
+            Op::SetProperty(index) => {
+                let Value::Instance(instance) = self.peek(1).clone() else {
+                    let message = format!("Only instances have fields, got '{}'.", self.peek(1));
+                    return Err(self.error(&message));
+                };
+                let name = self.chunk().names[index as usize].clone();
+                let value = self.pop();
+                instance.borrow_mut().fields.insert(name, value.clone());
+                self.pop();
+                self.push(value);
+            }
+            Op::GetSuper(index) => {
+                let Value::Class(superclass) = self.pop() else {
+                    return Err(self.bug("super was not a class (interpreter bug?)"));
+                };
+                let Value::Instance(instance) = self.pop() else {
+                    return Err(self.bug("this was not an instance (interpreter bug?)"));
+                };
+                let value = self.bind_method(&superclass, instance, index)?;
+                self.push(value);
+            }
+            Op::List(count) => {
+                let elements = self.stack.split_off(self.stack.len() - count as usize);
+                let list = self.heap.borrow_mut().track(RefCell::new(elements));
+                self.push(list.into());
+            }
+            Op::Map(count) => {
+                let entries = self.stack.split_off(self.stack.len() - 2 * count as usize);
+                let mut map = Map::new();
+                for (key, value) in entries.into_iter().tuples() {
+                    map.set(&key, value)
+                        .map_err(|message| self.error(&message))?;
+                }
+                let map = self.heap.borrow_mut().track(RefCell::new(map));
+                self.push(map.into());
+            }
+            Op::GetIndex => {
+                let index = self.pop();
+                let object = self.pop();
+                let value = object
+                    .get_index(&index)
+                    .map_err(|message| self.error(&message))?;
+                self.push(value);
+            }
+            Op::SetIndex => {
+                let value = self.pop();
+                let index = self.pop();
+                let object = self.pop();
+                object
+                    .set_index(&index, value.clone())
+                    .map_err(|message| self.error(&message))?;
+                self.push(value);
+            }
+            Op::Equal => {
+                let right = self.pop();
+                let left = self.pop();
+                self.push(Literal::Bool(left == right).into());
+            }
+            Op::NotEqual => {
+                let right = self.pop();
+                let left = self.pop();
+                self.push(Literal::Bool(left != right).into());
+            }
+            Op::Greater => self.comparison(|l, r| l > r)?,
+            Op::GreaterEqual => self.comparison(|l, r| l >= r)?,
+            Op::Less => self.comparison(|l, r| l < r)?,
+            Op::LessEqual => self.comparison(|l, r| l <= r)?,
+            Op::Add => {
+                let right = self.pop();
+                let left = self.pop();
+                let result = match (left, right) {
+                    (Value::Literal(Literal::Number(l)), Value::Literal(Literal::Number(r))) => {
+                        Literal::Number(l + r)
+                    }
+                    (Value::Literal(Literal::String(l)), Value::Literal(Literal::String(r))) => {
+                        Literal::String(l + &r)
+                    }
+                    (_, _) => return Err(self.error("invalid types for addition")),
+                };
+                self.push(result.into());
+            }
+            Op::Subtract => self.arithmetic("invalid types for subtraction", |l, r| l - r)?,
+            Op::Multiply => {
+                self.arithmetic("invalid types for multiplication", |l, r| l * r)?;
+            }
+            Op::Divide => self.arithmetic("invalid types for division", |l, r| l / r)?,
+            Op::Not => {
+                let value = self.pop();
+                self.push(Literal::Bool(!value.is_truthy()).into());
+            }
+            Op::Negate => match self.pop() {
+                Value::Literal(Literal::Number(n)) => self.push(Literal::Number(-n).into()),
+                _ => return Err(self.error("invalid type for negation")),
+            },
+            Op::Print => {
+                let value = self.pop();
+                (self.printer)(format!("{value}"));
+            }
+            Op::Jump(offset) => self.frame_mut().ip += offset as usize,
+            Op::JumpIfFalse(offset) => {
+                if !self.peek(0).is_truthy() {
+                    self.frame_mut().ip += offset as usize;
+                }
+            }
+            Op::Loop(offset) => self.frame_mut().ip -= offset as usize,
+            Op::PushHandler(offset) => {
+                let handler = Handler {
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    ip: self.frame().ip + offset as usize,
+                };
+                self.handlers.push(handler);
+            }
+            Op::PopHandler => {
+                self.handlers.pop();
+            }
+            Op::Throw => {
+                let exception = self.pop();
+                self.unwind(exception)?;
+            }
+            Op::Call(count) => self.call_value(count as usize)?,
+            Op::Closure(index) => {
+                let function = self.chunk().functions[index as usize].clone();
+                let slots = self.frame().slots;
+                let enclosing = self.frame().closure.clone();
+                let upvalues = function
+                    .upvalues
+                    .iter()
+                    .map(|upvalue| {
+                        if upvalue.is_local {
+                            self.capture_upvalue(slots + upvalue.index as usize)
+                        } else {
+                            enclosing.upvalues[upvalue.index as usize].clone()
+                        }
+                    })
+                    .collect();
+                let globals = enclosing.globals.clone();
+                let closure = self.heap.borrow_mut().track(Closure {
+                    function,
+                    upvalues,
+                    globals,
+                });
+                self.push(closure.into());
+            }
+            Op::CloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.pop();
+            }
+            Op::Return => {
+                let result = self.pop();
+                let frame = self.frames.pop().unwrap();
+                self.close_upvalues(frame.slots);
+                self.stack.truncate(frame.slots);
+                if let Some(name) = frame.module {
+                    let globals = frame.closure.globals.clone();
+                    let module: Value = Rc::new(Module { name, globals }).into();
+                    self.modules.leave(module.clone());
+                    self.push(module);
+                } else if !self.frames.is_empty() {
+                    // (Once the script itself returns, we're done.)
+                    self.push(result);
+                }
+            }
+            Op::Class(index) => {
+                let name = self.chunk().names[index as usize].clone();
+                let class_ = self.heap.borrow_mut().track(RefCell::new(Class {
+                    name,
+                    methods: HashMap::new(),
+                }));
+                self.push(class_.into());
+            }
+            Op::Inherit => {
+                let Value::Class(superclass) = self.peek(1).clone() else {
+                    let message = format!("Superclass must be a class (was '{}').", self.peek(1));
+                    return Err(self.error(&message));
+                };
+                let Value::Class(subclass) = self.pop() else {
+                    return Err(self.bug("subclass was not a class (compiler bug?)"));
+                };
+                let methods = superclass.borrow().methods.clone();
+                subclass.borrow_mut().methods.extend(methods);
+            }
+            Op::Method(index) => {
+                let Value::Closure(method) = self.pop() else {
+                    return Err(self.bug("method was not a closure (compiler bug?)"));
+                };
+                let Value::Class(class_) = self.peek(0) else {
+                    return Err(self.bug("method outside of a class (compiler bug?)"));
+                };
+                let name = self.chunk().names[index as usize].clone();
+                class_.borrow_mut().methods.insert(name, method);
+            }
+            Op::Import(index) => {
+                let path = &self.chunk().names[index as usize];
+                let found = self.modules.find(path);
+                let found = found.map_err(|message| LoxError {
+                    code: ErrorCode::Import,
+                    ..self.error(&message)
+                });
+                match found? {
+                    Import::Loaded(module) => self.push(module),
+                    Import::New(source) => {
+                        let name = source.name.clone();
+                        let text = self.modules.enter(source);
+                        match compile_source(&text) {
+                            Ok(function) => {
+                                let globals = Rc::new(RefCell::new(self.builtins.clone()));
+                                self.push_script(function, globals, Some(name));
+                            }
+                            Err(error) => return Err(self.modules.fail(&error, &self.snippet())),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn call_value(&mut self, count: usize) -> Result<(), LoxError> {
+        let callee_slot = self.stack.len() - count - 1;
+        match self.stack[callee_slot].clone() {
+            Value::Closure(closure) => self.call(closure, count),
+            Value::BoundMethod(bound) => {
+                self.stack[callee_slot] = bound.receiver.clone().into();
+                self.call(bound.method.clone(), count)
+            }
+            Value::Class(class_) => {
+                let initializer = class_.borrow().methods.get("init").cloned();
+                self.stack[callee_slot] = self
+                    .heap
+                    .borrow_mut()
+                    .track(RefCell::new(Instance {
+                        class_,
+                        fields: HashMap::new(),
+                    }))
+                    .into();
+                match initializer {
+                    Some(init) => self.call(init, count),
+                    None => self.arity_check(0, count),
+                }
+            }
+            Value::BoundNative(method) => {
+                self.arity_check(method.arity, count)?;
+                let arguments = self.stack.split_off(callee_slot + 1);
+                self.pop();
+                let result = method
+                    .call(arguments, |values| {
+                        self.heap.borrow_mut().track(RefCell::new(values)).into()
+                    })
+                    .map_err(|message| self.error(&message))?;
+                self.push(result);
+                Ok(())
+            }
+            Value::Native(native) => {
+                self.arity_check(native.arity, count)?;
+                let arguments = self.stack.split_off(callee_slot + 1);
+                self.pop();
+                let result =
+                    (native.function.borrow_mut())(arguments).map_err(|error| match error {
+                        NativeError::Message(message) => self.error(&message),
+                        NativeError::Exit(status) => exit_error(status),
+                    })?;
+                self.push(result);
+                Ok(())
+            }
+            callee @ (Value::Literal(_)
+            | Value::Instance(_)
+            | Value::List(_)
+            | Value::Map(_)
+            | Value::Error(_)
+            | Value::Module(_)) => Err(self.error(&format!(
+                "Can only call functions and classes, got '{callee}'."
+            ))),
+        }
+    }
+
+    fn call(&mut self, closure: Rc<Closure>, count: usize) -> Result<(), LoxError> {
+        self.arity_check(closure.function.arity, count)?;
+        // (Not counting the frames running the script and its modules.)
+        if self.frames.len() - 1 - self.modules.running() >= self.max_depth {
+            return Err(LoxError {
+                code: ErrorCode::StackOverflow,
+                ..self.error("Stack overflow.")
+            });
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - count - 1,
+            module: None,
+        });
+        Ok(())
+    }
+
+    fn arity_check(&self, expected: usize, actual: usize) -> Result<(), LoxError> {
+        if expected != actual {
+            Err(self.error(&format!("Expected {expected} arguments but got {actual}.")))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn bind_method(
+        &self,
+        class_: &Rc<RefCell<Class>>,
+        receiver: Rc<RefCell<Instance>>,
+        name_index: u16,
+    ) -> Result<Value, LoxError> {
+        let name = &self.chunk().names[name_index as usize];
+        let method = class_.borrow().methods.get(name).cloned();
+        match method {
+            Some(method) => Ok(self
+                .heap
+                .borrow_mut()
+                .track(BoundMethod { receiver, method })
+                .into()),
+            None => Err(self.error(&format!("Undefined property '{name}'."))),
+        }
+    }
+
+    // Binds a list or map method.
+    fn native_method(&self, receiver: Receiver<Value>, name_index: u16) -> Result<Value, LoxError> {
+        let name = &self.chunk().names[name_index as usize];
+        match BoundNative::bind(receiver, name) {
+            Some(method) => Ok(method.into()),
+            None => Err(self.error(&format!("Undefined property '{name}'."))),
+        }
+    }
+
+    // Jumps to the innermost handler with the given exception. If there's
+    // none, or the exception escapes a module on the way, it becomes an
+    // error (naming the module).
+    fn unwind(&mut self, mut exception: Value) -> Result<(), LoxError> {
+        let floor = self.handlers.last().map_or(0, |handler| handler.frames);
+        let escapes = self.frames[floor..]
+            .iter()
+            .any(|frame| frame.module.is_some());
+        if self.handlers.is_empty() || escapes {
+            let mut error = self.uncaught(&exception);
+            while self.frames.len() > floor {
+                let frame = self.frames.pop().unwrap();
+                if frame.module.is_some() {
+                    error = self.modules.fail(&error, &self.snippet());
+                }
+            }
+            if self.handlers.is_empty() {
+                return Err(error);
+            }
+            exception = Value::Error(Rc::new(error));
+        }
+        let handler = self.handlers.pop().unwrap();
+        self.frames.truncate(handler.frames);
+        self.close_upvalues(handler.stack);
+        self.stack.truncate(handler.stack);
+        self.push(exception);
+        self.frame_mut().ip = handler.ip;
+        Ok(())
+    }
+
+    // The error to report for a thrown value nothing caught. Rethrowing a
+    // caught runtime error reports it just as if it had never been caught.
+    fn uncaught(&self, exception: &Value) -> LoxError {
+        match exception {
+            Value::Error(e) => (**e).clone(),
+            _ => LoxError {
+                code: ErrorCode::UncaughtException,
+                ..self.error(&format!("Uncaught exception: {exception}."))
+            },
+        }
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let position = self
+            .open_upvalues
+            .iter()
+            .position(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(open) if open >= slot));
+        if let Some(i) = position {
+            if matches!(*self.open_upvalues[i].borrow(), Upvalue::Open(open) if open == slot) {
+                return self.open_upvalues[i].clone();
+            }
+        }
+        let upvalue = self
+            .heap
+            .borrow_mut()
+            .track(RefCell::new(Upvalue::Open(slot)));
+        let i = position.unwrap_or(self.open_upvalues.len());
+        self.open_upvalues.insert(i, upvalue.clone());
+        upvalue
+    }
+
+    // Moves every variable at or above the given slot off the stack and
+    // into its upvalue, since the stack slot is about to go away.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let Upvalue::Open(slot) = *upvalue.borrow() else {
+                panic!("closed upvalue in open list (vm bug?)");
+            };
+            if slot < last {
+                break;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot].clone());
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn comparison(&mut self, f: impl Fn(f64, f64) -> bool) -> Result<(), LoxError> {
+        self.binary_number("invalid types for comparison", |l, r| {
+            Literal::Bool(f(l, r))
+        })
+    }
+
+    fn arithmetic(&mut self, message: &str, f: impl Fn(f64, f64) -> f64) -> Result<(), LoxError> {
+        self.binary_number(message, |l, r| Literal::Number(f(l, r)))
+    }
+
+    fn binary_number(
+        &mut self,
+        message: &str,
+        f: impl Fn(f64, f64) -> Literal,
+    ) -> Result<(), LoxError> {
+        let right = self.pop();
+        let left = self.pop();
+        match (left, right) {
+            (Value::Literal(Literal::Number(l)), Value::Literal(Literal::Number(r))) => {
+                self.push(f(l, r).into());
+                Ok(())
+            }
+            (_, _) => Err(self.error(message)),
+        }
+    }
+
+    fn undefined(&self, name_index: u16) -> LoxError {
+        let name = &self.chunk().names[name_index as usize];
+        self.error(&format!("Undefined variable '{name}'."))
+    }
+
+    // The line of the instruction we're running.
+    fn line(&self) -> usize {
+        let frame = self.frame();
+        frame.closure.function.chunk.lines[frame.ip - 1]
+    }
+
+    fn error(&self, message: &str) -> LoxError {
+        LoxError {
+            code: ErrorCode::Runtime,
+            line: self.line(),
+            loc: String::new(),
+            exit: 70,
+            message: message.to_string(),
+            trace: self.trace(),
+            snippet: Some(Box::new(self.snippet())),
+            more: Vec::new(),
+        }
+    }
+
+    // An error that means there's a bug in the VM (or the compiler).
+    fn bug(&self, message: &str) -> LoxError {
+        LoxError {
+            code: ErrorCode::Internal,
+            ..self.error(message)
+        }
+    }
+
+    // The code of the instruction we're running.
+    fn snippet(&self) -> Snippet {
+        let frame = self.frame();
+        let chunk = &frame.closure.function.chunk;
+        Snippet::new(&chunk.source, chunk.spans[frame.ip - 1])
+    }
+
+    // The calls in progress in the script or module we're running, for
+    // tracebacks.
+    fn trace(&self) -> Vec<CallSite> {
+        let script = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.module.is_some())
+            .unwrap_or(0);
+        if self.frames.len() - script < 2 {
+            return Vec::new();
+        }
+        self.frames[script..]
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| CallSite {
+                function: match i {
+                    0 => "script".to_string(),
+                    _ => frame.closure.function.name.clone(),
+                },
+                line: frame.closure.function.chunk.lines[frame.ip - 1],
+            })
+            .collect()
+    }
+
+    fn read_op(&mut self) -> Op {
+        let frame = self.frame_mut();
+        let op = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        op
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().closure.function.chunk
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+}
+
+// Globals hold the closures defined in them, which refer back to their
+// globals, so we break those cycles once we're done.
+impl<F: FnMut(String)> Drop for Vm<F> {
+    fn drop(&mut self) {
+        let mut all = vec![self.globals.clone()];
+        for module in self.modules.loaded() {
+            if let Value::Module(module) = module {
+                all.push(module.globals.clone());
+            }
+        }
+        for globals in all {
+            let values = std::mem::take(&mut *globals.borrow_mut());
+            drop(values);
+        }
+    }
+}
+
+fn compile_source(source: &str) -> Result<Function, LoxError> {
+    let tokens = scanner::scan_tokens(source)?;
+    let mut prog = parser::parse(tokens)?;
+    resolver::resolve(&mut prog)?;
+    compiler::compile(&prog)
+}
+
+#[cfg(test)]
+pub fn execute_for_tests(source: &str) -> Result<Vec<String>, LoxError> {
+    let mut printed: Vec<String> = Vec::new();
+    let mut time = 0.0;
+    let tokens = scanner::scan_tokens(source)?;
+    let mut prog = parser::parse(tokens)?;
+    resolver::resolve(&mut prog)?;
+    {
+        let mut vm = Vm::new(
+            |s| printed.push(s),
+            move || {
+                time += 1.0;
+                time
+            },
+        );
+        vm.interpret(&prog)?;
+    }
+    Ok(printed)
+}
+
+#[test]
+fn test_vm_upvalues() {
+    // Closures outliving the frame they captured from, and sharing the
+    // captured variable with each other.
+    assert_eq!(
+        execute_for_tests(
+            r"
+                var get;
+                var set;
+                fun make() {
+                    var v = 1;
+                    fun g() { return v; }
+                    fun s(x) { v = x; }
+                    get = g;
+                    set = s;
+                }
+                make();
+                print get();
+                set(2);
+                print get();
+            "
+        )
+        .unwrap(),
+        vec!["1", "2"],
+    );
+    // Each block-scoped variable gets its own upvalue once closed.
+    assert_eq!(
+        execute_for_tests(
+            r"
+                var fs;
+                var gs;
+                for (var i = 0; i < 2; i = i + 1) {
+                    var j = i;
+                    fun f() { return j; }
+                    if (i == 0) fs = f; else gs = f;
+                }
+                print fs();
+                print gs();
+            "
+        )
+        .unwrap(),
+        vec!["0", "1"],
+    );
+}
+
+#[test]
+fn test_vm_limits() {
+    let locals = (0..256).map(|i| format!("var v{i};")).join("");
+    assert_eq!(
+        execute_for_tests(&format!("fun f() {{ {locals} }}"))
+            .unwrap_err()
+            .to_string(),
+        "[line 1] Error: Too many local variables in function.",
+    );
+}
+
+#[test]
+fn test_vm_gc() {
+    // A recursive local function is a cycle through its own upvalue.
+    assert_eq!(
+        execute_for_tests(
+            r"
+                fun outer() {
+                    fun inner() { return inner; }
+                    return inner;
+                }
+                for (var i = 0; i < 10; i = i + 1) {
+                    outer();
+                }
+                var kept = outer();
+                print gc();
+                print kept() == kept;
+            "
+        )
+        .unwrap(),
+        vec!["20", "true"]
+    );
+}
+
+#[test]
+fn test_vm_interpret_line() {
+    let mut printed = Vec::new();
+    {
+        let mut vm = Vm::new(|s| printed.push(s), builtins::now_sec);
+        for line in [
+            "1 + 2",
+            "var a = \"x\";\n",
+            "a + a\n",
+            "a;",
+            "print a;",
+            "[a]",
+        ] {
+            vm.interpret_line(line).unwrap();
+        }
+    }
+    assert_eq!(printed, ["3", "xx", "x", r#"["x"]"#]);
+}